@@ -1,19 +1,45 @@
-use crate::ledger::serialize_4dp;
+use crate::ledger::{
+    ChargebackPolicy, deserialize_4dp, serialize_4dp,
+    transaction::{Currency, TransactionType},
+};
+use log::warn;
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    io::{Read, Write},
+    str::FromStr,
+};
 
 pub type AccountId = u16;
 
+/// Default for `Account::with_held_tolerance`: half the smallest amount the output shows.
+pub const DEFAULT_HELD_TOLERANCE: f64 = 0.00005;
+
 #[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub struct Account {
     client: AccountId,
-    #[serde(serialize_with = "serialize_4dp")]
+    #[serde(serialize_with = "serialize_4dp", deserialize_with = "deserialize_4dp")]
     available: f64,
-    #[serde(serialize_with = "serialize_4dp")]
+    #[serde(serialize_with = "serialize_4dp", deserialize_with = "deserialize_4dp")]
     held: f64,
-    #[serde(serialize_with = "serialize_4dp")]
+    #[serde(serialize_with = "serialize_4dp", deserialize_with = "deserialize_4dp")]
     total: f64,
     locked: bool,
+    // approved overdraft, withdrawals may take `available` down to `-credit_limit`
+    #[serde(skip)]
+    credit_limit: f64,
+    // transactions currently under dispute, not part of the default output
+    #[serde(skip)]
+    disputes_open: u32,
+    // how far short of a release `held` may be and still count as float drift
+    #[serde(skip, default = "default_held_tolerance")]
+    held_tolerance: f64,
+    // currency of the first applied deposit/withdrawal that named one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+    // per-type tallies of what has been applied, not part of the default output
+    #[serde(skip)]
+    volumes: Volumes,
 }
 
 impl Display for Account {
@@ -39,49 +65,152 @@ impl Account {
             held: 0.0,
             total: 0.0,
             locked: false,
+            credit_limit: 0.0,
+            disputes_open: 0,
+            held_tolerance: DEFAULT_HELD_TOLERANCE,
+            currency: None,
+            volumes: Volumes::default(),
+        }
+    }
+
+    // lets tests build inconsistent balances the public API can't produce
+    #[cfg(test)]
+    pub(crate) fn with_balances(client: AccountId, available: f64, held: f64, total: f64) -> Self {
+        Self {
+            available,
+            held,
+            total,
+            ..Self::new(client)
         }
     }
 
+    pub fn with_credit_limit(mut self, credit_limit: f64) -> Self {
+        self.credit_limit = credit_limit;
+        self
+    }
+
+    /// Resolves and chargebacks releasing up to `tolerance` more than `held` empty it with a
+    /// warning instead of failing, so float drift can't leave a tiny negative `held` behind.
+    /// A larger shortfall is still an error.
+    pub fn with_held_tolerance(mut self, tolerance: f64) -> Self {
+        self.held_tolerance = tolerance;
+        self
+    }
+
     pub fn deposit(&mut self, amount: f64) {
         self.available += amount;
         self.total += amount;
+        self.volumes.record(TransactionType::Deposit, amount);
     }
 
     pub fn withdraw(&mut self, amount: f64) -> anyhow::Result<()> {
-        if self.available < amount {
+        if self.available + self.credit_limit < amount {
             anyhow::bail!("Insufficient available funds for withdrawal");
         }
         self.available -= amount;
         self.total -= amount;
+        self.volumes.record(TransactionType::Withdrawal, amount);
         Ok(())
     }
 
+    /// Withdraws up to `amount`, as much as `available` (and the credit limit) allows, and
+    /// returns what was withdrawn: 0 when nothing is available.
+    pub fn withdraw_available(&mut self, amount: f64) -> f64 {
+        let drained = amount.min(self.available + self.credit_limit).max(0.0);
+        self.available -= drained;
+        self.total -= drained;
+        if drained > 0.0 {
+            self.volumes.record(TransactionType::Withdrawal, drained);
+        }
+        drained
+    }
+
     pub fn dispute(&mut self, amount: f64) -> anyhow::Result<()> {
         if self.available < amount {
             anyhow::bail!("Insufficient available funds for dispute");
         }
         self.available -= amount;
         self.held += amount;
+        self.disputes_open += 1;
+        self.volumes.record(TransactionType::Dispute, amount);
         Ok(())
     }
 
+    /// Disputing a withdrawal contests the original debit: the withdrawn funds are
+    /// restored to the account but held until the dispute is resolved or charged back.
+    pub fn dispute_withdrawal(&mut self, amount: f64) {
+        self.held += amount;
+        self.total += amount;
+        self.disputes_open += 1;
+        self.volumes.record(TransactionType::Dispute, amount);
+    }
+
     pub fn resolve(&mut self, amount: f64) -> anyhow::Result<()> {
-        if self.held < amount {
-            anyhow::bail!("Insufficient held funds");
-        }
-        self.held -= amount;
-        self.available += amount;
+        let released = self.release_held(amount)?;
+        self.available += released;
+        self.disputes_open = self.disputes_open.saturating_sub(1);
+        self.volumes.record(TransactionType::Resolve, released);
         Ok(())
     }
 
     pub fn chargeback(&mut self, amount: f64) -> anyhow::Result<()> {
-        if self.held < amount {
+        self.chargeback_with(amount, ChargebackPolicy::LockAccount)
+    }
+
+    /// `chargeback`, locking the account only under `ChargebackPolicy::LockAccount`.
+    pub fn chargeback_with(&mut self, amount: f64, policy: ChargebackPolicy) -> anyhow::Result<()> {
+        let released = self.release_held(amount)?;
+        self.total -= released;
+        if policy == ChargebackPolicy::LockAccount {
+            self.locked = true;
+        }
+        self.disputes_open = self.disputes_open.saturating_sub(1);
+        self.volumes.record(TransactionType::Chargeback, released);
+        Ok(())
+    }
+
+    // takes `amount` out of `held` and returns what was actually released: all of `held` when
+    // it is short by no more than the tolerance, keeping `available + held == total`
+    fn release_held(&mut self, amount: f64) -> anyhow::Result<f64> {
+        if self.held >= amount {
+            self.held -= amount;
+            return Ok(amount);
+        }
+        let shortfall = amount - self.held;
+        if shortfall > self.held_tolerance {
             anyhow::bail!("Insufficient held funds");
         }
-        self.held -= amount;
-        self.total -= amount;
+        warn!(
+            client = self.client;
+            "Held funds short by {} releasing {} (client {}), clamping held to zero",
+            shortfall, amount, self.client
+        );
+        let released = self.held;
+        self.held = 0.0;
+        Ok(released)
+    }
+
+    /// Freezes the account without a chargeback, e.g. to mirror a lock from another system.
+    pub fn lock(&mut self) {
         self.locked = true;
-        Ok(())
+    }
+
+    pub fn unlock(&mut self) {
+        self.unlock_with(false);
+    }
+
+    /// `unlock`, also moving everything still `held` back to `available` with
+    /// `release_held`, as if every open dispute had been resolved. Returns what was released.
+    pub fn unlock_with(&mut self, release_held: bool) -> f64 {
+        self.locked = false;
+        let mut released = 0.0;
+        if release_held {
+            released = std::mem::take(&mut self.held);
+            self.available += released;
+            self.disputes_open = 0;
+        }
+        self.volumes.record(TransactionType::Unlock, released);
+        released
     }
 
     pub fn client(&self) -> AccountId {
@@ -103,12 +232,948 @@ impl Account {
     pub fn is_locked(&self) -> bool {
         self.locked
     }
+
+    pub fn credit_limit(&self) -> f64 {
+        self.credit_limit
+    }
+
+    pub fn disputes_open(&self) -> u32 {
+        self.disputes_open
+    }
+
+    pub fn currency(&self) -> Option<Currency> {
+        self.currency
+    }
+
+    /// Remembers the account's currency, only the first one sticks.
+    pub(crate) fn adopt_currency(&mut self, currency: Currency) {
+        self.currency.get_or_insert(currency);
+    }
+
+    /// Compares the output columns: the same client and `locked` flag, and `available`, `held`
+    /// and `total` each within `epsilon` (inclusive). Bookkeeping such as the volumes is
+    /// ignored. Handy in tests, where summed floats rarely match exactly.
+    pub fn approx_eq(&self, other: &Account, epsilon: f64) -> bool {
+        self.client == other.client
+            && self.locked == other.locked
+            && [
+                (self.available, other.available),
+                (self.held, other.held),
+                (self.total, other.total),
+            ]
+            .into_iter()
+            .all(|(a, b)| (a - b).abs() <= epsilon)
+    }
+
+    /// Funds not tied up in disputes: `available - held`.
+    pub fn net_position(&self) -> f64 {
+        self.available - self.held
+    }
+
+    /// Sum of the amounts applied by transactions of type `ty`: what was actually moved, so a
+    /// partial withdrawal counts what it drained and a clamped release what it released.
+    /// Resolves include disputes resolved by expiry, unlocks always sum to 0.
+    pub fn volume(&self, ty: TransactionType) -> f64 {
+        self.volumes.sums[ty as usize]
+    }
+
+    /// How many transactions of type `ty` have been applied to the account.
+    pub fn count(&self, ty: TransactionType) -> u32 {
+        self.volumes.counts[ty as usize]
+    }
+
+    pub fn volumes(&self) -> Volumes {
+        self.volumes
+    }
+
+    // the tallies aren't part of the account's serialized form, snapshots carry them separately
+    pub(crate) fn set_volumes(&mut self, volumes: Volumes) {
+        self.volumes = volumes;
+    }
+
+    // the counter isn't serialized, restoring from a snapshot recomputes it
+    pub(crate) fn set_disputes_open(&mut self, disputes_open: u32) {
+        self.disputes_open = disputes_open;
+    }
+}
+
+/// Per-type count and summed amount of the transactions applied to an account, see
+/// `Account::volume` and `Account::count`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct Volumes {
+    counts: [u32; TransactionType::ALL.len()],
+    sums: [f64; TransactionType::ALL.len()],
+}
+
+impl Volumes {
+    fn record(&mut self, ty: TransactionType, amount: f64) {
+        self.counts[ty as usize] += 1;
+        self.sums[ty as usize] += amount;
+    }
+}
+
+fn default_held_tolerance() -> f64 {
+    DEFAULT_HELD_TOLERANCE
+}
+
+/// The order account rows are written in, named as `--sort` takes it: `client-asc`,
+/// `total-desc` or `available-desc`. Equal balances keep client order, so the output is
+/// the same between runs whatever the order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    ClientAsc,
+    TotalDesc,
+    AvailableDesc,
+}
+
+impl SortOrder {
+    pub const ALL: [Self; 3] = [Self::ClientAsc, Self::TotalDesc, Self::AvailableDesc];
+
+    pub fn sorted<'a>(self, accounts: impl IntoIterator<Item = &'a Account>) -> Vec<&'a Account> {
+        // accounts usually come out of a `HashMap`, sort them so the output is stable
+        // between runs
+        let mut accounts: Vec<&Account> = accounts.into_iter().collect();
+        accounts.sort_by_key(|account| account.client);
+        match self {
+            Self::ClientAsc => {}
+            Self::TotalDesc => accounts.sort_by(|a, b| b.total.total_cmp(&a.total)),
+            Self::AvailableDesc => accounts.sort_by(|a, b| b.available.total_cmp(&a.available)),
+        }
+        accounts
+    }
+}
+
+impl Display for SortOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ClientAsc => "client-asc",
+            Self::TotalDesc => "total-desc",
+            Self::AvailableDesc => "available-desc",
+        })
+    }
+}
+
+impl FromStr for SortOrder {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match Self::ALL.into_iter().find(|order| order.to_string() == s) {
+            Some(order) => Ok(order),
+            None => anyhow::bail!(
+                "Unknown sort order: {}, expected one of {}",
+                s,
+                Self::ALL.map(|order| order.to_string()).join(",")
+            ),
+        }
+    }
+}
+
+/// How the account CSV quotes its fields, named as `--quoting` takes it. Nothing written
+/// needs quotes, so `Necessary` (the default) quotes nothing either; `Always` quotes every
+/// field, the header included.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Quoting {
+    Always,
+    #[default]
+    Necessary,
+    Never,
+}
+
+impl Quoting {
+    pub const ALL: [Self; 3] = [Self::Always, Self::Necessary, Self::Never];
+}
+
+impl From<Quoting> for csv::QuoteStyle {
+    fn from(quoting: Quoting) -> Self {
+        match quoting {
+            Quoting::Always => csv::QuoteStyle::Always,
+            Quoting::Necessary => csv::QuoteStyle::Necessary,
+            Quoting::Never => csv::QuoteStyle::Never,
+        }
+    }
+}
+
+impl Display for Quoting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Always => "always",
+            Self::Necessary => "necessary",
+            Self::Never => "never",
+        })
+    }
+}
+
+impl FromStr for Quoting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match Self::ALL
+            .into_iter()
+            .find(|quoting| quoting.to_string() == s)
+        {
+            Some(quoting) => Ok(quoting),
+            None => anyhow::bail!(
+                "Unknown quoting: {}, expected one of {}",
+                s,
+                Self::ALL.map(|quoting| quoting.to_string()).join(",")
+            ),
+        }
+    }
+}
+
+/// Decimal places of the money columns in the CSV output (and always in `Display`/JSON).
+pub const DEFAULT_PRECISION: usize = 4;
+
+/// A column of the account CSV, named by its header. `write_accounts_csv_selecting` writes
+/// any subset of them in any order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    Extra(ExtraColumn),
+}
+
+impl Column {
+    /// The columns `accounts_to_csv` writes.
+    pub const DEFAULT: [Self; 5] = [
+        Self::Client,
+        Self::Available,
+        Self::Held,
+        Self::Total,
+        Self::Locked,
+    ];
+
+    /// Every column that can be selected, the defaults first.
+    pub fn all() -> impl Iterator<Item = Self> {
+        Self::DEFAULT.into_iter().chain(
+            [ExtraColumn::DisputesOpen, ExtraColumn::NetPosition]
+                .into_iter()
+                .chain(ExtraColumn::volumes())
+                .map(Self::Extra),
+        )
+    }
+
+    fn header(self) -> String {
+        match self {
+            Self::Client => "client".to_string(),
+            Self::Available => "available".to_string(),
+            Self::Held => "held".to_string(),
+            Self::Total => "total".to_string(),
+            Self::Locked => "locked".to_string(),
+            Self::Extra(column) => column.header(),
+        }
+    }
+
+    // the serde serializers are fixed at 4 decimal places, values with another precision
+    // are formatted here
+    fn value(self, account: &Account, precision: usize) -> String {
+        match self {
+            Self::Client => account.client.to_string(),
+            Self::Available => format!("{:.p$}", account.available, p = precision),
+            Self::Held => format!("{:.p$}", account.held, p = precision),
+            Self::Total => format!("{:.p$}", account.total, p = precision),
+            Self::Locked => account.locked.to_string(),
+            Self::Extra(column) => column.value(account, precision),
+        }
+    }
+}
+
+impl FromStr for Column {
+    type Err = anyhow::Error;
+
+    /// A header name as written in the output: `client`, `net_position`, `deposit_volume`...
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match Self::all().find(|column| column.header() == s) {
+            Some(column) => Ok(column),
+            None => anyhow::bail!(
+                "Unknown column: {}, expected one of {}",
+                s,
+                Self::all()
+                    .map(Column::header)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ),
+        }
+    }
+}
+
+/// Opt-in columns appended after `locked` by `accounts_to_csv_with_columns`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtraColumn {
+    DisputesOpen,
+    NetPosition,
+    /// `<type>_count`, see `Account::count`.
+    Count(TransactionType),
+    /// `<type>_volume`, see `Account::volume`.
+    Volume(TransactionType),
+}
+
+impl ExtraColumn {
+    /// A `Count` and a `Volume` column for every transaction type.
+    pub fn volumes() -> impl Iterator<Item = Self> {
+        TransactionType::ALL
+            .into_iter()
+            .flat_map(|ty| [Self::Count(ty), Self::Volume(ty)])
+    }
+
+    fn header(self) -> String {
+        match self {
+            Self::DisputesOpen => "disputes_open".to_string(),
+            Self::NetPosition => "net_position".to_string(),
+            Self::Count(ty) => format!("{ty}_count"),
+            Self::Volume(ty) => format!("{ty}_volume"),
+        }
+    }
+
+    fn value(self, account: &Account, precision: usize) -> String {
+        match self {
+            Self::DisputesOpen => account.disputes_open().to_string(),
+            Self::NetPosition => format!("{:.p$}", account.net_position(), p = precision),
+            Self::Count(ty) => account.count(ty).to_string(),
+            Self::Volume(ty) => format!("{:.p$}", account.volume(ty), p = precision),
+        }
+    }
+}
+
+/// Rows are sorted by client ID ascending, money columns have `precision` decimal places.
+pub fn accounts_to_csv<'a>(
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+) -> String {
+    accounts_to_csv_with_columns(accounts, precision, &[])
+}
+
+/// `accounts_to_csv` with an extra `disputes_open` column.
+pub fn accounts_to_verbose_csv<'a>(
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+) -> String {
+    accounts_to_csv_with_columns(accounts, precision, &[ExtraColumn::DisputesOpen])
+}
+
+/// `accounts_to_csv` with the `extra` columns appended, in order.
+pub fn accounts_to_csv_with_columns<'a>(
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+    extra: &[ExtraColumn],
+) -> String {
+    let mut buf = Vec::new();
+    write_accounts_csv_with_columns(&mut buf, accounts, precision, extra)
+        .expect("writing to a Vec can't fail");
+    String::from_utf8(buf).expect("the CSV is built from UTF-8 strings")
 }
 
-pub fn accounts_to_csv<'a>(accounts: impl IntoIterator<Item = &'a Account>) -> String {
-    let mut buf = vec!["client,available,held,total,locked".to_string()];
-    accounts
+/// Streams what `accounts_to_csv` returns to `writer` row by row (no trailing newline),
+/// without holding the whole output in memory.
+pub fn write_accounts_csv<'a, W: Write>(
+    writer: W,
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+) -> std::io::Result<()> {
+    write_accounts_csv_with_columns(writer, accounts, precision, &[])
+}
+
+/// `write_accounts_csv` with the `extra` columns appended, in order.
+pub fn write_accounts_csv_with_columns<'a, W: Write>(
+    writer: W,
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+    extra: &[ExtraColumn],
+) -> std::io::Result<()> {
+    let columns: Vec<Column> = Column::DEFAULT
         .into_iter()
-        .for_each(|account| buf.push(account.to_string()));
-    buf.join("\n")
+        .chain(extra.iter().copied().map(Column::Extra))
+        .collect();
+    write_accounts_csv_selecting(writer, accounts, precision, &columns)
+}
+
+/// `accounts_to_csv` with exactly `columns`, in that order.
+pub fn accounts_to_csv_selecting<'a>(
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+    columns: &[Column],
+) -> String {
+    let mut buf = Vec::new();
+    write_accounts_csv_selecting(&mut buf, accounts, precision, columns)
+        .expect("writing to a Vec can't fail");
+    String::from_utf8(buf).expect("the CSV is built from UTF-8 strings")
+}
+
+/// `write_accounts_csv` with exactly `columns`, in that order. Rows are still sorted by
+/// client, whether or not `client` is selected.
+pub fn write_accounts_csv_selecting<'a, W: Write>(
+    writer: W,
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+    columns: &[Column],
+) -> std::io::Result<()> {
+    write_accounts_csv_sorted(
+        writer,
+        accounts,
+        precision,
+        columns,
+        SortOrder::ClientAsc,
+        Quoting::Necessary,
+    )
+}
+
+/// `write_accounts_csv_selecting` with the rows in `order` and the fields quoted per
+/// `quoting`.
+pub fn write_accounts_csv_sorted<'a, W: Write>(
+    writer: W,
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+    columns: &[Column],
+    order: SortOrder,
+    quoting: Quoting,
+) -> std::io::Result<()> {
+    let mut csv = csv_writer(
+        NoTrailingNewline {
+            inner: writer,
+            pending: false,
+        },
+        quoting,
+    );
+    csv.write_record(columns.iter().map(|c| c.header()))?;
+    for account in order.sorted(accounts) {
+        csv.write_record(account_fields(account, precision, columns))?;
+    }
+    csv.flush()
+}
+
+/// `write_accounts_csv_sorted` without the header, every row ending with a newline: what
+/// to append to a file already holding that output.
+pub fn write_accounts_csv_rows<'a, W: Write>(
+    writer: W,
+    accounts: impl IntoIterator<Item = &'a Account>,
+    precision: usize,
+    columns: &[Column],
+    order: SortOrder,
+    quoting: Quoting,
+) -> std::io::Result<()> {
+    let mut csv = csv_writer(writer, quoting);
+    for account in order.sorted(accounts) {
+        csv.write_record(account_fields(account, precision, columns))?;
+    }
+    csv.flush()
+}
+
+fn account_fields<'a>(
+    account: &'a Account,
+    precision: usize,
+    columns: &'a [Column],
+) -> impl Iterator<Item = String> + 'a {
+    columns.iter().map(move |c| c.value(account, precision))
+}
+
+// one writer per output, it does the quoting and ends every record with a newline
+fn csv_writer<W: Write>(writer: W, quoting: Quoting) -> csv::Writer<W> {
+    csv::WriterBuilder::new()
+        .quote_style(quoting.into())
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(writer)
+}
+
+// holds a trailing newline back until more output follows, so the last record's terminator
+// never reaches `inner`: the full account CSV ends without one
+struct NoTrailingNewline<W> {
+    inner: W,
+    pending: bool,
+}
+
+impl<W: Write> Write for NoTrailingNewline<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let Some((&last, body)) = buf.split_last() else {
+            return Ok(0);
+        };
+        if self.pending {
+            self.inner.write_all(b"\n")?;
+            self.pending = false;
+        }
+        if last == b'\n' {
+            self.inner.write_all(body)?;
+            self.pending = true;
+        } else {
+            self.inner.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads accounts back from `accounts_to_csv` output (any precision, extra columns are
+/// ignored), in file order. Only the output columns are restored.
+pub fn accounts_from_csv<R: Read>(reader: R) -> Result<Vec<Account>, csv::Error> {
+    csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(reader)
+        .deserialize()
+        .collect()
+}
+
+pub fn accounts_to_json<'a>(
+    accounts: impl IntoIterator<Item = &'a Account>,
+) -> serde_json::Result<String> {
+    accounts_to_json_sorted(accounts, SortOrder::ClientAsc)
+}
+
+/// `accounts_to_json` with the accounts in `order`.
+pub fn accounts_to_json_sorted<'a>(
+    accounts: impl IntoIterator<Item = &'a Account>,
+    order: SortOrder,
+) -> serde_json::Result<String> {
+    serde_json::to_string(&order.sorted(accounts))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accounts_to_json_round_trips() {
+        let mut first = Account::new(1);
+        first.deposit(10.5);
+        let mut second = Account::new(2);
+        second.deposit(3.25);
+        second.dispute(1.0).unwrap();
+        let accounts = vec![first, second];
+
+        let json = accounts_to_json(accounts.iter()).unwrap();
+        assert!(json.contains("\"available\":\"10.5000\""));
+
+        // only the output columns round-trip, bookkeeping like `disputes_open` or the volumes
+        // is not serialized
+        let parsed: Vec<Account> = serde_json::from_str(&json).unwrap();
+        assert_eq!(
+            accounts_to_csv(&parsed, DEFAULT_PRECISION),
+            accounts_to_csv(&accounts, DEFAULT_PRECISION)
+        );
+        assert_eq!(
+            parsed[0],
+            Account {
+                volumes: Volumes::default(),
+                ..accounts[0]
+            }
+        );
+    }
+
+    #[test]
+    fn accounts_to_csv_round_trips() {
+        let mut first = Account::new(1);
+        first.deposit(10.5);
+        first.dispute(0.25).unwrap();
+        let mut second = Account::new(2).with_credit_limit(5.0);
+        second.withdraw(3.1234).unwrap();
+        second.lock();
+        let accounts = vec![first, second];
+
+        for csv in [
+            accounts_to_csv(&accounts, DEFAULT_PRECISION),
+            accounts_to_verbose_csv(&accounts, DEFAULT_PRECISION),
+        ] {
+            let parsed = accounts_from_csv(csv.as_bytes()).unwrap();
+            assert_eq!(parsed.len(), 2);
+            assert_eq!(parsed[1].available(), -3.1234);
+            assert!(parsed[1].is_locked());
+            // bookkeeping like `disputes_open` isn't part of the output, balances are
+            assert_eq!(
+                accounts_to_csv(&parsed, DEFAULT_PRECISION),
+                accounts_to_csv(&accounts, DEFAULT_PRECISION)
+            );
+        }
+
+        // plain numbers, as hand-written JSON would have them, read too
+        let parsed: Account = serde_json::from_str(
+            r#"{"client":3,"available":1.5,"held":"0.5000","total":2,"locked":false}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            (parsed.available(), parsed.held(), parsed.total()),
+            (1.5, 0.5, 2.0)
+        );
+    }
+
+    #[test]
+    fn verbose_csv_appends_disputes_open() {
+        let mut account = Account::new(7);
+        account.deposit(10.0);
+        account.dispute(4.0).unwrap();
+
+        let csv = accounts_to_verbose_csv([&account], DEFAULT_PRECISION);
+        assert_eq!(
+            csv,
+            "client,available,held,total,locked,disputes_open\n7,6.0000,4.0000,10.0000,false,1"
+        );
+    }
+
+    #[test]
+    fn accounts_to_csv_sorts_by_client() {
+        for _ in 0..10 {
+            let accounts: std::collections::HashMap<u16, Account> = [42, 7, 1000, 3, 19]
+                .into_iter()
+                .map(|client| (client, Account::new(client)))
+                .collect();
+            let csv = accounts_to_csv(accounts.values(), DEFAULT_PRECISION);
+            let rows: Vec<&str> = csv.lines().skip(1).collect();
+            assert!(rows[0].starts_with("3,"), "{csv}");
+            assert!(rows[4].starts_with("1000,"), "{csv}");
+        }
+    }
+
+    #[test]
+    fn accounts_to_csv_renders_requested_precision() {
+        let mut account = Account::new(3);
+        account.deposit(10.125);
+        account.dispute(2.5).unwrap();
+
+        assert_eq!(
+            accounts_to_csv([&account], 4),
+            "client,available,held,total,locked\n3,7.6250,2.5000,10.1250,false"
+        );
+        assert_eq!(
+            accounts_to_csv([&account], 2),
+            "client,available,held,total,locked\n3,7.62,2.50,10.12,false"
+        );
+        // the default matches the serde output
+        assert_eq!(
+            accounts_to_csv([&account], DEFAULT_PRECISION)
+                .lines()
+                .nth(1),
+            Some(account.to_string().as_str())
+        );
+    }
+
+    #[test]
+    fn net_position_subtracts_held_funds() {
+        let mut account = Account::new(4);
+        account.deposit(10.0);
+        account.deposit(2.5);
+        account.dispute(2.5).unwrap();
+
+        assert_eq!(account.net_position(), account.available() - account.held());
+        assert_eq!(account.net_position(), 7.5);
+        assert_eq!(
+            accounts_to_csv_with_columns([&account], 2, &[ExtraColumn::NetPosition]),
+            "client,available,held,total,locked,net_position\n4,10.00,2.50,12.50,false,7.50"
+        );
+    }
+
+    #[test]
+    fn held_shortfall_within_tolerance_clamps_to_zero() {
+        let mut account = Account::with_balances(1, 10.0, 5.0, 15.0);
+        account.resolve(5.00004).unwrap();
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.available(), 15.0);
+
+        let mut account = Account::with_balances(1, 10.0, 5.0, 15.0);
+        account.chargeback(5.00005).unwrap();
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.total(), 10.0);
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn held_shortfall_beyond_tolerance_is_an_error() {
+        let mut account = Account::with_balances(1, 10.0, 5.0, 15.0);
+        assert!(account.resolve(5.0001).is_err());
+        assert!(account.chargeback(5.0001).is_err());
+        assert_eq!(account, Account::with_balances(1, 10.0, 5.0, 15.0));
+
+        // the tolerance is configurable, zero makes any shortfall an error
+        let mut strict = Account::with_balances(1, 10.0, 5.0, 15.0).with_held_tolerance(0.0);
+        assert!(strict.resolve(5.00001).is_err());
+        let mut loose = Account::with_balances(1, 10.0, 5.0, 15.0).with_held_tolerance(0.01);
+        loose.resolve(5.005).unwrap();
+        assert_eq!(loose.held(), 0.0);
+    }
+
+    #[test]
+    fn write_accounts_csv_matches_the_string_version() {
+        let accounts: Vec<Account> = (1..=50)
+            .rev()
+            .map(|client| {
+                let mut account = Account::new(client);
+                account.deposit(client as f64 * 1.5);
+                account
+            })
+            .collect();
+
+        let mut buf = Vec::new();
+        write_accounts_csv(&mut buf, &accounts, 2).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            accounts_to_csv(&accounts, 2)
+        );
+
+        let extra = [ExtraColumn::DisputesOpen, ExtraColumn::NetPosition];
+        let mut buf = Vec::new();
+        write_accounts_csv_with_columns(&mut buf, &accounts, 4, &extra).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            accounts_to_csv_with_columns(&accounts, 4, &extra)
+        );
+    }
+
+    #[test]
+    fn volumes_tally_applied_amounts_per_type() {
+        let mut account = Account::new(1).with_held_tolerance(0.5);
+        account.deposit(100.0);
+        account.deposit(20.5);
+        account.withdraw(30.0).unwrap();
+        assert!(account.withdraw(500.0).is_err());
+        account.dispute(20.5).unwrap();
+        account.resolve(20.5).unwrap();
+        account.dispute(10.0).unwrap();
+        // clamped release: only the 10 held is charged back
+        account.chargeback(10.25).unwrap();
+        account.unlock();
+        assert_eq!(account.withdraw_available(1000.0), 80.5);
+        assert_eq!(account.withdraw_available(5.0), 0.0);
+
+        let expected = [
+            (TransactionType::Deposit, 2, 120.5),
+            (TransactionType::Withdrawal, 2, 110.5),
+            (TransactionType::Dispute, 2, 30.5),
+            (TransactionType::Resolve, 1, 20.5),
+            (TransactionType::Chargeback, 1, 10.0),
+            (TransactionType::Unlock, 1, 0.0),
+        ];
+        for (ty, count, volume) in expected {
+            assert_eq!(account.count(ty), count, "{ty} count");
+            assert_eq!(account.volume(ty), volume, "{ty} volume");
+        }
+    }
+
+    #[test]
+    fn volume_columns_follow_the_type_order() {
+        let mut account = Account::new(1);
+        account.deposit(10.0);
+        account.withdraw(2.5).unwrap();
+
+        let extra: Vec<_> = ExtraColumn::volumes().collect();
+        assert_eq!(
+            accounts_to_csv_with_columns([&account], 2, &extra),
+            "client,available,held,total,locked,deposit_count,deposit_volume,withdrawal_count,\
+             withdrawal_volume,dispute_count,dispute_volume,resolve_count,resolve_volume,\
+             chargeback_count,chargeback_volume,unlock_count,unlock_volume\n\
+             1,7.50,0.00,7.50,false,1,10.00,1,2.50,0,0.00,0,0.00,0,0.00,0,0.00"
+        );
+    }
+
+    #[test]
+    fn approx_eq_compares_balances_within_epsilon() {
+        let account = Account::with_balances(1, 10.0, 2.0, 12.0);
+
+        // 0.5 is exact in binary, so the boundary itself is representable
+        assert!(account.approx_eq(&Account::with_balances(1, 10.5, 2.0, 12.0), 0.5));
+        assert!(account.approx_eq(&Account::with_balances(1, 10.0, 1.5, 12.5), 0.5));
+        assert!(!account.approx_eq(&Account::with_balances(1, 10.0, 2.0, 12.5625), 0.5));
+        assert!(!account.approx_eq(&Account::with_balances(1, 9.4375, 2.0, 12.0), 0.5));
+
+        let mut summed = Account::new(1);
+        (0..10).for_each(|_| summed.deposit(0.1));
+        assert_ne!(summed.available(), 1.0);
+        assert!(summed.approx_eq(&Account::with_balances(1, 1.0, 0.0, 1.0), 0.0001));
+        assert!(!summed.approx_eq(&Account::with_balances(1, 1.001, 0.0, 1.001), 0.0001));
+
+        // the client and the locked flag compare exactly
+        assert!(!account.approx_eq(&Account::with_balances(2, 10.0, 2.0, 12.0), 0.5));
+        let mut locked = account;
+        locked.lock();
+        assert!(!account.approx_eq(&locked, 0.5));
+        assert!(locked.approx_eq(&locked, 0.0));
+    }
+
+    #[test]
+    fn selected_columns_are_written_in_the_given_order() {
+        let mut first = Account::new(1);
+        first.deposit(10.0);
+        first.dispute(2.5).unwrap();
+        let mut second = Account::new(2);
+        second.deposit(1.0);
+
+        let columns: Vec<Column> = ["total", "client", "locked", "net_position"]
+            .into_iter()
+            .map(|name| name.parse().unwrap())
+            .collect();
+        assert_eq!(
+            accounts_to_csv_selecting([&second, &first], 2, &columns),
+            "total,client,locked,net_position\n10.00,1,false,5.00\n1.00,2,false,1.00"
+        );
+
+        // the default selection is the plain output
+        assert_eq!(
+            accounts_to_csv_selecting([&second, &first], 4, &Column::DEFAULT),
+            accounts_to_csv([&second, &first], 4)
+        );
+    }
+
+    #[test]
+    fn unknown_column_names_are_an_error() {
+        let err = "balance".parse::<Column>().unwrap_err().to_string();
+        assert!(
+            err.starts_with("Unknown column: balance, expected one of client,"),
+            "{err}"
+        );
+        assert!("Client".parse::<Column>().is_err());
+        assert_eq!(
+            "chargeback_count".parse::<Column>().unwrap(),
+            Column::Extra(ExtraColumn::Count(TransactionType::Chargeback))
+        );
+    }
+
+    #[test]
+    fn rows_without_header_append_to_the_full_output() {
+        let accounts: Vec<Account> = (1..=3)
+            .map(|client| {
+                let mut account = Account::new(client);
+                account.deposit(client as f64);
+                account
+            })
+            .collect();
+        let (first, rest) = accounts.split_at(1);
+
+        let mut buf = Vec::new();
+        write_accounts_csv_selecting(&mut buf, first, 2, &Column::DEFAULT).unwrap();
+        buf.push(b'\n');
+        write_accounts_csv_rows(
+            &mut buf,
+            rest,
+            2,
+            &Column::DEFAULT,
+            SortOrder::ClientAsc,
+            Quoting::Necessary,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            format!("{}\n", accounts_to_csv(&accounts, 2))
+        );
+    }
+
+    #[test]
+    fn sort_orders_put_the_largest_balances_first() {
+        let accounts: Vec<Account> = [(1, 5.0, 0.0), (2, 20.0, 0.0), (3, 8.0, 6.0), (4, 20.0, 0.0)]
+            .into_iter()
+            .map(|(client, deposit, disputed)| {
+                let mut account = Account::new(client);
+                account.deposit(deposit);
+                if disputed > 0.0 {
+                    account.dispute(disputed).unwrap();
+                }
+                account
+            })
+            .collect();
+        let order = |order: SortOrder| -> Vec<AccountId> {
+            order.sorted(&accounts).iter().map(|a| a.client).collect()
+        };
+
+        assert_eq!(order(SortOrder::ClientAsc), [1, 2, 3, 4]);
+        // ties stay in client order
+        assert_eq!(order(SortOrder::TotalDesc), [2, 4, 3, 1]);
+        assert_eq!(order(SortOrder::AvailableDesc), [2, 4, 1, 3]);
+
+        let mut buf = Vec::new();
+        write_accounts_csv_sorted(
+            &mut buf,
+            &accounts,
+            1,
+            &[Column::Client, Column::Total],
+            SortOrder::TotalDesc,
+            Quoting::Necessary,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,total\n2,20.0\n4,20.0\n3,8.0\n1,5.0"
+        );
+    }
+
+    #[test]
+    fn sort_orders_parse_from_their_flag_names() {
+        for order in SortOrder::ALL {
+            assert_eq!(order.to_string().parse::<SortOrder>().unwrap(), order);
+        }
+        let err = "total".parse::<SortOrder>().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "Unknown sort order: total, expected one of client-asc,total-desc,available-desc"
+        );
+    }
+
+    #[test]
+    fn quoting_controls_the_quotes_around_fields() {
+        let mut account = Account::new(7);
+        account.deposit(1.5);
+        let columns = [Column::Client, Column::Total, Column::Locked];
+        // (full output with the header, rows only)
+        let write = |quoting: Quoting| {
+            let mut full = Vec::new();
+            write_accounts_csv_sorted(
+                &mut full,
+                [&account],
+                2,
+                &columns,
+                SortOrder::ClientAsc,
+                quoting,
+            )
+            .unwrap();
+            let mut rows = Vec::new();
+            write_accounts_csv_rows(
+                &mut rows,
+                [&account],
+                2,
+                &columns,
+                SortOrder::ClientAsc,
+                quoting,
+            )
+            .unwrap();
+            (
+                String::from_utf8(full).unwrap(),
+                String::from_utf8(rows).unwrap(),
+            )
+        };
+
+        assert_eq!(
+            write(Quoting::Always),
+            (
+                "\"client\",\"total\",\"locked\"\n\"7\",\"1.50\",\"false\"".to_string(),
+                "\"7\",\"1.50\",\"false\"\n".to_string()
+            )
+        );
+        assert_eq!(
+            write(Quoting::Necessary),
+            (
+                "client,total,locked\n7,1.50,false".to_string(),
+                "7,1.50,false\n".to_string()
+            )
+        );
+        assert_eq!(write(Quoting::Never), write(Quoting::Necessary));
+        let (full, rows) = write(Quoting::Never);
+        assert!(!full.contains('"') && !rows.contains('"'));
+
+        for quoting in Quoting::ALL {
+            assert_eq!(quoting.to_string().parse::<Quoting>().unwrap(), quoting);
+        }
+        assert!("minimal".parse::<Quoting>().is_err());
+    }
+
+    #[test]
+    fn only_the_final_newline_is_held_back() {
+        let mut out = NoTrailingNewline {
+            inner: Vec::new(),
+            pending: false,
+        };
+        for chunk in ["a,b\n", "\n", "c", "\"d\ne\"\n"] {
+            out.write_all(chunk.as_bytes()).unwrap();
+        }
+        assert_eq!(out.inner, b"a,b\n\nc\"d\ne\"");
+    }
 }
@@ -1,9 +1,26 @@
 use crate::ledger::{
-    account::{Account, AccountId},
+    ChargebackPolicy, LockPolicy, RoundingMode,
+    account::{Account, AccountId, Volumes},
+    stats::{AggregateSummary, LedgerProof, ProcessingStats},
     transaction::{Transaction, TransactionEntry, TransactionStatus, TransactionType},
 };
 use log::warn;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+// Warns with the transaction's client and ID, in the message for humans and as `client`/`tx`
+// key-values so log processors can filter on them.
+macro_rules! tx_warn {
+    ($tx:expr, $($arg:tt)+) => {
+        warn!(
+            client = $tx.client, tx = $tx.tx;
+            "{} (client {}, tx {})", format_args!($($arg)+), $tx.client, $tx.tx
+        )
+    };
+}
 
 #[derive(Debug, Clone)]
 pub struct Engine {
@@ -12,6 +29,80 @@ pub struct Engine {
     transactions: Vec<TransactionEntry>,
     // transaction state (mutable - efficient retrieval of latest state)
     tx_state: HashMap<u32, TxState>,
+    // tx IDs introduced by deposits/withdrawals (duplicate index, independent of the event log)
+    seen_tx_ids: HashSet<u32>,
+    // sequence index of the next processed transaction
+    seq: u64,
+    // latest recorded entry, kept even without the event log
+    last_entry: Option<TransactionEntry>,
+    // entries recorded by the current `apply_all` step, kept even without the event log
+    step_entries: Option<Vec<TransactionEntry>>,
+    event_log: bool,
+    credit_limit: f64,
+    dispute_window: Option<u64>,
+    allow_unlock: bool,
+    release_held_on_unlock: bool,
+    allow_redispute: bool,
+    allow_cross_client_dispute: bool,
+    partial_withdrawals: bool,
+    max_amount: Option<f64>,
+    skip_processed_files: bool,
+    rounding: Option<RoundingMode>,
+    auto_resolve_after: Option<u64>,
+    chargeback_policy: ChargebackPolicy,
+    lock_policy: LockPolicy,
+    // (sequence index the dispute was opened at, tx ID), oldest first. Only kept with
+    // `auto_resolve_after`, entries resolved in the meantime are dropped when reached
+    open_disputes: VecDeque<(u64, u32)>,
+    // content hashes of the input files processed so far
+    processed_files: HashSet<u64>,
+    hook: Option<Hook>,
+    // balance samples of the clients tracked with `with_history`, untracked ones have no key
+    history: HashMap<AccountId, Vec<BalanceSample>>,
+    // set by `with_sorted_clients`
+    sorted_clients: Option<SortedClients>,
+    // sum of the applied deposits and withdrawals, kept apart from `tx_state` which
+    // `with_sorted_clients` may drop
+    applied_volume: f64,
+    // what partial withdrawals actually took, by tx ID, for `ledger_proof`. Also kept apart
+    // from `tx_state`
+    partial_amounts: HashMap<u32, f64>,
+}
+
+// where a client-grouped input is, see `Engine::with_sorted_clients`
+#[derive(Debug, Clone, Default)]
+struct SortedClients {
+    drop_finished: bool,
+    // client of the block being processed
+    current: Option<AccountId>,
+    // clients whose block has ended
+    finished: HashSet<AccountId>,
+    // whether an out of order client was already reported
+    warned: bool,
+}
+
+/// An account's balances right after an applied transaction, see `Engine::with_history`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BalanceSample {
+    /// Sequence index of the transaction (or the one that triggered an auto-resolve).
+    pub seq: u64,
+    pub available: f64,
+    pub held: f64,
+    pub total: f64,
+}
+
+/// Called with every entry the engine records and the affected account's state after it,
+/// see `Engine::with_hook`.
+pub type StateHook = Box<dyn FnMut(&TransactionEntry, &Account) + Send>;
+
+// shared so that shards (`clone_config`) call the same hook
+#[derive(Clone)]
+struct Hook(Arc<Mutex<StateHook>>);
+
+impl std::fmt::Debug for Hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Hook")
+    }
 }
 
 impl Engine {
@@ -20,33 +111,283 @@ impl Engine {
             accounts: HashMap::new(),
             transactions: Vec::new(),
             tx_state: HashMap::new(),
+            seen_tx_ids: HashSet::new(),
+            seq: 0,
+            last_entry: None,
+            step_entries: None,
+            event_log: true,
+            credit_limit: 0.0,
+            dispute_window: None,
+            allow_unlock: false,
+            release_held_on_unlock: false,
+            allow_redispute: true,
+            allow_cross_client_dispute: false,
+            partial_withdrawals: false,
+            max_amount: None,
+            skip_processed_files: false,
+            rounding: None,
+            auto_resolve_after: None,
+            chargeback_policy: ChargebackPolicy::default(),
+            lock_policy: LockPolicy::default(),
+            open_disputes: VecDeque::new(),
+            processed_files: HashSet::new(),
+            hook: None,
+            history: HashMap::new(),
+            sorted_clients: None,
+            applied_volume: 0.0,
+            partial_amounts: HashMap::new(),
+        }
+    }
+
+    /// Enables or disables the append-only event log (enabled by default).
+    ///
+    /// With the log disabled, memory no longer grows with every processed line, only with
+    /// the number of distinct tx IDs (`tx_state` and the duplicate index) and clients.
+    /// The tradeoff is that `get_transactions` stays empty, so `--log` output and any
+    /// replay/recovery from the event source are unavailable.
+    pub fn with_event_log(mut self, enabled: bool) -> Self {
+        self.event_log = enabled;
+        self
+    }
+
+    /// Overdraft granted to every account (existing and newly created): withdrawals are
+    /// allowed until `available` reaches `-credit_limit`. Defaults to no overdraft.
+    pub fn with_credit_limit(mut self, credit_limit: f64) -> Self {
+        self.credit_limit = credit_limit;
+        self.accounts
+            .values_mut()
+            .for_each(|account| *account = account.with_credit_limit(credit_limit));
+        self
+    }
+
+    /// Only transactions applied within the last `window` processed transactions can be
+    /// disputed, older ones are ignored with `IgnoredOutsideWindow`. Defaults to no window.
+    pub fn with_dispute_window(mut self, window: u64) -> Self {
+        self.dispute_window = Some(window);
+        self
+    }
+
+    /// Lets `unlock` transactions reinstate locked accounts. Unlocking is privileged, so it's
+    /// off by default and unlock lines are ignored with `IgnoredUnauthorized`.
+    pub fn with_allow_unlock(mut self, allow_unlock: bool) -> Self {
+        self.allow_unlock = allow_unlock;
+        self
+    }
+
+    /// Makes an unlock also release everything the account still holds back to `available`,
+    /// closing its open disputes as resolved, so it comes back in a clean state: a later
+    /// resolve or chargeback of those disputes is ignored with `IgnoredNotDisputed`. Each
+    /// released dispute gets an `AutoResolved` entry right after the unlock's. Off by default,
+    /// held funds stay held until their disputes are settled.
+    pub fn with_release_held_on_unlock(mut self, release: bool) -> Self {
+        self.release_held_on_unlock = release;
+        self
+    }
+
+    /// Whether a resolved transaction can be disputed again (the default). Without it, a
+    /// dispute of a transaction whose dispute was resolved is ignored with
+    /// `IgnoredNotDisputed`.
+    pub fn with_allow_redispute(mut self, allow: bool) -> Self {
+        self.allow_redispute = allow;
+        self
+    }
+
+    /// Lets a dispute come from another client than the transaction's (e.g. a back-office
+    /// user acting on the client's behalf). The dispute is then applied to, and logged for,
+    /// the owning client; the resolve or chargeback still has to come from the owner. Off by
+    /// default, such disputes are ignored with `IgnoredMissingReference`. Sharded processing
+    /// routes by the disputing client, so only use it with a single engine.
+    pub fn with_allow_cross_client_dispute(mut self, allow: bool) -> Self {
+        self.allow_cross_client_dispute = allow;
+        self
+    }
+
+    /// Lets a withdrawal larger than the available funds drain them instead of failing: it's
+    /// logged with its requested amount and the `AppliedPartial` status, and a later dispute
+    /// reverses what was actually withdrawn. With nothing available it still fails with
+    /// `FailedInsufficientFunds`. Off by default.
+    pub fn with_partial_withdrawals(mut self, partial: bool) -> Self {
+        self.partial_withdrawals = partial;
+        self
+    }
+
+    /// Deposits and withdrawals above `max_amount` are rejected with `FailedAmountTooLarge`
+    /// (guards against fat-finger or corrupted inputs). Defaults to unlimited.
+    pub fn with_max_amount(mut self, max_amount: f64) -> Self {
+        self.max_amount = Some(max_amount);
+        self
+    }
+
+    /// Quantizes every incoming amount to 4 decimal places with `mode` before it's applied (and
+    /// logged), so balances only ever hold output precision. Defaults to amounts as parsed.
+    pub fn with_rounding(mut self, mode: RoundingMode) -> Self {
+        self.rounding = Some(mode);
+        self
+    }
+
+    /// Calls `hook` after each processed transaction (applied or not, and for synthetic
+    /// entries such as auto-resolves) with its entry and the account's resulting state, e.g.
+    /// to publish state changes. Runs whether or not the event log is kept. Sharded engines
+    /// share the hook, calls for different clients may then come from different threads.
+    pub fn with_hook(
+        mut self,
+        hook: impl FnMut(&TransactionEntry, &Account) + Send + 'static,
+    ) -> Self {
+        self.hook = Some(Hook(Arc::new(Mutex::new(Box::new(hook)))));
+        self
+    }
+
+    /// Records a balance sample for these clients after each transaction applied to their
+    /// account, read back with `history`. Other clients cost nothing.
+    pub fn with_history(mut self, client_ids: HashSet<AccountId>) -> Self {
+        self.history = client_ids
+            .into_iter()
+            .map(|client| (client, Vec::new()))
+            .collect();
+        self
+    }
+
+    /// Whether a chargeback locks the account (the default) or only reverses the funds.
+    pub fn with_chargeback_policy(mut self, policy: ChargebackPolicy) -> Self {
+        self.chargeback_policy = policy;
+        self
+    }
+
+    /// Which transactions still apply to a locked account, nothing but `unlock` by default.
+    pub fn with_lock_policy(mut self, policy: LockPolicy) -> Self {
+        self.lock_policy = policy;
+        self
+    }
+
+    /// Resolves disputes still open `events` processed transactions after they were opened,
+    /// releasing the held funds. Each one is logged as a synthetic `resolve` entry with the
+    /// `AutoResolved` status, ahead of the transaction that triggered it. Disputes on locked
+    /// accounts are left alone, unless the lock policy lets resolves through. Disabled by
+    /// default.
+    pub fn with_auto_resolve_after(mut self, events: u64) -> Self {
+        self.auto_resolve_after = Some(events);
+        self.rebuild_open_disputes();
+        self
+    }
+
+    /// Hints that transactions come grouped by client, each client's in one contiguous block
+    /// (e.g. a file sorted by client). A client's block ends when another client's
+    /// transaction arrives; the first one showing up again afterwards is logged as a warning,
+    /// such transactions are processed as usual.
+    ///
+    /// With `drop_finished`, the dispute state of a client's transactions is dropped as soon
+    /// as its block ends, so memory only holds one client's disputable transactions at a
+    /// time. Their tx IDs still count as used, but a later dispute, resolve or chargeback of
+    /// them is ignored with `IgnoredMissingReference`, and funds held by a dispute left open
+    /// stay held. Only use it when nothing references a previous block, e.g. not with files
+    /// disputing transactions of an earlier file. `aggregate` and `ledger_proof` don't read
+    /// the dropped state and are unaffected.
+    pub fn with_sorted_clients(mut self, drop_finished: bool) -> Self {
+        self.sorted_clients = Some(SortedClients {
+            drop_finished,
+            ..SortedClients::default()
+        });
+        self
+    }
+
+    /// Pre-creates these clients' accounts as locked (e.g. frozen in an external system), so
+    /// their transactions are ignored with `IgnoredLocked` as if a chargeback had happened.
+    pub fn with_locked_accounts(mut self, clients: impl IntoIterator<Item = AccountId>) -> Self {
+        for client in clients {
+            let credit_limit = self.credit_limit;
+            self.accounts
+                .entry(client)
+                .or_insert_with(|| Account::new(client).with_credit_limit(credit_limit))
+                .lock();
         }
+        self
     }
 
-    pub fn process(&mut self, tx: Transaction) {
+    /// Skips input files whose content (hashed by the consumer) was already processed, with a
+    /// warning. Duplicate tx IDs already make a repeated deposit or withdrawal harmless, but
+    /// disputes, resolves and chargebacks reference existing IDs and would apply again.
+    /// Off by default.
+    pub fn with_skip_processed_files(mut self, skip: bool) -> Self {
+        self.skip_processed_files = skip;
+        self
+    }
+
+    /// Whether a file with this content hash was already processed.
+    pub fn already_processed(&self, hash: u64) -> bool {
+        self.processed_files.contains(&hash)
+    }
+
+    pub(crate) fn skips_processed_files(&self) -> bool {
+        self.skip_processed_files
+    }
+
+    pub(crate) fn processed_files(&self) -> &HashSet<u64> {
+        &self.processed_files
+    }
+
+    /// Returns `false` if the hash was already recorded.
+    pub(crate) fn record_processed_file(&mut self, hash: u64) -> bool {
+        self.processed_files.insert(hash)
+    }
+
+    pub fn process(&mut self, mut tx: Transaction) {
+        if let Some(mode) = self.rounding {
+            tx.amount = tx.amount.map(|amount| mode.quantize(amount));
+        }
+        let seq = self.seq;
+        self.seq += 1;
+        self.expire_disputes(seq);
+        self.track_client_block(tx.client);
+
+        if self.allow_cross_client_dispute
+            && tx._type == TransactionType::Dispute
+            && let Some(state) = self.tx_state.get(&tx.tx)
+        {
+            tx.client = state.client;
+        }
+
         self.accounts
             .entry(tx.client)
-            .or_insert_with(|| Account::new(tx.client));
+            .or_insert_with(|| Account::new(tx.client).with_credit_limit(self.credit_limit));
 
         // negative state first, assume ignored due to chargeback lock
         // NOTE: this is used for logging, does not impact `account.is_locked()`
         let mut status = TransactionStatus::IgnoredLocked;
+        // disputes an unlock closed with `release_held_on_unlock`
+        let mut released = Vec::new();
+
+        // any deposit/withdrawal line claims its tx ID, whatever its outcome; disputes,
+        // resolves and chargebacks reference an ID instead, they never claim one nor count as
+        // duplicates of one
+        let duplicate = tx._type.introduces_tx_id() && !self.seen_tx_ids.insert(tx.tx);
 
         let account = self.accounts.get_mut(&tx.client).unwrap();
 
-        if account.is_locked() {
-            self.transactions.push(TransactionEntry { tx, status });
+        // transactions without a currency are in the implicit one and match any account
+        let currency_mismatch = matches!(
+            (tx.currency, account.currency()),
+            (Some(currency), Some(expected)) if currency != expected
+        );
+
+        if account.is_locked() && self.lock_policy.blocks(tx._type) {
+            self.append(TransactionEntry { seq, tx, status });
             return;
         }
 
+        // only deposits/withdrawals go through here: they introduce new tx IDs, so the O(1)
+        // duplicate index is checked before anything else
         let mut ensure_valid =
             |tx: Transaction, callable: &mut dyn FnMut() -> TransactionStatus| {
-                if self.transactions.iter().any(|entry| entry.tx.tx.eq(&tx.tx)) {
+                if duplicate {
                     status = TransactionStatus::FailedDuplicateTxID;
-                } else if let Some(amount) = tx.amount
-                    && &amount <= &0.0
-                {
+                } else if tx.validate().is_err() {
                     status = TransactionStatus::FailedInvalidAmount;
+                } else if let (Some(amount), Some(max)) = (tx.amount, self.max_amount)
+                    && amount > max
+                {
+                    status = TransactionStatus::FailedAmountTooLarge;
+                } else if currency_mismatch {
+                    status = TransactionStatus::FailedCurrencyMismatch;
                 } else {
                     status = callable();
                 }
@@ -55,45 +396,71 @@ impl Engine {
         match tx._type {
             TransactionType::Deposit => {
                 if let Some(amount) = tx.amount {
-                    ensure_valid(tx.clone(), &mut || {
+                    ensure_valid(tx, &mut || {
                         account.deposit(amount);
+                        self.applied_volume += amount;
                         self.tx_state.insert(
                             tx.tx,
                             TxState {
                                 client: tx.client,
+                                tx_type: tx._type,
+                                seq,
                                 amount,
                                 dispute_state: DisputeState::Normal,
+                                disputed_seq: 0,
+                                held: 0.0,
                             },
                         );
                         TransactionStatus::Applied
                     });
                 } else {
+                    // missing, or not a plain decimal (`1e2`, `inf`) and kept as written
                     status = TransactionStatus::FailedInvalidAmount;
                 }
             }
             TransactionType::Withdrawal => {
                 let Some(amount) = tx.amount else {
                     status = TransactionStatus::FailedInvalidAmount;
-                    self.transactions.push(TransactionEntry { tx, status });
+                    self.append(TransactionEntry { seq, tx, status });
                     return;
                 };
 
-                ensure_valid(tx.clone(), &mut || match account.withdraw(amount) {
-                    Ok(_) => {
-                        self.tx_state.insert(
-                            tx.tx,
-                            TxState {
-                                client: tx.client,
-                                amount,
-                                dispute_state: DisputeState::Normal,
-                            },
-                        );
-                        TransactionStatus::Applied
-                    }
-                    Err(e) => {
-                        warn!("Withdrawal error: {}", e);
-                        TransactionStatus::FailedInsufficientFunds
-                    }
+                ensure_valid(tx, &mut || {
+                    let (withdrawn, status) = match account.withdraw(amount) {
+                        Ok(_) => (amount, TransactionStatus::Applied),
+                        Err(e) => {
+                            let drained = if self.partial_withdrawals {
+                                account.withdraw_available(amount)
+                            } else {
+                                0.0
+                            };
+                            if drained <= 0.0 {
+                                tx_warn!(tx, "Withdrawal error: {}", e);
+                                return TransactionStatus::FailedInsufficientFunds;
+                            }
+                            tx_warn!(
+                                tx,
+                                "Partial withdrawal: {} short of the requested amount",
+                                amount - drained
+                            );
+                            self.partial_amounts.insert(tx.tx, drained);
+                            (drained, TransactionStatus::AppliedPartial)
+                        }
+                    };
+                    self.applied_volume += withdrawn;
+                    self.tx_state.insert(
+                        tx.tx,
+                        TxState {
+                            client: tx.client,
+                            tx_type: tx._type,
+                            seq,
+                            amount: withdrawn,
+                            dispute_state: DisputeState::Normal,
+                            disputed_seq: 0,
+                            held: 0.0,
+                        },
+                    );
+                    status
                 });
             }
             TransactionType::Dispute => {
@@ -102,22 +469,52 @@ impl Engine {
                     .get_mut(&tx.tx)
                     .and_then(|state| {
                         if state.client == tx.client && !state.is_under_dispute() {
-                            account
-                                .dispute(state.amount)
-                                .map(|_| {
-                                    state.dispute_state = DisputeState::Disputed;
-                                    TransactionStatus::Applied
-                                })
-                                .map_err(|e| {
-                                    warn!("Dispute error: {}", e);
-                                })
-                                .ok()
+                            if !self.allow_redispute
+                                && state.dispute_state == DisputeState::Resolved
+                            {
+                                tx_warn!(tx, "Dispute error: transaction was already resolved");
+                                return Some(TransactionStatus::IgnoredNotDisputed);
+                            }
+                            if self
+                                .dispute_window
+                                .is_some_and(|window| seq - state.seq > window)
+                            {
+                                tx_warn!(
+                                    tx,
+                                    "Dispute error: transaction is outside the dispute window"
+                                );
+                                return Some(TransactionStatus::IgnoredOutsideWindow);
+                            }
+                            let disputed = match state.tx_type {
+                                TransactionType::Withdrawal => {
+                                    account.dispute_withdrawal(state.amount);
+                                    Ok(())
+                                }
+                                _ => account.dispute(state.amount),
+                            };
+                            // the transaction exists, but its funds may have been withdrawn since
+                            Some(
+                                disputed
+                                    .map(|_| {
+                                        state.dispute_state = DisputeState::Disputed;
+                                        state.disputed_seq = seq;
+                                        state.held = state.amount;
+                                        if self.auto_resolve_after.is_some() {
+                                            self.open_disputes.push_back((seq, tx.tx));
+                                        }
+                                        TransactionStatus::Applied
+                                    })
+                                    .unwrap_or_else(|e| {
+                                        tx_warn!(tx, "Dispute error: {}", e);
+                                        TransactionStatus::FailedInsufficientAvailableForDispute
+                                    }),
+                            )
                         } else {
                             None
                         }
                     })
                     .unwrap_or_else(|| {
-                        warn!("Dispute error: no previous transaction found");
+                        tx_warn!(tx, "Dispute error: no previous transaction found");
                         TransactionStatus::IgnoredMissingReference
                     });
             }
@@ -126,23 +523,31 @@ impl Engine {
                     .tx_state
                     .get_mut(&tx.tx)
                     .and_then(|state| {
-                        if state.client == tx.client && state.is_under_dispute() {
+                        if state.client != tx.client {
+                            None
+                        } else if !state.is_under_dispute() {
+                            // the reference is right, the transaction just isn't disputed now
+                            tx_warn!(tx, "Resolve error: transaction is not under dispute");
+                            Some(TransactionStatus::IgnoredNotDisputed)
+                        } else {
                             account
-                                .resolve(state.amount)
+                                .resolve(state.held)
                                 .map(|_| {
                                     state.dispute_state = DisputeState::Resolved;
+                                    state.held = 0.0;
                                     TransactionStatus::Applied
                                 })
                                 .map_err(|e| {
-                                    warn!("Resolve error: {}", e);
+                                    tx_warn!(tx, "Resolve error: {}", e);
                                 })
                                 .ok()
-                        } else {
-                            None
                         }
                     })
                     .unwrap_or_else(|| {
-                        warn!("Resolve error: no previous transaction in dispute state found");
+                        tx_warn!(
+                            tx,
+                            "Resolve error: no previous transaction in dispute state found"
+                        );
                         TransactionStatus::IgnoredMissingReference
                     });
             }
@@ -151,30 +556,250 @@ impl Engine {
                     .tx_state
                     .get_mut(&tx.tx)
                     .and_then(|state| {
-                        if state.client == tx.client && state.is_under_dispute() {
+                        if state.client != tx.client {
+                            None
+                        } else if !state.is_under_dispute() {
+                            // the reference is right, the transaction just isn't disputed now
+                            tx_warn!(tx, "Chargeback error: transaction is not under dispute");
+                            Some(TransactionStatus::IgnoredNotDisputed)
+                        } else {
                             account
-                                .chargeback(state.amount)
+                                .chargeback_with(state.held, self.chargeback_policy)
                                 .map(|_| {
                                     state.dispute_state = DisputeState::Chargeback;
+                                    state.held = 0.0;
                                     TransactionStatus::Applied
                                 })
                                 .map_err(|e| {
-                                    warn!("Chargeback error: {}", e);
+                                    tx_warn!(tx, "Chargeback error: {}", e);
                                 })
                                 .ok()
-                        } else {
-                            None
                         }
                     })
                     .unwrap_or_else(|| {
-                        warn!("Chargeback error: no previous transaction in dispute state found");
+                        tx_warn!(
+                            tx,
+                            "Chargeback error: no previous transaction in dispute state found"
+                        );
                         TransactionStatus::IgnoredMissingReference
                     });
             }
+            TransactionType::Unlock => {
+                status = if self.allow_unlock {
+                    account.unlock_with(self.release_held_on_unlock);
+                    if self.release_held_on_unlock {
+                        for (&tx_id, state) in self.tx_state.iter_mut() {
+                            if state.client == tx.client && state.is_under_dispute() {
+                                state.dispute_state = DisputeState::Resolved;
+                                state.held = 0.0;
+                                released.push(tx_id);
+                            }
+                        }
+                        released.sort_unstable();
+                    }
+                    TransactionStatus::Applied
+                } else {
+                    tx_warn!(tx, "Unlock error: unlocking accounts is not allowed");
+                    TransactionStatus::IgnoredUnauthorized
+                };
+            }
+        }
+
+        if matches!(
+            status,
+            TransactionStatus::Applied | TransactionStatus::AppliedPartial
+        ) && tx._type.introduces_tx_id()
+            && let Some(currency) = tx.currency
+        {
+            account.adopt_currency(currency);
+        }
+
+        // Append an event to the event source. Always (unless the event log is disabled).
+        self.append(TransactionEntry { seq, tx, status });
+
+        if !released.is_empty() {
+            for tx_id in released {
+                self.append(TransactionEntry {
+                    seq,
+                    tx: Transaction::new(TransactionType::Resolve, tx.client, tx_id, None),
+                    status: TransactionStatus::AutoResolved,
+                });
+            }
+            self.rebuild_open_disputes();
+        }
+    }
+
+    fn expire_disputes(&mut self, seq: u64) {
+        let Some(after) = self.auto_resolve_after else {
+            return;
+        };
+        let locked_blocks_resolve = self.lock_policy.blocks(TransactionType::Resolve);
+        while let Some(&(opened, tx_id)) = self.open_disputes.front()
+            && seq - opened > after
+        {
+            self.open_disputes.pop_front();
+            let Some(state) = self.tx_state.get_mut(&tx_id) else {
+                continue;
+            };
+            // resolved or charged back in the meantime
+            if !state.is_under_dispute() || state.disputed_seq != opened {
+                continue;
+            }
+            let Some(account) = self.accounts.get_mut(&state.client) else {
+                continue;
+            };
+            if account.is_locked() && locked_blocks_resolve || account.resolve(state.held).is_err()
+            {
+                continue;
+            }
+            state.dispute_state = DisputeState::Resolved;
+            state.held = 0.0;
+            let client = state.client;
+            self.append(TransactionEntry {
+                seq,
+                tx: Transaction::new(TransactionType::Resolve, client, tx_id, None),
+                status: TransactionStatus::AutoResolved,
+            });
+        }
+    }
+
+    /// Resolves every open dispute at once, e.g. to close out the day: held funds go back to
+    /// `available` and each gets an `AutoResolved` entry. Disputes on locked accounts are left
+    /// open. The entries share the next sequence index, as if one transaction had triggered
+    /// them. Returns how many disputes were resolved.
+    pub fn resolve_all_open(&mut self) -> usize {
+        let mut open: Vec<u32> = self
+            .tx_state
+            .iter()
+            .filter(|(_, state)| state.is_under_dispute())
+            .map(|(&tx_id, _)| tx_id)
+            .collect();
+        open.sort_unstable();
+        let seq = self.seq;
+        let mut resolved = 0;
+        for tx_id in open {
+            let state = self
+                .tx_state
+                .get_mut(&tx_id)
+                .expect("collected from tx_state");
+            let Some(account) = self.accounts.get_mut(&state.client) else {
+                continue;
+            };
+            if account.is_locked() || account.resolve(state.held).is_err() {
+                continue;
+            }
+            state.dispute_state = DisputeState::Resolved;
+            state.held = 0.0;
+            let client = state.client;
+            self.append(TransactionEntry {
+                seq,
+                tx: Transaction::new(TransactionType::Resolve, client, tx_id, None),
+                status: TransactionStatus::AutoResolved,
+            });
+            resolved += 1;
+        }
+        if resolved > 0 {
+            self.seq += 1;
+            self.rebuild_open_disputes();
+        }
+        resolved
+    }
+
+    /// Empties the engine for the next independent batch, keeping its configuration and the
+    /// capacity of its maps: accounts (including `with_locked_accounts` ones), the event log,
+    /// the transaction state and processed file hashes are gone, and the sequence index
+    /// starts over at 0.
+    pub fn clear(&mut self) {
+        self.accounts.clear();
+        self.transactions.clear();
+        self.tx_state.clear();
+        self.seen_tx_ids.clear();
+        self.seq = 0;
+        self.last_entry = None;
+        self.open_disputes.clear();
+        self.processed_files.clear();
+        self.applied_volume = 0.0;
+        self.partial_amounts.clear();
+        self.history.values_mut().for_each(Vec::clear);
+        if let Some(sorted) = &mut self.sorted_clients {
+            *sorted = SortedClients {
+                drop_finished: sorted.drop_finished,
+                ..SortedClients::default()
+            };
+        }
+    }
+
+    // ends the previous client's block when `client` differs, see `with_sorted_clients`
+    fn track_client_block(&mut self, client: AccountId) {
+        let Some(sorted) = &mut self.sorted_clients else {
+            return;
+        };
+        let Some(previous) = sorted.current.replace(client) else {
+            return;
+        };
+        if previous == client {
+            return;
+        }
+        sorted.finished.insert(previous);
+        if !sorted.warned && sorted.finished.contains(&client) {
+            sorted.warned = true;
+            warn!(
+                client = client;
+                "Client {} shows up again after its block ended, the input isn't grouped by client",
+                client
+            );
+        }
+        if sorted.drop_finished {
+            self.tx_state.retain(|_, state| state.client != previous);
+        }
+    }
+
+    // recomputes the open dispute queue from the transaction state (restore, shards, merges)
+    fn rebuild_open_disputes(&mut self) {
+        self.open_disputes.clear();
+        if self.auto_resolve_after.is_none() {
+            return;
         }
+        let mut open: Vec<(u64, u32)> = self
+            .tx_state
+            .iter()
+            .filter(|(_, state)| state.is_under_dispute())
+            .map(|(&tx_id, state)| (state.disputed_seq, tx_id))
+            .collect();
+        open.sort_unstable();
+        self.open_disputes.extend(open);
+    }
 
-        // Append an event to the event source. Always.
-        self.transactions.push(TransactionEntry { tx, status });
+    fn append(&mut self, entry: TransactionEntry) {
+        self.last_entry = Some(entry);
+        if let Some(step) = &mut self.step_entries {
+            step.push(entry);
+        }
+        if let Some(Hook(hook)) = &self.hook
+            && let Some(account) = self.accounts.get(&entry.tx.client)
+        {
+            // a hook that panicked once still gets the next entries
+            let mut hook = hook.lock().unwrap_or_else(|e| e.into_inner());
+            hook(&entry, account);
+        }
+        if matches!(
+            entry.status,
+            TransactionStatus::Applied
+                | TransactionStatus::AppliedPartial
+                | TransactionStatus::AutoResolved
+        ) && let Some(samples) = self.history.get_mut(&entry.tx.client)
+            && let Some(account) = self.accounts.get(&entry.tx.client)
+        {
+            samples.push(BalanceSample {
+                seq: entry.seq,
+                available: account.available(),
+                held: account.held(),
+                total: account.total(),
+            });
+        }
+        if self.event_log {
+            self.transactions.push(entry);
+        }
     }
 
     pub fn get_account(&self, account_id: AccountId) -> Option<&Account> {
@@ -185,120 +810,865 @@ impl Engine {
         &self.accounts
     }
 
+    /// Number of distinct clients with an account.
+    pub fn client_count(&self) -> usize {
+        self.accounts.len()
+    }
+
     pub fn get_transactions(&self) -> &Vec<TransactionEntry> {
         &self.transactions
     }
-}
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum DisputeState {
-    Normal,
-    Disputed,
-    Resolved,
-    Chargeback,
-}
 
-#[derive(Debug, Clone, Copy)]
-struct TxState {
-    client: AccountId,
-    amount: f64,
-    dispute_state: DisputeState,
-}
+    /// The balance samples recorded for `client`, oldest first. Empty when the client isn't
+    /// tracked (see `with_history`) or nothing was applied to its account yet.
+    pub fn history(&self, client: AccountId) -> &[BalanceSample] {
+        self.history.get(&client).map_or(&[], Vec::as_slice)
+    }
 
-impl TxState {
-    fn is_under_dispute(&self) -> bool {
-        matches!(self.dispute_state, DisputeState::Disputed)
+    /// Captures the state needed to resume processing later: accounts (with their volumes),
+    /// transaction state, the duplicate index and the sequence. The event log and
+    /// configuration are not included, configure the restored engine with the usual builders.
+    pub fn snapshot(&self) -> EngineSnapshot {
+        EngineSnapshot {
+            accounts: self.accounts.values().copied().collect(),
+            volumes: self
+                .accounts
+                .values()
+                .map(|account| (account.client(), account.volumes()))
+                .collect(),
+            tx_state: self.tx_state.clone(),
+            seen_tx_ids: self.seen_tx_ids.clone(),
+            seq: self.seq,
+            applied_volume: Some(self.applied_volume),
+        }
     }
-}
 
-impl Default for Engine {
-    fn default() -> Self {
-        Self::new()
+    pub fn restore(snapshot: EngineSnapshot) -> Self {
+        let applied_volume = snapshot
+            .applied_volume
+            .unwrap_or_else(|| snapshot.tx_state.values().map(|state| state.amount).sum());
+        let mut engine = Self {
+            applied_volume,
+            accounts: snapshot
+                .accounts
+                .into_iter()
+                .map(|account| (account.client(), account))
+                .collect(),
+            tx_state: snapshot.tx_state,
+            seen_tx_ids: snapshot.seen_tx_ids,
+            seq: snapshot.seq,
+            ..Self::new()
+        };
+        engine
+            .accounts
+            .values_mut()
+            .for_each(|account| account.set_disputes_open(0));
+        for state in engine.tx_state.values().filter(|s| s.is_under_dispute()) {
+            if let Some(account) = engine.accounts.get_mut(&state.client) {
+                account.set_disputes_open(account.disputes_open() + 1);
+            }
+        }
+        for (client, volumes) in snapshot.volumes {
+            if let Some(account) = engine.accounts.get_mut(&client) {
+                account.set_volumes(volumes);
+            }
+        }
+        engine
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::any::Any;
+    /// Tallies statuses across the event log (empty when the log is disabled), plus the
+    /// number of clients and locked accounts.
+    pub fn stats(&self) -> ProcessingStats {
+        let mut stats = ProcessingStats::default();
+        self.transactions
+            .iter()
+            .for_each(|entry| stats.record(entry.status));
+        stats.clients = self.accounts.len() as u64;
+        stats.locked_accounts = self.accounts.values().filter(|a| a.is_locked()).count() as u64;
+        stats
+    }
 
-    use super::*;
+    /// Totals across all accounts. The applied volume is summed as transactions apply, so it
+    /// is available with the event log disabled too.
+    pub fn aggregate(&self) -> AggregateSummary {
+        let mut summary = AggregateSummary {
+            clients: self.accounts.len() as u64,
+            ..AggregateSummary::default()
+        };
+        self.accounts.values().for_each(|account| {
+            summary.available += account.available();
+            summary.held += account.held();
+            summary.locked_accounts += account.is_locked() as u64;
+        });
+        summary.applied_volume = self.applied_volume;
+        summary
+    }
 
-    fn tx(t: TransactionType, client: u16, tx_id: u32, amount: Option<f64>) -> Transaction {
-        Transaction::new(t, client, tx_id, amount)
+    /// Recomputes the net flow of money from the event log and compares it with the sum of
+    /// the account totals, see `LedgerProof`: a mismatch means the balances moved without a
+    /// logged reason, i.e. an engine bug. Only meaningful when the log covers the whole
+    /// history, not with `with_event_log(false)` or after `restore`. Each balance may drift
+    /// by the output rounding, so the tolerance grows with the number of accounts.
+    pub fn ledger_proof(&self) -> LedgerProof {
+        // (type, amount moved) of the applied deposits and withdrawals, by tx ID
+        let mut moved: HashMap<u32, (TransactionType, f64)> = HashMap::new();
+        let mut flows = 0.0;
+        for entry in &self.transactions {
+            let applied = matches!(
+                entry.status,
+                TransactionStatus::Applied | TransactionStatus::AppliedPartial
+            );
+            if !applied {
+                continue;
+            }
+            let tx = entry.tx;
+            match tx._type {
+                TransactionType::Deposit | TransactionType::Withdrawal => {
+                    // a partial withdrawal is logged with the requested amount, the state
+                    // holds what was actually withdrawn
+                    let amount = match self.partial_amounts.get(&tx.tx) {
+                        Some(&withdrawn) if entry.status == TransactionStatus::AppliedPartial => {
+                            withdrawn
+                        }
+                        _ => tx.amount.unwrap_or_default(),
+                    };
+                    moved.insert(tx.tx, (tx._type, amount));
+                    flows += match tx._type {
+                        TransactionType::Deposit => amount,
+                        _ => -amount,
+                    };
+                }
+                TransactionType::Dispute => {
+                    if let Some(&(TransactionType::Withdrawal, amount)) = moved.get(&tx.tx) {
+                        flows += amount;
+                    }
+                }
+                TransactionType::Chargeback => {
+                    if let Some(&(_, amount)) = moved.get(&tx.tx) {
+                        flows -= amount;
+                    }
+                }
+                TransactionType::Resolve | TransactionType::Unlock => {}
+            }
+        }
+        let totals: f64 = self.accounts.values().map(|account| account.total()).sum();
+        let tolerance = BALANCE_TOLERANCE * self.accounts.len().max(1) as f64;
+        LedgerProof {
+            flows,
+            totals,
+            balanced: (flows - totals).abs() <= tolerance,
+            tolerance,
+        }
     }
 
-    #[test]
-    fn chargeback_attempt_while_locked_is_rejected() {
+    /// Rebuilds an engine (default configuration) by processing the transactions of an event
+    /// log in order, e.g. one kept from another run or dumped with `--log`. Recomputed
+    /// statuses that differ from the recorded ones are reported as warnings: the log came
+    /// from a differently configured engine, or processing isn't deterministic.
+    pub fn replay(entries: &[TransactionEntry]) -> Engine {
         let mut engine = Engine::new();
-        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
-        engine.process(tx(TransactionType::Dispute, 1, 1, None));
-        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
-        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        for entry in entries {
+            if let Err(mismatch) = engine.process_entry(*entry) {
+                tx_warn!(
+                    entry.tx,
+                    "Replay mismatch: recorded {:?}, recomputed {:?}",
+                    mismatch.recorded,
+                    mismatch.recomputed
+                );
+            }
+        }
+        engine
+    }
 
-        let account = engine.get_account(1).unwrap();
-        assert_eq!(account.available(), 0.0);
-        assert_eq!(account.held(), 0.0);
-        assert_eq!(account.total(), 0.0);
-        assert!(account.is_locked());
-        assert!(
-            engine
-                .transactions
-                .iter()
-                .filter(|stored_tx| stored_tx.tx._type == TransactionType::Chargeback)
-                .count()
-                == 2
-        );
-        println!("{:?}", engine.transactions);
-        assert!(
-            engine
-                .transactions
-                .iter()
-                .filter(
-                    |stored_tx| stored_tx.tx._type == TransactionType::Chargeback
-                        && stored_tx.status == TransactionStatus::IgnoredLocked
-                )
-                .count()
-                == 1
-        );
+    /// Processes `transactions` lazily, one at a time as entries are pulled, yielding every
+    /// entry each one recorded (kept even without the event log) so outcomes can be inspected
+    /// inline instead of re-scanning the log. That's the transaction's own entry, preceded by
+    /// the `AutoResolved` entries of the disputes it expired with `with_auto_resolve_after`.
+    /// Transactions left when the iterator is dropped aren't processed.
+    pub fn apply_all<'a, I>(
+        &'a mut self,
+        transactions: I,
+    ) -> impl Iterator<Item = TransactionEntry> + 'a
+    where
+        I: IntoIterator<Item = Transaction>,
+        I::IntoIter: 'a,
+    {
+        transactions.into_iter().flat_map(|tx| {
+            self.step_entries = Some(Vec::new());
+            self.process(tx);
+            self.step_entries
+                .take()
+                .expect("the step's entries are only taken here")
+        })
     }
 
-    #[test]
-    fn zero_amount_is_rejected() {
-        let mut engine = Engine::new();
-        engine.process(tx(TransactionType::Deposit, 1, 1, Some(0.0)));
+    /// Processes the entry's transaction and checks the recomputed status against the one
+    /// the entry recorded, e.g. to re-ingest an audited log. The transaction applies either
+    /// way; a mismatch means the log came from a differently configured engine, was
+    /// tampered with, or processing isn't deterministic. A recorded `AutoResolved` entry
+    /// processes as a plain resolve and is expected to apply.
+    pub fn process_entry(&mut self, entry: TransactionEntry) -> Result<(), StatusMismatch> {
+        self.process(entry.tx);
+        let recomputed = self
+            .last_entry
+            .expect("processing always records an entry")
+            .status;
+        let expected = match entry.status {
+            TransactionStatus::AutoResolved => TransactionStatus::Applied,
+            status => status,
+        };
+        if recomputed == expected {
+            return Ok(());
+        }
+        Err(StatusMismatch {
+            tx: entry.tx,
+            recorded: entry.status,
+            recomputed,
+        })
+    }
 
-        let account = engine.get_account(1).unwrap();
-        assert_eq!(account.available(), 0.0);
-        assert_eq!(account.held(), 0.0);
-        assert_eq!(account.total(), 0.0);
-        assert!(!account.is_locked());
+    /// Accounts whose balances or lock differ between this engine (before) and `other`
+    /// (after), e.g. a reprocessing with a fixed rule, sorted by client. Amounts within half
+    /// of the last output decimal place count as equal.
+    pub fn diff(&self, other: &Engine) -> Vec<AccountDiff> {
+        let mut clients: Vec<AccountId> = self
+            .accounts
+            .keys()
+            .chain(
+                other
+                    .accounts
+                    .keys()
+                    .filter(|c| !self.accounts.contains_key(c)),
+            )
+            .copied()
+            .collect();
+        clients.sort_unstable();
+        clients
+            .into_iter()
+            .map(|client| AccountDiff {
+                client,
+                before: self.accounts.get(&client).copied(),
+                after: other.accounts.get(&client).copied(),
+            })
+            .filter(AccountDiff::changed)
+            .collect()
     }
 
-    #[test]
-    fn negative_amount_is_rejected() {
-        let mut engine = Engine::new();
-        engine.process(tx(TransactionType::Deposit, 1, 1, Some(-100.0)));
+    /// Checks every account for `available + held == total` (within half of the last output
+    /// decimal place) and a non-negative `held`. A violation means an engine bug, not bad input.
+    pub fn verify_invariants(&self) -> Result<(), InvariantViolation> {
+        for account in self.accounts.values() {
+            if (account.available() + account.held() - account.total()).abs() > BALANCE_TOLERANCE {
+                return Err(InvariantViolation::BalanceMismatch {
+                    client: account.client(),
+                    available: account.available(),
+                    held: account.held(),
+                    total: account.total(),
+                });
+            }
+            if account.held() < -BALANCE_TOLERANCE {
+                return Err(InvariantViolation::NegativeHeld {
+                    client: account.client(),
+                    held: account.held(),
+                });
+            }
+        }
+        Ok(())
+    }
 
-        let account = engine.get_account(1).unwrap();
-        assert_eq!(account.available(), 0.0);
-        assert_eq!(account.held(), 0.0);
-        assert_eq!(account.total(), 0.0);
-        assert!(!account.is_locked());
+    /// Clients with a balance that has non-zero digits past the cents (e.g. `10.0001`),
+    /// sorted. Amounts carry four decimal places, real currencies usually two. `available`
+    /// and `held` are checked too, their dust may cancel out in `total`.
+    pub fn dust_accounts(&self) -> Vec<AccountId> {
+        let is_dust = |balance: f64| (balance * 10_000.0).round() % 100.0 != 0.0;
+        let mut dust: Vec<AccountId> = self
+            .accounts
+            .values()
+            .filter(|a| is_dust(a.available()) || is_dust(a.held()) || is_dust(a.total()))
+            .map(|account| account.client())
+            .collect();
+        dust.sort_unstable();
+        dust
     }
 
-    #[test]
-    fn duplicate_transactions_ids_are_rejected() {
-        let mut engine = Engine::new();
-        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
-        engine.process(tx(TransactionType::Withdrawal, 1, 1, Some(50.0)));
+    /// A client's entries from the event log, in chronological order (linear scan).
+    pub fn transactions_for(&self, client: AccountId) -> Vec<&TransactionEntry> {
+        self.transactions
+            .iter()
+            .filter(|entry| entry.tx.client == client)
+            .collect()
+    }
 
-        let account = engine.get_account(1).unwrap();
+    /// Every entry from the event log referencing `tx_id`, whatever its type and client: the
+    /// deposit/withdrawal then each dispute, resolve and chargeback, in chronological order.
+    pub fn dispute_trail(&self, tx_id: u32) -> Vec<&TransactionEntry> {
+        self.transactions
+            .iter()
+            .filter(|entry| entry.tx.tx == tx_id)
+            .collect()
+    }
+
+    /// What makes up the client's `held`: each transaction of theirs under dispute, with the
+    /// amount its dispute holds, by ascending tx ID. Empty without open disputes.
+    pub fn held_breakdown(&self, client: AccountId) -> Vec<(u32, f64)> {
+        let mut held: Vec<(u32, f64)> = self
+            .tx_state
+            .iter()
+            .filter(|(_, state)| state.client == client && state.is_under_dispute())
+            .map(|(&tx_id, state)| (tx_id, state.held))
+            .collect();
+        held.sort_by_key(|&(tx_id, _)| tx_id);
+        held
+    }
+
+    /// Marks a tx ID as taken without processing anything, so a later deposit/withdrawal
+    /// using it is rejected as a duplicate. Used when the ID was claimed on another shard.
+    pub(crate) fn reserve_tx_id(&mut self, tx_id: u32) {
+        self.seen_tx_ids.insert(tx_id);
+    }
+
+    pub(crate) fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Aligns the sequence with the global one assigned by the shard router, so sequence
+    /// based rules (e.g. the dispute window) behave as on a single engine.
+    pub(crate) fn sync_seq(&mut self, seq: u64) {
+        self.seq = seq;
+    }
+
+    /// Splits the engine into `shards` engines with the same configuration, partitioning
+    /// accounts and transaction state by `client % shards`. The existing event log stays
+    /// with the first shard and every shard knows all the tx IDs seen so far.
+    pub(crate) fn into_shards(self, shards: usize) -> Vec<Engine> {
+        let shard_of = |client: AccountId| client as usize % shards;
+        let mut engines: Vec<Engine> = (0..shards)
+            .map(|_| Engine {
+                seen_tx_ids: self.seen_tx_ids.clone(),
+                seq: self.seq,
+                ..self.clone_config()
+            })
+            .collect();
+        for (id, account) in self.accounts {
+            engines[shard_of(id)].accounts.insert(id, account);
+        }
+        for (id, state) in self.tx_state {
+            engines[shard_of(state.client)].tx_state.insert(id, state);
+        }
+        for (id, samples) in self.history {
+            engines[shard_of(id)].history.insert(id, samples);
+        }
+        engines[0].transactions = self.transactions;
+        engines[0].processed_files = self.processed_files;
+        engines[0].applied_volume = self.applied_volume;
+        engines[0].partial_amounts = self.partial_amounts;
+        engines
+            .iter_mut()
+            .for_each(|engine| engine.rebuild_open_disputes());
+        engines
+    }
+
+    /// Folds another engine (e.g. a shard, or a run over another slice of the clients) into
+    /// this one. The event logs are interleaved by sequence index, entries sharing one keep
+    /// their order: shards fed by one router merge back into the log a single engine would
+    /// have written, whatever order they finished in.
+    ///
+    /// The engines must have split the work by client: an account or an applied transaction
+    /// present in both means a bad split and is rejected before anything is merged. Seen tx
+    /// IDs are simply combined, failed lines claim IDs too and may legitimately repeat.
+    pub fn merge(&mut self, other: Engine) -> Result<(), MergeError> {
+        if let Some(&client) = other
+            .accounts
+            .keys()
+            .find(|c| self.accounts.contains_key(c))
+        {
+            return Err(MergeError::AccountCollision(client));
+        }
+        if let Some(&tx) = other
+            .tx_state
+            .keys()
+            .find(|tx| self.tx_state.contains_key(tx))
+        {
+            return Err(MergeError::TxIdCollision(tx));
+        }
+        self.accounts.extend(other.accounts);
+        self.tx_state.extend(other.tx_state);
+        self.seen_tx_ids.extend(other.seen_tx_ids);
+        self.transactions.extend(other.transactions);
+        // both logs are already in sequence order, the stable sort merges the two runs
+        self.transactions.sort_by_key(|entry| entry.seq);
+        self.processed_files.extend(other.processed_files);
+        self.applied_volume += other.applied_volume;
+        self.partial_amounts.extend(other.partial_amounts);
+        for (client, samples) in other.history {
+            self.history.entry(client).or_default().extend(samples);
+        }
+        self.seq = self.seq.max(other.seq);
+        self.rebuild_open_disputes();
+        Ok(())
+    }
+
+    fn clone_config(&self) -> Engine {
+        Engine {
+            event_log: self.event_log,
+            credit_limit: self.credit_limit,
+            dispute_window: self.dispute_window,
+            allow_unlock: self.allow_unlock,
+            release_held_on_unlock: self.release_held_on_unlock,
+            allow_redispute: self.allow_redispute,
+            allow_cross_client_dispute: self.allow_cross_client_dispute,
+            partial_withdrawals: self.partial_withdrawals,
+            max_amount: self.max_amount,
+            skip_processed_files: self.skip_processed_files,
+            rounding: self.rounding,
+            auto_resolve_after: self.auto_resolve_after,
+            chargeback_policy: self.chargeback_policy,
+            lock_policy: self.lock_policy,
+            hook: self.hook.clone(),
+            sorted_clients: self.sorted_clients.as_ref().map(|sorted| SortedClients {
+                drop_finished: sorted.drop_finished,
+                ..SortedClients::default()
+            }),
+            history: self
+                .history
+                .keys()
+                .map(|&client| (client, Vec::new()))
+                .collect(),
+            ..Engine::new()
+        }
+    }
+}
+
+// balances are output with 4 decimal places, smaller drift is float noise
+const BALANCE_TOLERANCE: f64 = 0.00005;
+
+/// One client's account in two engines, see `Engine::diff`. `None` when the client has no
+/// account on that side.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AccountDiff {
+    pub client: AccountId,
+    pub before: Option<Account>,
+    pub after: Option<Account>,
+}
+
+impl AccountDiff {
+    fn changed(&self) -> bool {
+        let (Some(before), Some(after)) = (self.before, self.after) else {
+            return true;
+        };
+        let differs = |a: f64, b: f64| (a - b).abs() > BALANCE_TOLERANCE;
+        differs(before.available(), after.available())
+            || differs(before.held(), after.held())
+            || differs(before.total(), after.total())
+            || before.is_locked() != after.is_locked()
+    }
+}
+
+/// One row per diff: each balance before and after, empty for a side without the account.
+pub fn account_diffs_to_csv(diffs: &[AccountDiff], precision: usize) -> String {
+    let mut buf = vec![
+        "client,available_before,available_after,held_before,held_after,total_before,\
+         total_after,locked_before,locked_after"
+            .to_string(),
+    ];
+    let amount = |account: Option<Account>, field: fn(&Account) -> f64| {
+        account.map_or(String::new(), |a| {
+            format!("{:.p$}", field(&a), p = precision)
+        })
+    };
+    let locked =
+        |account: Option<Account>| account.map_or(String::new(), |a| a.is_locked().to_string());
+    diffs.iter().for_each(|diff| {
+        buf.push(format!(
+            "{},{},{},{},{},{},{},{},{}",
+            diff.client,
+            amount(diff.before, Account::available),
+            amount(diff.after, Account::available),
+            amount(diff.before, Account::held),
+            amount(diff.after, Account::held),
+            amount(diff.before, Account::total),
+            amount(diff.after, Account::total),
+            locked(diff.before),
+            locked(diff.after),
+        ))
+    });
+    buf.join("\n")
+}
+
+/// An account balance that `Engine::verify_invariants` found inconsistent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InvariantViolation {
+    BalanceMismatch {
+        client: AccountId,
+        available: f64,
+        held: f64,
+        total: f64,
+    },
+    NegativeHeld {
+        client: AccountId,
+        held: f64,
+    },
+}
+
+impl std::fmt::Display for InvariantViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::BalanceMismatch {
+                client,
+                available,
+                held,
+                total,
+            } => write!(
+                f,
+                "Client {}: available {} + held {} does not match total {}",
+                client, available, held, total
+            ),
+            Self::NegativeHeld { client, held } => {
+                write!(f, "Client {}: held is negative ({})", client, held)
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvariantViolation {}
+
+/// An entry `Engine::process_entry` recomputed a different status for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StatusMismatch {
+    pub tx: Transaction,
+    pub recorded: TransactionStatus,
+    pub recomputed: TransactionStatus,
+}
+
+impl std::fmt::Display for StatusMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Transaction {} (client {}): recorded {:?}, recomputed {:?}",
+            self.tx.tx, self.tx.client, self.recorded, self.recomputed
+        )
+    }
+}
+
+impl std::error::Error for StatusMismatch {}
+
+/// Why `Engine::merge` refused to combine two engines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeError {
+    AccountCollision(AccountId),
+    TxIdCollision(u32),
+}
+
+impl std::fmt::Display for MergeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AccountCollision(client) => {
+                write!(f, "Client {} has an account in both engines", client)
+            }
+            Self::TxIdCollision(tx) => {
+                write!(f, "Transaction {} was applied in both engines", tx)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MergeError {}
+
+/// Serde-friendly engine state produced by `Engine::snapshot` for warm restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EngineSnapshot {
+    accounts: Vec<Account>,
+    // missing from snapshots taken before accounts tracked volumes
+    #[serde(default)]
+    volumes: HashMap<AccountId, Volumes>,
+    tx_state: HashMap<u32, TxState>,
+    seen_tx_ids: HashSet<u32>,
+    seq: u64,
+    // missing from snapshots taken before it was kept apart, summed from `tx_state` then
+    #[serde(default)]
+    applied_volume: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum DisputeState {
+    Normal,
+    Disputed,
+    Resolved,
+    Chargeback,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(from = "TxStateRow")]
+struct TxState {
+    client: AccountId,
+    // resolve/chargeback release `held` for both types, only the dispute differs
+    tx_type: TransactionType,
+    // sequence index the transaction was applied at
+    seq: u64,
+    amount: f64,
+    dispute_state: DisputeState,
+    // sequence index of the latest dispute, for `auto_resolve_after`
+    #[serde(default)]
+    disputed_seq: u64,
+    // what the open dispute moved into the account's `held`, released exactly by the
+    // resolve/chargeback whatever else the account holds
+    held: f64,
+}
+
+// `TxState` as a snapshot stores it. Snapshots taken before `held` existed leave it out: an
+// open dispute there held the transaction's whole amount, anything else holds nothing
+#[derive(Deserialize)]
+struct TxStateRow {
+    client: AccountId,
+    tx_type: TransactionType,
+    seq: u64,
+    amount: f64,
+    dispute_state: DisputeState,
+    #[serde(default)]
+    disputed_seq: u64,
+    held: Option<f64>,
+}
+
+impl From<TxStateRow> for TxState {
+    fn from(row: TxStateRow) -> Self {
+        let held = row.held.unwrap_or(match row.dispute_state {
+            DisputeState::Disputed => row.amount,
+            _ => 0.0,
+        });
+        Self {
+            client: row.client,
+            tx_type: row.tx_type,
+            seq: row.seq,
+            amount: row.amount,
+            dispute_state: row.dispute_state,
+            disputed_seq: row.disputed_seq,
+            held,
+        }
+    }
+}
+
+impl TxState {
+    fn is_under_dispute(&self) -> bool {
+        matches!(self.dispute_state, DisputeState::Disputed)
+    }
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tx(t: TransactionType, client: u16, tx_id: u32, amount: Option<f64>) -> Transaction {
+        Transaction::new(t, client, tx_id, amount)
+    }
+
+    #[test]
+    fn chargeback_attempt_while_locked_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.total(), 0.0);
+        assert!(account.is_locked());
+        assert!(
+            engine
+                .transactions
+                .iter()
+                .filter(|stored_tx| stored_tx.tx._type == TransactionType::Chargeback)
+                .count()
+                == 2
+        );
+        println!("{:?}", engine.transactions);
+        assert!(
+            engine
+                .transactions
+                .iter()
+                .filter(
+                    |stored_tx| stored_tx.tx._type == TransactionType::Chargeback
+                        && stored_tx.status == TransactionStatus::IgnoredLocked
+                )
+                .count()
+                == 1
+        );
+    }
+
+    #[test]
+    fn zero_amount_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(0.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.total(), 0.0);
+        assert!(!account.is_locked());
+    }
+
+    #[test]
+    fn negative_amount_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(-100.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.total(), 0.0);
+        assert!(!account.is_locked());
+    }
+
+    #[test]
+    fn duplicate_transactions_ids_are_rejected() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 1, Some(50.0)));
+
+        let account = engine.get_account(1).unwrap();
         assert_eq!(account.available(), 100.0);
         assert_eq!(account.held(), 0.0);
         assert_eq!(account.total(), 100.0);
         assert!(!account.is_locked());
     }
 
+    #[test]
+    fn references_to_a_tx_id_are_not_duplicates() {
+        let mut engine = Engine::new();
+        let status = |engine: &Engine| engine.last_entry.unwrap().status;
+        engine.process(tx(TransactionType::Deposit, 1, 6, Some(50.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 7, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 8, Some(30.0)));
+
+        for (ty, tx_id) in [
+            (TransactionType::Dispute, 7),
+            (TransactionType::Resolve, 7),
+            (TransactionType::Dispute, 8),
+            (TransactionType::Chargeback, 8),
+        ] {
+            engine.process(tx(ty, 1, tx_id, None));
+            assert_eq!(status(&engine), TransactionStatus::Applied, "{ty} {tx_id}");
+        }
+        assert_eq!(engine.seen_tx_ids, HashSet::from([6, 7, 8]));
+
+        // a reference to an unknown ID doesn't claim it for a later deposit
+        engine.process(tx(TransactionType::Dispute, 2, 9, None));
+        assert_ne!(status(&engine), TransactionStatus::FailedDuplicateTxID);
+        engine.process(tx(TransactionType::Deposit, 2, 9, Some(5.0)));
+        assert_eq!(status(&engine), TransactionStatus::Applied);
+
+        // while a deposit or withdrawal reusing any of them still is one
+        engine.process(tx(TransactionType::Deposit, 2, 8, Some(5.0)));
+        assert_eq!(status(&engine), TransactionStatus::FailedDuplicateTxID);
+    }
+
+    #[test]
+    fn held_breakdown_lists_each_open_dispute() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 3, Some(40.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(25.5)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(10.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 4, Some(7.0)));
+        for (client, tx_id) in [(1, 3), (1, 1), (1, 2), (2, 4)] {
+            engine.process(tx(TransactionType::Dispute, client, tx_id, None));
+        }
+        // a resolved dispute no longer holds anything
+        engine.process(tx(TransactionType::Resolve, 1, 2, None));
+
+        assert_eq!(engine.held_breakdown(1), [(1, 25.5), (3, 40.0)]);
+        let held: f64 = engine.held_breakdown(1).iter().map(|(_, held)| held).sum();
+        assert_eq!(held, engine.get_account(1).unwrap().held());
+        assert_eq!(engine.held_breakdown(2), [(4, 7.0)]);
+        assert!(engine.held_breakdown(3).is_empty());
+    }
+
+    #[test]
+    fn apply_all_yields_each_entry_as_it_applies() {
+        let transactions = [
+            tx(TransactionType::Deposit, 1, 1, Some(100.0)),
+            tx(TransactionType::Withdrawal, 1, 2, Some(500.0)),
+            tx(TransactionType::Dispute, 1, 1, None),
+            tx(TransactionType::Deposit, 1, 1, Some(5.0)),
+            tx(TransactionType::Chargeback, 1, 1, None),
+            tx(TransactionType::Deposit, 1, 3, Some(1.0)),
+        ];
+        let mut engine = Engine::new();
+        let entries: Vec<TransactionEntry> = engine.apply_all(transactions).collect();
+
+        assert_eq!(entries, engine.transactions);
+        assert_eq!(
+            entries.iter().map(|entry| entry.status).collect::<Vec<_>>(),
+            [
+                TransactionStatus::Applied,
+                TransactionStatus::FailedInsufficientFunds,
+                TransactionStatus::Applied,
+                TransactionStatus::FailedDuplicateTxID,
+                TransactionStatus::Applied,
+                TransactionStatus::IgnoredLocked,
+            ]
+        );
+
+        // lazy: only what's been pulled is processed
+        let mut engine = Engine::new();
+        let first = engine.apply_all(transactions).next().unwrap();
+        assert_eq!(first.status, TransactionStatus::Applied);
+        assert_eq!(engine.transactions.len(), 1);
+        assert_eq!(engine.get_account(1).unwrap().total(), 100.0);
+    }
+
+    #[test]
+    fn resolve_all_open_releases_every_dispute() {
+        let mut engine = Engine::new();
+        for (client, tx_id, amount) in [(1, 1, 10.0), (1, 2, 5.5), (2, 3, 7.0), (3, 4, 1.0)] {
+            engine.process(tx(TransactionType::Deposit, client, tx_id, Some(amount)));
+        }
+        for (client, tx_id) in [(1, 1), (1, 2), (2, 3), (3, 4)] {
+            engine.process(tx(TransactionType::Dispute, client, tx_id, None));
+        }
+        // client 3 is locked by another chargeback, its dispute stays open
+        engine.process(tx(TransactionType::Deposit, 3, 5, Some(2.0)));
+        engine.process(tx(TransactionType::Dispute, 3, 5, None));
+        engine.process(tx(TransactionType::Chargeback, 3, 5, None));
+        let seq = engine.seq;
+
+        assert_eq!(engine.resolve_all_open(), 3);
+        for (client, total) in [(1, 15.5), (2, 7.0)] {
+            let account = engine.get_account(client).unwrap();
+            assert_eq!(account.held(), 0.0);
+            assert_eq!(account.available(), total);
+            assert_eq!(account.disputes_open(), 0);
+        }
+        assert_eq!(engine.held_breakdown(3), [(4, 1.0)]);
+        let synthetic: Vec<_> = engine.transactions[engine.transactions.len() - 3..]
+            .iter()
+            .map(|entry| (entry.seq, entry.tx._type, entry.tx.tx, entry.status))
+            .collect();
+        assert_eq!(
+            synthetic,
+            [1, 2, 3].map(|tx_id| (
+                seq,
+                TransactionType::Resolve,
+                tx_id,
+                TransactionStatus::AutoResolved
+            ))
+        );
+
+        // nothing left to resolve, and the resolved disputes can't be resolved again
+        assert_eq!(engine.resolve_all_open(), 0);
+        engine.process(tx(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(
+            engine.last_entry.unwrap().status,
+            TransactionStatus::IgnoredNotDisputed
+        );
+    }
+
     #[test]
     fn deposit_credits_new_account() {
         let mut engine = Engine::new();
@@ -317,7 +1687,7 @@ mod tests {
         engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
         assert!(engine.get_account(1).is_some());
 
-        let account = engine.get_account(1).unwrap().clone();
+        let account = *engine.get_account(1).unwrap();
         assert_eq!(account.total(), 100.0);
         assert_eq!(account.available(), 100.0);
 
@@ -371,6 +1741,38 @@ mod tests {
         assert_eq!(account.total(), 50.0);
     }
 
+    #[test]
+    fn partial_withdrawal_drains_the_available_funds() {
+        let mut engine = Engine::new().with_partial_withdrawals(true);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(150.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 0.0);
+        assert_eq!(account.total(), 0.0);
+        let entry = engine.transactions.last().unwrap();
+        assert_eq!(entry.status, TransactionStatus::AppliedPartial);
+        // the log keeps the requested amount
+        assert_eq!(entry.tx.amount, Some(150.0));
+
+        // nothing left to drain
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(10.0)));
+        assert_eq!(
+            engine.transactions.last().unwrap().status,
+            TransactionStatus::FailedInsufficientFunds
+        );
+
+        // disputing it restores what was actually withdrawn
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.held(), 100.0);
+        assert_eq!(account.total(), 100.0);
+
+        let stats = engine.stats();
+        assert_eq!((stats.applied, stats.applied_partial), (2, 1));
+        assert_eq!(stats.rejected(), 1);
+    }
+
     #[test]
     fn dispute_moves_funds_to_held() {
         let mut engine = Engine::new();
@@ -412,6 +1814,26 @@ mod tests {
         assert!(account.is_locked());
     }
 
+    #[test]
+    fn reverse_only_chargeback_keeps_the_account_open() {
+        let mut engine = Engine::new().with_chargeback_policy(ChargebackPolicy::ReverseOnly);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(20.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        engine.process(tx(TransactionType::Deposit, 1, 3, Some(5.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert!(!account.is_locked());
+        assert_eq!(account.available(), 25.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.total(), 25.0);
+        assert_eq!(
+            engine.transactions.last().unwrap().status,
+            TransactionStatus::Applied
+        );
+    }
+
     #[test]
     fn locked_account_ignores_transactions() {
         let mut engine = Engine::new();
@@ -453,6 +1875,36 @@ mod tests {
         assert_eq!(account2.held(), 0.0);
     }
 
+    #[test]
+    fn cross_client_dispute_applies_to_the_owning_account() {
+        let mut engine = Engine::new().with_allow_cross_client_dispute(true);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 2, Some(50.0)));
+        engine.process(tx(TransactionType::Dispute, 2, 1, None));
+
+        let account1 = engine.get_account(1).unwrap();
+        assert_eq!(account1.available(), 0.0);
+        assert_eq!(account1.held(), 100.0);
+        assert_eq!(engine.get_account(2).unwrap().held(), 0.0);
+        let entry = engine.transactions.last().unwrap();
+        assert_eq!(entry.tx.client, 1);
+        assert_eq!(entry.status, TransactionStatus::Applied);
+
+        // a back-office client gets no account of its own
+        engine.process(tx(TransactionType::Dispute, 9, 2, None));
+        assert!(engine.get_account(9).is_none());
+        assert_eq!(engine.get_account(2).unwrap().held(), 50.0);
+
+        engine.process(tx(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(engine.get_account(1).unwrap().available(), 100.0);
+        // unknown tx IDs are still missing references
+        engine.process(tx(TransactionType::Dispute, 2, 99, None));
+        assert_eq!(
+            engine.transactions.last().unwrap().status,
+            TransactionStatus::IgnoredMissingReference
+        );
+    }
+
     #[test]
     fn cannot_dispute_already_disputed_tx() {
         let mut engine = Engine::new();
@@ -501,6 +1953,76 @@ mod tests {
         let account = engine.get_account(1).unwrap();
         assert_eq!(account.available(), 100.0);
         assert_eq!(account.held(), 0.0);
+        assert_eq!(
+            engine.transactions.last().unwrap().status,
+            TransactionStatus::IgnoredNotDisputed
+        );
+    }
+
+    #[test]
+    fn settling_an_undisputed_tx_is_ignored_not_disputed() {
+        use TransactionStatus::{Applied, IgnoredMissingReference, IgnoredNotDisputed};
+        use TransactionType::{Chargeback, Deposit, Dispute, Resolve};
+
+        // a chargeback that doesn't lock, so the client's later rows aren't `IgnoredLocked`
+        let mut engine = Engine::new().with_chargeback_policy(ChargebackPolicy::ReverseOnly);
+        let rows = [
+            // never disputed
+            (tx(Deposit, 1, 1, Some(100.0)), Applied),
+            (tx(Resolve, 1, 1, None), IgnoredNotDisputed),
+            (tx(Chargeback, 1, 1, None), IgnoredNotDisputed),
+            // already resolved
+            (tx(Deposit, 2, 2, Some(5.0)), Applied),
+            (tx(Dispute, 2, 2, None), Applied),
+            (tx(Resolve, 2, 2, None), Applied),
+            (tx(Resolve, 2, 2, None), IgnoredNotDisputed),
+            (tx(Chargeback, 2, 2, None), IgnoredNotDisputed),
+            // already charged back
+            (tx(Deposit, 3, 3, Some(7.0)), Applied),
+            (tx(Dispute, 3, 3, None), Applied),
+            (tx(Chargeback, 3, 3, None), Applied),
+            (tx(Chargeback, 3, 3, None), IgnoredNotDisputed),
+            (tx(Resolve, 3, 3, None), IgnoredNotDisputed),
+            // an unknown ID is still a missing reference
+            (tx(Deposit, 4, 4, Some(1.0)), Applied),
+            (tx(Resolve, 4, 9, None), IgnoredMissingReference),
+            (tx(Chargeback, 4, 9, None), IgnoredMissingReference),
+        ];
+        for (row, _) in rows {
+            engine.process(row);
+        }
+
+        let statuses: Vec<_> = engine.transactions.iter().map(|e| e.status).collect();
+        assert_eq!(statuses, rows.map(|(_, status)| status));
+        assert!(engine.get_accounts().values().all(|a| !a.is_locked()));
+        assert_eq!(engine.get_account(1).unwrap().total(), 100.0);
+        assert_eq!(engine.get_account(2).unwrap().total(), 5.0);
+        assert_eq!(engine.get_account(3).unwrap().total(), 0.0);
+    }
+
+    #[test]
+    fn simultaneous_disputes_release_their_own_amounts() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(30.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(12.5)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        assert_eq!(engine.tx_state[&1].held, 30.0);
+        assert_eq!(engine.tx_state[&2].held, 12.5);
+        assert_eq!(engine.get_account(1).unwrap().held(), 42.5);
+
+        engine.process(tx(TransactionType::Resolve, 1, 2, None));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.held(), 30.0);
+        assert_eq!(account.available(), 12.5);
+        assert_eq!(engine.tx_state[&2].held, 0.0);
+
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.available(), 12.5);
+        assert_eq!(account.total(), 12.5);
+        assert_eq!(engine.tx_state[&1].held, 0.0);
     }
 
     #[test]
@@ -517,8 +2039,71 @@ mod tests {
     }
 
     #[test]
-    fn multiple_clients_independent() {
-        let mut engine = Engine::new();
+    fn redispute_after_resolve_can_be_disallowed() {
+        for allow in [true, false] {
+            let mut engine = Engine::new().with_allow_redispute(allow);
+            engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+            engine.process(tx(TransactionType::Dispute, 1, 1, None));
+            engine.process(tx(TransactionType::Resolve, 1, 1, None));
+            engine.process(tx(TransactionType::Dispute, 1, 1, None));
+
+            let account = engine.get_account(1).unwrap();
+            let redispute = engine.get_transactions()[3].status;
+            if allow {
+                assert_eq!(redispute, TransactionStatus::Applied);
+                assert_eq!((account.available(), account.held()), (0.0, 100.0));
+            } else {
+                assert_eq!(redispute, TransactionStatus::IgnoredNotDisputed);
+                assert_eq!((account.available(), account.held()), (100.0, 0.0));
+            }
+        }
+
+        // a first dispute is still fine
+        let mut engine = Engine::new().with_allow_redispute(false);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        assert_eq!(engine.get_account(1).unwrap().held(), 100.0);
+    }
+
+    #[test]
+    fn ledger_proof_balances_the_log_against_the_accounts() {
+        let mut engine = Engine::new()
+            .with_partial_withdrawals(true)
+            .with_allow_unlock(true)
+            .with_chargeback_policy(ChargebackPolicy::ReverseOnly);
+        for t in [
+            tx(TransactionType::Deposit, 1, 1, Some(100.0)),
+            tx(TransactionType::Withdrawal, 1, 2, Some(30.0)),
+            tx(TransactionType::Dispute, 1, 2, None),
+            tx(TransactionType::Resolve, 1, 2, None),
+            tx(TransactionType::Deposit, 2, 3, Some(50.0)),
+            tx(TransactionType::Dispute, 2, 3, None),
+            tx(TransactionType::Chargeback, 2, 3, None),
+            tx(TransactionType::Deposit, 3, 4, Some(20.0)),
+            tx(TransactionType::Withdrawal, 3, 5, Some(25.5)),
+            tx(TransactionType::Dispute, 3, 5, None),
+            tx(TransactionType::Chargeback, 3, 5, None),
+            tx(TransactionType::Withdrawal, 3, 6, Some(1.0)),
+            tx(TransactionType::Deposit, 4, 7, Some(10.0)),
+            tx(TransactionType::Dispute, 4, 7, None),
+        ] {
+            engine.process(t);
+        }
+        let proof = engine.ledger_proof();
+        assert!(proof.balanced, "{proof:?}");
+        assert_eq!(proof.totals, 110.0);
+        assert_eq!(proof.to_string(), "ledger balanced: true");
+
+        // money that appeared in an account without a logged transaction
+        engine.accounts.get_mut(&1).unwrap().deposit(0.01);
+        let proof = engine.ledger_proof();
+        assert!(!proof.balanced, "{proof:?}");
+        assert_eq!(proof.to_string(), "ledger balanced: false");
+    }
+
+    #[test]
+    fn multiple_clients_independent() {
+        let mut engine = Engine::new();
         engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
         engine.process(tx(TransactionType::Deposit, 2, 2, Some(200.0)));
         engine.process(tx(TransactionType::Dispute, 1, 1, None));
@@ -542,4 +2127,1272 @@ mod tests {
         assert_eq!(account.held(), 100.0);
         assert_eq!(account.total(), 150.0);
     }
+
+    #[test]
+    fn dispute_on_withdrawal_restores_and_holds_funds() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(40.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 60.0);
+        assert_eq!(account.held(), 40.0);
+        assert_eq!(account.total(), 100.0);
+        assert!(!account.is_locked());
+    }
+
+    #[test]
+    fn resolve_on_disputed_withdrawal_returns_funds_to_available() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(100.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        engine.process(tx(TransactionType::Resolve, 1, 2, None));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 100.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.total(), 100.0);
+        assert!(!account.is_locked());
+    }
+
+    #[test]
+    fn chargeback_on_disputed_withdrawal_removes_held_and_locks() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(40.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 2, None));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 60.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.total(), 60.0);
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn disabled_event_log_keeps_balances_and_duplicate_detection() {
+        let mut engine = Engine::new().with_event_log(false);
+        for id in 0..100_000 {
+            engine.process(tx(TransactionType::Deposit, 1, id, Some(1.0)));
+        }
+        // replayed ID must still be caught without the log to scan
+        engine.process(tx(TransactionType::Deposit, 1, 42, Some(1.0)));
+
+        assert!(engine.get_transactions().is_empty());
+        assert_eq!(engine.seen_tx_ids.len(), 100_000);
+        assert_eq!(engine.tx_state.len(), 100_000);
+        assert_eq!(engine.get_account(1).unwrap().total(), 100_000.0);
+    }
+
+    #[test]
+    fn duplicate_detection_goes_through_the_id_index() {
+        let mut engine = Engine::new();
+        for id in 0..10_000 {
+            engine.process(tx(
+                TransactionType::Deposit,
+                (id % 100) as u16,
+                id,
+                Some(1.0),
+            ));
+        }
+        let capacity = engine.seen_tx_ids.capacity();
+        // one index entry per introduced ID, replays and disputes don't add any
+        for id in (0..10_000).step_by(1_000) {
+            engine.process(tx(TransactionType::Deposit, 7, id, Some(1.0)));
+            engine.process(tx(TransactionType::Dispute, (id % 100) as u16, id, None));
+        }
+        assert_eq!(engine.seen_tx_ids.len(), 10_000);
+        assert_eq!(engine.seen_tx_ids.capacity(), capacity);
+        assert_eq!(
+            engine
+                .transactions
+                .iter()
+                .filter(|entry| entry.status == TransactionStatus::FailedDuplicateTxID)
+                .count(),
+            10
+        );
+
+        // the index alone catches replays, nothing is scanned
+        engine.transactions.clear();
+        engine.process(tx(TransactionType::Withdrawal, 1, 4_242, Some(1.0)));
+        assert_eq!(
+            engine.last_entry.unwrap().status,
+            TransactionStatus::FailedDuplicateTxID
+        );
+    }
+
+    // timing based, run with `cargo test -- --ignored`
+    #[test]
+    #[ignore]
+    fn duplicate_detection_scales_linearly() {
+        fn time_deposits(count: u32) -> std::time::Duration {
+            let mut engine = Engine::new();
+            let start = std::time::Instant::now();
+            for id in 0..count {
+                engine.process(tx(
+                    TransactionType::Deposit,
+                    (id % 100) as u16,
+                    id,
+                    Some(1.0),
+                ));
+            }
+            start.elapsed()
+        }
+
+        let small = time_deposits(100_000);
+        let large = time_deposits(1_000_000);
+        // 10x the rows: linear is ~10x, the old linear scan per row was ~100x
+        let ratio = large.as_secs_f64() / small.as_secs_f64();
+        assert!(
+            ratio < 40.0,
+            "1M rows took {ratio:.1}x the time of 100k rows"
+        );
+    }
+
+    #[test]
+    fn transactions_for_returns_client_history_in_order() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 2, Some(50.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(500.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+
+        let history = engine.transactions_for(1);
+        let ids: Vec<(TransactionType, u32)> = history
+            .iter()
+            .map(|entry| (entry.tx._type, entry.tx.tx))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                (TransactionType::Deposit, 1),
+                (TransactionType::Withdrawal, 3),
+                (TransactionType::Dispute, 1),
+            ]
+        );
+        assert_eq!(
+            history[1].status,
+            TransactionStatus::FailedInsufficientFunds
+        );
+        assert!(engine.transactions_for(3).is_empty());
+    }
+
+    #[test]
+    fn withdrawal_can_reach_exactly_the_credit_limit() {
+        let mut engine = Engine::new().with_credit_limit(50.0);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(150.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), -50.0);
+        assert_eq!(account.total(), -50.0);
+        assert_eq!(engine.transactions[1].status, TransactionStatus::Applied);
+    }
+
+    #[test]
+    fn withdrawal_past_the_credit_limit_is_rejected() {
+        let mut engine = Engine::new().with_credit_limit(50.0);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(150.01)));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 100.0);
+        assert_eq!(
+            engine.transactions[1].status,
+            TransactionStatus::FailedInsufficientFunds
+        );
+    }
+
+    #[test]
+    fn deposits_refill_an_overdrawn_account() {
+        let mut engine = Engine::new().with_credit_limit(50.0);
+        engine.process(tx(TransactionType::Withdrawal, 1, 1, Some(50.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(30.0)));
+        assert_eq!(engine.get_account(1).unwrap().available(), -20.0);
+
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(30.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 4, Some(1.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), -50.0);
+        assert_eq!(account.total(), -50.0);
+        assert_eq!(
+            engine.transactions[3].status,
+            TransactionStatus::FailedInsufficientFunds
+        );
+    }
+
+    #[test]
+    fn dispute_within_window_is_applied() {
+        let mut engine = Engine::new().with_dispute_window(2);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 2, Some(10.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+
+        assert_eq!(engine.transactions[2].status, TransactionStatus::Applied);
+        assert_eq!(engine.get_account(1).unwrap().held(), 100.0);
+    }
+
+    #[test]
+    fn dispute_outside_window_is_ignored() {
+        let mut engine = Engine::new().with_dispute_window(2);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 2, Some(10.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 3, Some(10.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+
+        assert_eq!(
+            engine.transactions[3].status,
+            TransactionStatus::IgnoredOutsideWindow
+        );
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 100.0);
+        assert_eq!(account.held(), 0.0);
+    }
+
+    #[test]
+    fn unlock_reenables_a_locked_account() {
+        let mut engine = Engine::new().with_allow_unlock(true);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(20.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        assert!(engine.get_account(1).unwrap().is_locked());
+
+        engine.process(tx(TransactionType::Unlock, 1, 3, None));
+        engine.process(tx(TransactionType::Deposit, 1, 4, Some(5.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert!(!account.is_locked());
+        assert_eq!(account.available(), 25.0);
+        assert_eq!(account.total(), 25.0);
+        assert_eq!(engine.transactions[4].status, TransactionStatus::Applied);
+        assert_eq!(engine.transactions[5].status, TransactionStatus::Applied);
+    }
+
+    #[test]
+    fn unlock_can_release_what_open_disputes_hold() {
+        let run = |release: bool| {
+            let mut engine = Engine::new()
+                .with_allow_unlock(true)
+                .with_release_held_on_unlock(release);
+            engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+            engine.process(tx(TransactionType::Deposit, 1, 2, Some(30.0)));
+            engine.process(tx(TransactionType::Deposit, 1, 3, Some(20.0)));
+            engine.process(tx(TransactionType::Dispute, 1, 1, None));
+            engine.process(tx(TransactionType::Dispute, 1, 2, None));
+            engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+            engine.process(tx(TransactionType::Unlock, 1, 4, None));
+            engine
+        };
+
+        // without the option the dispute on tx 2 keeps its funds held
+        let mut engine = run(false);
+        let account = *engine.get_account(1).unwrap();
+        assert!(!account.is_locked());
+        assert_eq!((account.available(), account.held()), (20.0, 30.0));
+        assert_eq!(account.disputes_open(), 1);
+        engine.process(tx(TransactionType::Resolve, 1, 2, None));
+        assert_eq!(engine.get_account(1).unwrap().available(), 50.0);
+
+        let mut engine = run(true);
+        let account = *engine.get_account(1).unwrap();
+        assert!(!account.is_locked());
+        assert_eq!((account.available(), account.held()), (50.0, 0.0));
+        // the released dispute is logged after the unlock, as if it had been resolved
+        assert_eq!(
+            engine.transactions[6..]
+                .iter()
+                .map(|entry| (entry.seq, entry.tx, entry.status))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    6,
+                    tx(TransactionType::Unlock, 1, 4, None),
+                    TransactionStatus::Applied
+                ),
+                (
+                    6,
+                    tx(TransactionType::Resolve, 1, 2, None),
+                    TransactionStatus::AutoResolved
+                ),
+            ]
+        );
+        assert_eq!(account.total(), 50.0);
+        assert_eq!(account.disputes_open(), 0);
+        assert_eq!(account.volume(TransactionType::Unlock), 30.0);
+        assert!(engine.held_breakdown(1).is_empty());
+        // the released dispute is closed, settling it again changes nothing
+        engine.process(tx(TransactionType::Chargeback, 1, 2, None));
+        assert_eq!(
+            engine.get_transactions().last().unwrap().status,
+            TransactionStatus::IgnoredNotDisputed
+        );
+        assert_eq!(*engine.get_account(1).unwrap(), account);
+    }
+
+    #[test]
+    fn sorted_clients_can_drop_the_state_of_finished_clients() {
+        let input = [
+            tx(TransactionType::Deposit, 1, 1, Some(10.0)),
+            tx(TransactionType::Deposit, 1, 2, Some(5.0)),
+            tx(TransactionType::Dispute, 1, 2, None),
+            // takes the 10.0 available
+            tx(TransactionType::Withdrawal, 1, 4, Some(12.0)),
+            tx(TransactionType::Deposit, 2, 3, Some(7.0)),
+            tx(TransactionType::Dispute, 2, 3, None),
+            tx(TransactionType::Resolve, 2, 3, None),
+        ];
+        let run = |engine: Engine| {
+            let mut engine = engine.with_partial_withdrawals(true);
+            input.iter().for_each(|&t| engine.process(t));
+            engine
+        };
+
+        let plain = run(Engine::new());
+        assert_eq!(plain.aggregate().applied_volume, 32.0);
+        assert!(plain.ledger_proof().balanced);
+        for drop_finished in [false, true] {
+            let sorted = run(Engine::new().with_sorted_clients(drop_finished));
+            assert_eq!(sorted.get_accounts(), plain.get_accounts());
+            assert_eq!(sorted.get_transactions(), plain.get_transactions());
+            assert_eq!(sorted.aggregate(), plain.aggregate());
+            assert_eq!(sorted.ledger_proof(), plain.ledger_proof());
+        }
+
+        // client 1's block is over: its transactions can't be referenced anymore once dropped,
+        // their IDs are still taken
+        let mut kept = run(Engine::new().with_sorted_clients(false));
+        let mut dropped = run(Engine::new().with_sorted_clients(true));
+        assert_eq!(dropped.held_breakdown(1), []);
+        assert_eq!(dropped.tx_state.len(), 1);
+        for engine in [&mut kept, &mut dropped] {
+            engine.process(tx(TransactionType::Resolve, 1, 2, None));
+            engine.process(tx(TransactionType::Deposit, 1, 1, Some(1.0)));
+        }
+        let statuses = |engine: &Engine| -> Vec<TransactionStatus> {
+            engine.get_transactions()[7..]
+                .iter()
+                .map(|entry| entry.status)
+                .collect()
+        };
+        assert_eq!(
+            statuses(&kept),
+            [
+                TransactionStatus::Applied,
+                TransactionStatus::FailedDuplicateTxID
+            ]
+        );
+        assert_eq!(
+            statuses(&dropped),
+            [
+                TransactionStatus::IgnoredMissingReference,
+                TransactionStatus::FailedDuplicateTxID
+            ]
+        );
+        assert_eq!(dropped.get_account(1).unwrap().held(), 5.0);
+    }
+
+    #[test]
+    fn unlock_is_ignored_unless_allowed() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        engine.process(tx(TransactionType::Unlock, 1, 2, None));
+        engine.process(tx(TransactionType::Deposit, 1, 3, Some(5.0)));
+
+        let account = engine.get_account(1).unwrap();
+        assert!(account.is_locked());
+        assert_eq!(account.total(), 0.0);
+        assert_eq!(
+            engine.transactions[3].status,
+            TransactionStatus::IgnoredUnauthorized
+        );
+        assert_eq!(
+            engine.transactions[4].status,
+            TransactionStatus::IgnoredLocked
+        );
+    }
+
+    #[test]
+    fn diff_reports_only_diverging_accounts() {
+        let transactions = [
+            tx(TransactionType::Deposit, 1, 1, Some(100.0)),
+            tx(TransactionType::Deposit, 2, 2, Some(50.0)),
+            tx(TransactionType::Dispute, 2, 2, None),
+            tx(TransactionType::Chargeback, 2, 2, None),
+            tx(TransactionType::Deposit, 2, 3, Some(5.0)),
+        ];
+        let mut before = Engine::new();
+        transactions.iter().for_each(|t| before.process(*t));
+        let mut after = Engine::new().with_chargeback_policy(ChargebackPolicy::ReverseOnly);
+        transactions.iter().for_each(|t| after.process(*t));
+
+        assert!(before.diff(&before).is_empty());
+        let diff = before.diff(&after);
+        assert_eq!(diff.len(), 1);
+        assert_eq!(diff[0].client, 2);
+        let (old, new) = (diff[0].before.unwrap(), diff[0].after.unwrap());
+        assert!(old.is_locked() && !new.is_locked());
+        assert_eq!((old.total(), new.total()), (0.0, 5.0));
+
+        // an account only one side has is a change too
+        after.process(tx(TransactionType::Deposit, 3, 4, Some(1.0)));
+        let diff = before.diff(&after);
+        assert_eq!(diff.len(), 2);
+        assert_eq!((diff[1].client, diff[1].before), (3, None));
+        assert_eq!(
+            account_diffs_to_csv(&diff, 2)
+                .lines()
+                .skip(1)
+                .collect::<Vec<_>>(),
+            [
+                "2,0.00,5.00,0.00,0.00,0.00,5.00,true,false",
+                "3,,1.00,,0.00,,1.00,,false"
+            ]
+        );
+    }
+
+    #[test]
+    fn entries_carry_dense_increasing_seq() {
+        let mut engine = Engine::new();
+        let entries = "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,50.0\n\
+             deposit,2,3,5.0\n\
+             dispute,1,1,\n\
+             resolve,1,9,\n\
+             chargeback,1,1,\n\
+             deposit,1,4,1.0\n";
+        csv::ReaderBuilder::new()
+            .from_reader(entries.as_bytes())
+            .deserialize::<Transaction>()
+            .for_each(|t| engine.process(t.unwrap()));
+
+        let seqs: Vec<u64> = engine.transactions.iter().map(|e| e.seq).collect();
+        assert_eq!(seqs, (0..7).collect::<Vec<_>>());
+        let csv = crate::ledger::transaction::transaction_entries_to_csv(&engine.transactions);
+        assert!(csv.starts_with("seq,type,client,tx,amount,status\n0,deposit,1,1,"));
+        assert!(
+            csv.ends_with("\n6,deposit,1,4,1.0000,ignored_locked"),
+            "{csv}"
+        );
+    }
+
+    #[test]
+    fn non_finite_amounts_are_invalid() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(10.0)));
+        for (tx_id, amount) in [(2, f64::NAN), (3, f64::INFINITY), (4, f64::NEG_INFINITY)] {
+            engine.process(tx(TransactionType::Deposit, 1, tx_id, Some(amount)));
+            engine.process(tx(TransactionType::Withdrawal, 1, tx_id + 10, Some(amount)));
+        }
+
+        assert!(
+            engine.transactions[1..]
+                .iter()
+                .all(|entry| entry.status == TransactionStatus::FailedInvalidAmount)
+        );
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 10.0);
+        assert_eq!(account.total(), 10.0);
+        // nothing to dispute either
+        engine.process(tx(TransactionType::Dispute, 1, 3, None));
+        assert_eq!(
+            engine.transactions.last().unwrap().status,
+            TransactionStatus::IgnoredMissingReference
+        );
+    }
+
+    #[test]
+    fn hook_sees_every_entry_with_the_resulting_account() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = Arc::clone(&seen);
+        let mut engine = Engine::new()
+            .with_event_log(false)
+            .with_hook(move |entry, account| {
+                recorded
+                    .lock()
+                    .unwrap()
+                    .push((entry.tx.tx, entry.status, account.available()))
+            });
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(30.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(500.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [
+                (1, TransactionStatus::Applied, 100.0),
+                (2, TransactionStatus::Applied, 70.0),
+                (3, TransactionStatus::FailedInsufficientFunds, 70.0),
+                (2, TransactionStatus::Applied, 70.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn history_samples_tracked_clients_after_each_applied_transaction() {
+        let mut engine = Engine::new().with_history(HashSet::from([1]));
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 2, Some(50.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(500.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Resolve, 1, 1, None));
+
+        let sample = |seq, available, held| BalanceSample {
+            seq,
+            available,
+            held,
+            total: available + held,
+        };
+        assert_eq!(
+            engine.history(1),
+            [
+                sample(0, 100.0, 0.0),
+                sample(3, 0.0, 100.0),
+                sample(4, 100.0, 0.0),
+            ]
+        );
+        assert!(engine.history(2).is_empty());
+    }
+
+    #[test]
+    fn stats_tally_mixed_outcomes() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Withdrawal, 2, 2, Some(10.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 3, Some(-1.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 99, None));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        engine.process(tx(TransactionType::Deposit, 1, 4, Some(1.0)));
+
+        let stats = engine.stats();
+        assert_eq!(
+            stats,
+            ProcessingStats {
+                transactions: 8,
+                applied: 3,
+                ignored_locked: 1,
+                ignored_missing_reference: 1,
+                failed_insufficient_funds: 1,
+                failed_invalid_amount: 1,
+                failed_duplicate_tx_id: 1,
+                clients: 2,
+                locked_accounts: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(stats.rejected(), 5);
+        assert!(stats.to_string().starts_with("transactions=8 applied=3 "));
+    }
+
+    #[test]
+    fn aggregate_sums_accounts_and_applied_volume() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 2, 2, Some(40.5)));
+        engine.process(tx(TransactionType::Withdrawal, 2, 3, Some(10.25)));
+        engine.process(tx(TransactionType::Withdrawal, 2, 4, Some(500.0)));
+        engine.process(tx(TransactionType::Deposit, 3, 5, Some(20.0)));
+        engine.process(tx(TransactionType::Dispute, 3, 5, None));
+        engine.process(tx(TransactionType::Deposit, 1, 6, Some(5.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 6, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 6, None));
+
+        let summary = engine.aggregate();
+        // 1: 100 available, locked; 2: 40.5 - 10.25; 3: 20 held
+        assert_eq!(
+            summary,
+            AggregateSummary {
+                available: 130.25,
+                held: 20.0,
+                clients: 3,
+                locked_accounts: 1,
+                // the failed 500 withdrawal isn't counted
+                applied_volume: 100.0 + 40.5 + 10.25 + 20.0 + 5.0,
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&summary).unwrap(),
+            r#"{"available":"130.2500","held":"20.0000","clients":3,"locked_accounts":1,"applied_volume":"175.7500"}"#
+        );
+    }
+
+    #[test]
+    fn snapshot_restore_resumes_like_a_single_pass() {
+        let transactions = [
+            tx(TransactionType::Deposit, 1, 1, Some(100.0)),
+            tx(TransactionType::Deposit, 2, 2, Some(50.5)),
+            tx(TransactionType::Withdrawal, 1, 3, Some(25.25)),
+            tx(TransactionType::Dispute, 2, 2, None),
+            tx(TransactionType::Deposit, 3, 4, Some(10.0)),
+            // second half references state from the first
+            tx(TransactionType::Deposit, 1, 1, Some(999.0)),
+            tx(TransactionType::Chargeback, 2, 2, None),
+            tx(TransactionType::Dispute, 1, 3, None),
+            tx(TransactionType::Withdrawal, 3, 5, Some(2.5)),
+            tx(TransactionType::Deposit, 2, 6, Some(1.0)),
+        ];
+        let (first_half, second_half) = transactions.split_at(5);
+
+        let mut single_pass = Engine::new();
+        transactions.iter().for_each(|t| single_pass.process(*t));
+
+        let mut first = Engine::new();
+        first_half.iter().for_each(|t| first.process(*t));
+        let json = serde_json::to_string(&first.snapshot()).unwrap();
+
+        let mut resumed = Engine::restore(serde_json::from_str(&json).unwrap());
+        second_half.iter().for_each(|t| resumed.process(*t));
+
+        assert_eq!(resumed.get_accounts(), single_pass.get_accounts());
+        let statuses = |engine: &Engine| -> Vec<TransactionStatus> {
+            engine
+                .transactions
+                .iter()
+                .map(|entry| entry.status)
+                .collect()
+        };
+        assert_eq!(statuses(&resumed), statuses(&single_pass)[5..]);
+    }
+
+    #[test]
+    fn disputes_open_tracks_disputes_in_flight() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(50.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        assert_eq!(engine.get_account(1).unwrap().disputes_open(), 2);
+
+        engine.process(tx(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(engine.get_account(1).unwrap().disputes_open(), 1);
+
+        let restored = Engine::restore(engine.snapshot());
+        assert_eq!(restored.get_account(1).unwrap().disputes_open(), 1);
+    }
+
+    #[test]
+    fn amount_at_max_is_allowed() {
+        let mut engine = Engine::new().with_max_amount(1000.0);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(1000.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(1000.0)));
+
+        assert_eq!(engine.transactions[0].status, TransactionStatus::Applied);
+        assert_eq!(engine.transactions[1].status, TransactionStatus::Applied);
+        assert_eq!(engine.get_account(1).unwrap().total(), 0.0);
+    }
+
+    #[test]
+    fn amount_above_max_is_rejected() {
+        let mut engine = Engine::new().with_max_amount(1000.0);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(1000.0001)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(500.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(1000.0001)));
+
+        assert_eq!(
+            engine.transactions[0].status,
+            TransactionStatus::FailedAmountTooLarge
+        );
+        assert_eq!(
+            engine.transactions[2].status,
+            TransactionStatus::FailedAmountTooLarge
+        );
+        assert_eq!(engine.get_account(1).unwrap().total(), 500.0);
+    }
+
+    // (message, key-values) of every record logged
+    type Captured = Vec<(String, Vec<(String, String)>)>;
+
+    struct CapturingLogger(std::sync::Mutex<Captured>);
+
+    impl log::Log for CapturingLogger {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            struct Collect(Vec<(String, String)>);
+            impl<'kvs> log::kv::VisitSource<'kvs> for Collect {
+                fn visit_pair(
+                    &mut self,
+                    key: log::kv::Key<'kvs>,
+                    value: log::kv::Value<'kvs>,
+                ) -> Result<(), log::kv::Error> {
+                    self.0.push((key.to_string(), value.to_string()));
+                    Ok(())
+                }
+            }
+            let mut fields = Collect(Vec::new());
+            record.key_values().visit(&mut fields).unwrap();
+            self.0
+                .lock()
+                .unwrap()
+                .push((record.args().to_string(), fields.0));
+        }
+
+        fn flush(&self) {}
+    }
+
+    static LOGGER: CapturingLogger = CapturingLogger(std::sync::Mutex::new(Vec::new()));
+
+    #[test]
+    fn warnings_carry_client_and_tx_id() {
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+
+        // a client no other test uses, the logger is global and tests run in parallel
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Withdrawal, 4242, 4201, Some(1.0)));
+        engine.process(tx(TransactionType::Dispute, 4242, 4202, None));
+        engine.process(tx(TransactionType::Resolve, 4242, 4203, None));
+        engine.process(tx(TransactionType::Chargeback, 4242, 4204, None));
+
+        let records = LOGGER.0.lock().unwrap();
+        let ours: Vec<_> = records
+            .iter()
+            .filter(|(_, fields)| fields.contains(&("client".to_string(), "4242".to_string())))
+            .collect();
+        assert_eq!(ours.len(), 4);
+        for (id, (message, fields)) in (4201..).zip(ours) {
+            assert!(
+                message.contains(&format!("client 4242, tx {id}")),
+                "{message}"
+            );
+            assert!(fields.contains(&("tx".to_string(), id.to_string())));
+        }
+    }
+
+    fn in_currency(tx: Transaction, currency: &str) -> Transaction {
+        Transaction {
+            currency: Some(currency.parse().unwrap()),
+            ..tx
+        }
+    }
+
+    #[test]
+    fn same_currency_transactions_apply() {
+        let mut engine = Engine::new();
+        engine.process(in_currency(
+            tx(TransactionType::Deposit, 1, 1, Some(10.0)),
+            "USD",
+        ));
+        engine.process(in_currency(
+            tx(TransactionType::Withdrawal, 1, 2, Some(4.0)),
+            "usd",
+        ));
+        // no currency column, the implicit currency matches any account
+        engine.process(tx(TransactionType::Deposit, 1, 3, Some(1.0)));
+
+        assert!(
+            engine
+                .transactions
+                .iter()
+                .all(|entry| entry.status == TransactionStatus::Applied)
+        );
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.total(), 7.0);
+        assert_eq!(account.currency(), Some("USD".parse().unwrap()));
+    }
+
+    #[test]
+    fn mismatched_currency_is_rejected() {
+        let mut engine = Engine::new();
+        engine.process(in_currency(
+            tx(TransactionType::Deposit, 1, 1, Some(10.0)),
+            "USD",
+        ));
+        engine.process(in_currency(
+            tx(TransactionType::Deposit, 1, 2, Some(5.0)),
+            "EUR",
+        ));
+        engine.process(in_currency(
+            tx(TransactionType::Withdrawal, 1, 3, Some(5.0)),
+            "EUR",
+        ));
+        // other clients keep their own currency
+        engine.process(in_currency(
+            tx(TransactionType::Deposit, 2, 4, Some(5.0)),
+            "EUR",
+        ));
+
+        assert_eq!(
+            engine.transactions[1].status,
+            TransactionStatus::FailedCurrencyMismatch
+        );
+        assert_eq!(
+            engine.transactions[2].status,
+            TransactionStatus::FailedCurrencyMismatch
+        );
+        assert_eq!(engine.transactions[3].status, TransactionStatus::Applied);
+        assert_eq!(engine.get_account(1).unwrap().total(), 10.0);
+    }
+
+    #[test]
+    fn verify_invariants_passes_after_processing() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(0.1)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(0.2)));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(0.05)));
+        assert_eq!(engine.verify_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn locked_account_ignores_deposits_by_default() {
+        let mut engine = Engine::new().with_locked_accounts([1]);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+
+        assert_eq!(engine.get_account(1).unwrap().total(), 0.0);
+        assert_eq!(
+            engine.transactions.last().unwrap().status,
+            TransactionStatus::IgnoredLocked
+        );
+    }
+
+    #[test]
+    fn block_debits_only_lets_deposits_and_resolves_into_a_locked_account() {
+        let mut engine = Engine::new().with_lock_policy(LockPolicy::BlockDebitsOnly);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(30.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        assert!(engine.get_account(1).unwrap().is_locked());
+
+        let last_status = |engine: &Engine| engine.transactions.last().unwrap().status;
+        engine.process(tx(TransactionType::Deposit, 1, 3, Some(50.0)));
+        assert_eq!(last_status(&engine), TransactionStatus::Applied);
+        engine.process(tx(TransactionType::Resolve, 1, 2, None));
+        assert_eq!(last_status(&engine), TransactionStatus::Applied);
+        for (tx_type, tx_id, amount) in [
+            (TransactionType::Withdrawal, 4, Some(10.0)),
+            (TransactionType::Dispute, 3, None),
+        ] {
+            engine.process(tx(tx_type, 1, tx_id, amount));
+            assert_eq!(last_status(&engine), TransactionStatus::IgnoredLocked);
+        }
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 80.0);
+        assert_eq!(account.held(), 0.0);
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn dispute_trail_follows_one_tx_id() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(5.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        engine.process(tx(TransactionType::Resolve, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+
+        let trail: Vec<(TransactionType, TransactionStatus)> = engine
+            .dispute_trail(1)
+            .iter()
+            .map(|entry| (entry.tx._type, entry.status))
+            .collect();
+        assert_eq!(
+            trail,
+            [
+                (TransactionType::Deposit, TransactionStatus::Applied),
+                (TransactionType::Dispute, TransactionStatus::Applied),
+                (TransactionType::Resolve, TransactionStatus::Applied),
+                (TransactionType::Dispute, TransactionStatus::Applied),
+            ]
+        );
+        assert!(engine.dispute_trail(3).is_empty());
+    }
+
+    #[test]
+    fn dust_accounts_have_digits_past_the_cents() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(10.0001)));
+        engine.process(tx(TransactionType::Deposit, 2, 2, Some(10.25)));
+        engine.process(tx(TransactionType::Deposit, 3, 3, Some(0.1)));
+        engine.process(tx(TransactionType::Deposit, 3, 4, Some(0.2)));
+        engine.process(tx(TransactionType::Deposit, 4, 5, Some(1.005)));
+        engine.process(tx(TransactionType::Withdrawal, 4, 6, Some(0.005)));
+        engine.process(tx(TransactionType::Deposit, 5, 7, Some(5.0)));
+        engine.process(tx(TransactionType::Withdrawal, 5, 8, Some(0.0150)));
+        engine.process(tx(TransactionType::Deposit, 6, 9, Some(0.0001)));
+        engine.process(tx(TransactionType::Deposit, 6, 10, Some(999.9999)));
+        engine.process(tx(TransactionType::Dispute, 6, 10, None));
+
+        // 0.1 + 0.2 float noise isn't dust, and neither is what a withdrawal brought back to
+        // cents. Client 6's total is a round 1000 but its available/held aren't
+        assert_eq!(engine.dust_accounts(), [1, 5, 6]);
+    }
+
+    #[test]
+    fn verify_invariants_detects_violations() {
+        let mut engine = Engine::new();
+        engine
+            .accounts
+            .insert(1, Account::with_balances(1, 10.0, 2.0, 11.0));
+        assert_eq!(
+            engine.verify_invariants(),
+            Err(InvariantViolation::BalanceMismatch {
+                client: 1,
+                available: 10.0,
+                held: 2.0,
+                total: 11.0,
+            })
+        );
+
+        engine
+            .accounts
+            .insert(1, Account::with_balances(1, 12.0, -1.0, 11.0));
+        assert_eq!(
+            engine.verify_invariants(),
+            Err(InvariantViolation::NegativeHeld {
+                client: 1,
+                held: -1.0
+            })
+        );
+    }
+
+    #[test]
+    fn seeded_locked_accounts_ignore_transactions() {
+        let mut engine = Engine::new().with_locked_accounts([5]);
+        engine.process(tx(TransactionType::Deposit, 5, 1, Some(10.0)));
+        engine.process(tx(TransactionType::Deposit, 6, 2, Some(10.0)));
+
+        assert_eq!(
+            engine.transactions[0].status,
+            TransactionStatus::IgnoredLocked
+        );
+        assert_eq!(engine.transactions[1].status, TransactionStatus::Applied);
+        let account = engine.get_account(5).unwrap();
+        assert!(account.is_locked());
+        assert_eq!(account.total(), 0.0);
+    }
+
+    #[test]
+    fn replay_rebuilds_the_same_accounts() {
+        let input = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n\
+                     withdrawal,1,3,20.0\ndispute,1,1,\ndeposit,1,1,3.0\nchargeback,1,1,\n\
+                     withdrawal,2,4,1.5\ndispute,2,4,\nresolve,2,4,\n";
+        let mut engine = Engine::new();
+        csv::Reader::from_reader(input.as_bytes())
+            .deserialize::<Transaction>()
+            .for_each(|tx| engine.process(tx.unwrap()));
+
+        let replayed = Engine::replay(engine.get_transactions());
+        assert_eq!(replayed.get_accounts(), engine.get_accounts());
+        assert_eq!(replayed.get_transactions(), engine.get_transactions());
+
+        // the same from a `--log` dump
+        let dump =
+            crate::ledger::transaction::transaction_entries_to_csv(engine.get_transactions());
+        let entries: Vec<TransactionEntry> = csv::Reader::from_reader(dump.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            Engine::replay(&entries).get_accounts(),
+            engine.get_accounts()
+        );
+    }
+
+    #[test]
+    fn process_entry_detects_a_tampered_status() {
+        let mut original = Engine::new();
+        original.process(tx(TransactionType::Deposit, 1, 1, Some(10.0)));
+        original.process(tx(TransactionType::Withdrawal, 1, 2, Some(50.0)));
+        original.process(tx(TransactionType::Dispute, 1, 1, None));
+        let mut entries = original.get_transactions().clone();
+        // the failed withdrawal recorded as applied
+        entries[1].status = TransactionStatus::Applied;
+
+        let mut audit = Engine::new().with_event_log(false);
+        assert_eq!(audit.process_entry(entries[0]), Ok(()));
+        let mismatch = audit.process_entry(entries[1]).unwrap_err();
+        assert_eq!(
+            mismatch,
+            StatusMismatch {
+                tx: entries[1].tx,
+                recorded: TransactionStatus::Applied,
+                recomputed: TransactionStatus::FailedInsufficientFunds,
+            }
+        );
+        assert_eq!(audit.process_entry(entries[2]), Ok(()));
+        // the engine's own view stands
+        assert_eq!(audit.get_account(1).unwrap().held(), 10.0);
+    }
+
+    #[test]
+    fn rounding_mode_quantizes_incoming_amounts() {
+        let cases = [
+            (RoundingMode::HalfUp, 1.2346),
+            (RoundingMode::HalfEven, 1.2346),
+            (RoundingMode::Truncate, 1.2345),
+        ];
+        for (mode, expected) in cases {
+            let mut engine = Engine::new().with_rounding(mode);
+            engine.process(tx(TransactionType::Deposit, 1, 1, Some(1.23455)));
+            engine.process(tx(TransactionType::Deposit, 2, 2, Some(1.2345)));
+            assert_eq!(engine.get_account(1).unwrap().total(), expected, "{mode:?}");
+            assert_eq!(engine.transactions[0].tx.amount, Some(expected));
+            // already at 4 places, untouched by every mode
+            assert_eq!(engine.get_account(2).unwrap().total(), 1.2345, "{mode:?}");
+        }
+
+        assert_eq!(RoundingMode::HalfEven.quantize(1.23445), 1.2344);
+        assert_eq!(RoundingMode::HalfUp.quantize(-1.23455), -1.2346);
+        assert_eq!(RoundingMode::Truncate.quantize(0.99999), 0.9999);
+    }
+
+    #[test]
+    fn dispute_after_withdrawing_the_funds_is_a_distinct_failure() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(10.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(10.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 99, None));
+
+        assert_eq!(
+            engine.transactions[2].status,
+            TransactionStatus::FailedInsufficientAvailableForDispute
+        );
+        assert_eq!(
+            engine.transactions[3].status,
+            TransactionStatus::IgnoredMissingReference
+        );
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.disputes_open(), 0);
+    }
+
+    #[test]
+    fn zero_amounts_are_invalid() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(0.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(-0.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 3, Some(-0.0)));
+
+        assert!(
+            engine
+                .transactions
+                .iter()
+                .all(|entry| entry.status == TransactionStatus::FailedInvalidAmount)
+        );
+    }
+
+    #[test]
+    fn merge_combines_disjoint_engines() {
+        let mut left = Engine::new();
+        left.process(tx(TransactionType::Deposit, 1, 1, Some(10.0)));
+        left.process(tx(TransactionType::Dispute, 1, 1, None));
+        let mut right = Engine::new();
+        right.process(tx(TransactionType::Deposit, 2, 2, Some(5.0)));
+        right.process(tx(TransactionType::Withdrawal, 2, 3, Some(50.0)));
+
+        left.merge(right).unwrap();
+        assert_eq!(left.get_accounts().len(), 2);
+        assert_eq!(left.get_account(1).unwrap().held(), 10.0);
+        assert_eq!(left.get_account(2).unwrap().total(), 5.0);
+        assert_eq!(
+            left.transactions
+                .iter()
+                .map(|entry| (entry.seq, entry.tx.client))
+                .collect::<Vec<_>>(),
+            [(0, 1), (0, 2), (1, 1), (1, 2)]
+        );
+
+        // the merged state keeps working: the duplicate index and tx state came along
+        left.process(tx(TransactionType::Deposit, 3, 2, Some(1.0)));
+        left.process(tx(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(
+            left.transactions[4].status,
+            TransactionStatus::FailedDuplicateTxID
+        );
+        assert_eq!(left.transactions[5].status, TransactionStatus::Applied);
+    }
+
+    #[test]
+    fn merge_rejects_collisions() {
+        let mut left = Engine::new();
+        left.process(tx(TransactionType::Deposit, 1, 7, Some(10.0)));
+
+        let mut same_tx = Engine::new();
+        same_tx.process(tx(TransactionType::Deposit, 2, 7, Some(5.0)));
+        assert_eq!(left.merge(same_tx), Err(MergeError::TxIdCollision(7)));
+
+        let mut same_client = Engine::new();
+        same_client.process(tx(TransactionType::Deposit, 1, 8, Some(5.0)));
+        assert_eq!(
+            left.merge(same_client),
+            Err(MergeError::AccountCollision(1))
+        );
+
+        // nothing was merged by the failed attempts
+        assert_eq!(left.get_accounts().len(), 1);
+        assert_eq!(left.transactions.len(), 1);
+        assert_eq!(left.get_account(1).unwrap().total(), 10.0);
+    }
+
+    #[test]
+    fn stale_disputes_auto_resolve() {
+        let mut engine = Engine::new().with_auto_resolve_after(3);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(10.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        // three more events keep the dispute open, the fourth releases it
+        for tx_id in 2..=5 {
+            engine.process(tx(TransactionType::Deposit, 2, tx_id, Some(1.0)));
+        }
+        let statuses: Vec<_> = engine.transactions.iter().map(|e| e.status).collect();
+        assert_eq!(statuses[5], TransactionStatus::AutoResolved);
+        assert_eq!(
+            engine.transactions[5].tx,
+            tx(TransactionType::Resolve, 1, 1, None)
+        );
+        assert_eq!(statuses[6], TransactionStatus::Applied);
+
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 10.0);
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.disputes_open(), 0);
+
+        // a resolved dispute can't be charged back anymore
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        assert!(!engine.get_account(1).unwrap().is_locked());
+    }
+
+    #[test]
+    fn disputes_settled_in_time_do_not_auto_resolve() {
+        let mut engine = Engine::new().with_auto_resolve_after(2);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(10.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        for tx_id in 2..=5 {
+            engine.process(tx(TransactionType::Deposit, 2, tx_id, Some(1.0)));
+        }
+
+        assert!(
+            engine
+                .transactions
+                .iter()
+                .all(|entry| entry.status != TransactionStatus::AutoResolved)
+        );
+        assert_eq!(engine.get_account(1).unwrap().total(), 0.0);
+    }
+
+    #[test]
+    fn clear_empties_the_engine_for_the_next_batch() {
+        let mut engine = Engine::new().with_credit_limit(5.0);
+        for i in 0..100 {
+            engine.process(tx(TransactionType::Deposit, i as u16, i, Some(10.0)));
+        }
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        let capacity = (engine.accounts.capacity(), engine.tx_state.capacity());
+
+        engine.clear();
+        assert!(engine.get_accounts().is_empty());
+        assert!(engine.get_transactions().is_empty());
+        assert!(engine.tx_state.is_empty());
+        assert_eq!(engine.stats(), ProcessingStats::default());
+        assert_eq!(
+            (engine.accounts.capacity(), engine.tx_state.capacity()),
+            capacity
+        );
+
+        // tx IDs of the previous batch are free again, the configuration is kept
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(2.0)));
+        engine.process(tx(TransactionType::Withdrawal, 1, 2, Some(6.0)));
+        assert_eq!(engine.get_account(1).unwrap().available(), -4.0);
+        let entries = engine.get_transactions();
+        assert_eq!(entries.iter().map(|e| e.seq).collect::<Vec<_>>(), [0, 1]);
+        assert!(
+            entries
+                .iter()
+                .all(|e| e.status == TransactionStatus::Applied)
+        );
+    }
+
+    #[test]
+    fn restoring_an_old_snapshot_keeps_what_open_disputes_hold() {
+        let mut engine = Engine::new();
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(30.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(12.5)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        engine.process(tx(TransactionType::Resolve, 1, 2, None));
+
+        // as written before transaction states recorded `held`
+        let mut snapshot = serde_json::to_value(engine.snapshot()).unwrap();
+        for state in snapshot["tx_state"].as_object_mut().unwrap().values_mut() {
+            state.as_object_mut().unwrap().remove("held").unwrap();
+        }
+        let mut restored = Engine::restore(serde_json::from_value(snapshot).unwrap());
+        assert_eq!(restored.tx_state[&1].held, 30.0);
+        assert_eq!(restored.tx_state[&2].held, 0.0);
+
+        restored.process(tx(TransactionType::Resolve, 1, 1, None));
+        let account = restored.get_account(1).unwrap();
+        assert_eq!(account.held(), 0.0);
+        assert_eq!(account.available(), 42.5);
+        assert_eq!(account.disputes_open(), 0);
+    }
+
+    #[test]
+    fn apply_all_yields_auto_resolved_entries() {
+        let transactions = [
+            tx(TransactionType::Deposit, 1, 1, Some(10.0)),
+            tx(TransactionType::Dispute, 1, 1, None),
+            tx(TransactionType::Deposit, 2, 2, Some(1.0)),
+            tx(TransactionType::Deposit, 2, 3, Some(1.0)),
+        ];
+        let mut engine = Engine::new()
+            .with_auto_resolve_after(1)
+            .with_event_log(false);
+        let entries: Vec<TransactionEntry> = engine.apply_all(transactions).collect();
+
+        assert_eq!(
+            entries
+                .iter()
+                .map(|entry| (entry.tx.tx, entry.status))
+                .collect::<Vec<_>>(),
+            [
+                (1, TransactionStatus::Applied),
+                (1, TransactionStatus::Applied),
+                (2, TransactionStatus::Applied),
+                (1, TransactionStatus::AutoResolved),
+                (3, TransactionStatus::Applied),
+            ]
+        );
+        assert_eq!(entries[3].tx, tx(TransactionType::Resolve, 1, 1, None));
+        assert_eq!(engine.get_account(1).unwrap().held(), 0.0);
+    }
+
+    #[test]
+    fn disputes_released_on_unlock_leave_the_auto_resolve_queue() {
+        let mut engine = Engine::new()
+            .with_allow_unlock(true)
+            .with_release_held_on_unlock(true)
+            .with_auto_resolve_after(10);
+        engine.process(tx(TransactionType::Deposit, 1, 1, Some(100.0)));
+        engine.process(tx(TransactionType::Deposit, 1, 2, Some(30.0)));
+        engine.process(tx(TransactionType::Dispute, 1, 1, None));
+        engine.process(tx(TransactionType::Dispute, 1, 2, None));
+        engine.process(tx(TransactionType::Chargeback, 1, 1, None));
+        assert!(engine.open_disputes.contains(&(3, 2)));
+
+        engine.process(tx(TransactionType::Unlock, 1, 3, None));
+        assert!(engine.open_disputes.is_empty());
+        assert_eq!(
+            engine.get_transactions().last().unwrap().status,
+            TransactionStatus::AutoResolved
+        );
+    }
 }
@@ -1,11 +1,166 @@
 pub mod account;
 pub mod engine;
+pub mod stats;
 pub mod transaction;
 
+use transaction::{InvalidAmount, TransactionType};
+
+// amounts carry 4 decimal places
+const SCALE: f64 = 10_000.0;
+
+/// How `Engine::with_rounding` quantizes incoming amounts with more than 4 decimal places.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    HalfUp,
+    HalfEven,
+    Truncate,
+}
+
+impl RoundingMode {
+    pub fn quantize(self, amount: f64) -> f64 {
+        let scaled = amount * SCALE;
+        // decimal inputs aren't exact in binary (1.2345 * 10^4 may land just under 12345),
+        // anything this close to a whole number or to a tie counts as one
+        const NOISE: f64 = 1e-6;
+        let nearest = scaled.round();
+        if (scaled - nearest).abs() < NOISE {
+            return nearest / SCALE;
+        }
+        let whole = scaled.trunc();
+        let tie = ((scaled - whole).abs() - 0.5).abs() < NOISE;
+        let rounded = match self {
+            Self::Truncate => whole,
+            Self::HalfUp if tie => whole + scaled.signum(),
+            Self::HalfEven if tie && whole % 2.0 == 0.0 => whole,
+            Self::HalfEven if tie => whole + scaled.signum(),
+            Self::HalfUp | Self::HalfEven => nearest,
+        };
+        rounded / SCALE
+    }
+}
+
+/// What `Engine` does to an account on a chargeback, besides reversing the disputed funds.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChargebackPolicy {
+    /// Freeze the account, later transactions are ignored.
+    #[default]
+    LockAccount,
+    /// Only reverse the funds, the account stays usable.
+    ReverseOnly,
+}
+
+/// Which transactions `Engine` ignores (`IgnoredLocked`) on a locked account. `unlock`
+/// always goes through.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// Nothing else applies.
+    #[default]
+    FreezeAll,
+    /// Only what takes funds out is blocked: withdrawals, new disputes and chargebacks.
+    /// Deposits and resolves still apply.
+    BlockDebitsOnly,
+}
+
+impl LockPolicy {
+    pub fn blocks(self, tx_type: TransactionType) -> bool {
+        match self {
+            Self::FreezeAll => tx_type != TransactionType::Unlock,
+            Self::BlockDebitsOnly => matches!(
+                tx_type,
+                TransactionType::Withdrawal
+                    | TransactionType::Dispute
+                    | TransactionType::Chargeback
+            ),
+        }
+    }
+}
+
 pub fn serialize_4dp<S: serde::Serializer>(val: &f64, s: S) -> Result<S::Ok, S::Error> {
     s.serialize_str(&format!("{:.4}", val))
 }
 
+/// Reads back what `serialize_4dp` writes (`"10.5000"`), or a plain number as hand-written
+/// JSON would have it (`10.5`).
+pub fn deserialize_4dp<'de, D: serde::Deserializer<'de>>(d: D) -> Result<f64, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Balance {
+        Number(f64),
+        Text(String),
+    }
+    match <Balance as serde::Deserialize>::deserialize(d)? {
+        Balance::Number(val) => Ok(val),
+        Balance::Text(val) => val.parse().map_err(serde::de::Error::custom),
+    }
+}
+
+/// The `amount` column as read, see `deserialize_amount`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AmountColumn {
+    Empty,
+    Plain(f64),
+    // a number, just not written as a plain decimal
+    NotPlain(InvalidAmount),
+}
+
+impl AmountColumn {
+    pub(crate) fn amount(self) -> Option<f64> {
+        match self {
+            Self::Plain(amount) => Some(amount),
+            Self::Empty | Self::NotPlain(_) => None,
+        }
+    }
+
+    pub(crate) fn invalid(self) -> Option<InvalidAmount> {
+        match self {
+            Self::NotPlain(invalid) => Some(invalid),
+            Self::Empty | Self::Plain(_) => None,
+        }
+    }
+}
+
+/// Reads the `amount` column, which must be present even when empty (serde only treats a
+/// missing field as absent when no `deserialize_with` is set).
+///
+/// Only plain decimals are read as amounts (`100`, `-0.0`, `0.50`). `1e2`, `inf` or `NaN`
+/// parse as floats but are not how a financial file writes amounts: they're kept as written,
+/// for the engine to fail with `FailedInvalidAmount` and the reports to show. Anything else
+/// (`abc`, `1.2.3`) fails to deserialize.
+pub(crate) fn deserialize_amount<'de, D: serde::Deserializer<'de>>(
+    d: D,
+) -> Result<AmountColumn, D::Error> {
+    match <Option<String> as serde::Deserialize>::deserialize(d)? {
+        Some(val) if !val.is_empty() => match parse_plain_decimal(&val) {
+            Some(amount) => Ok(AmountColumn::Plain(amount)),
+            None if val.parse::<f64>().is_ok() => Ok(AmountColumn::NotPlain(val.as_str().into())),
+            None => Err(serde::de::Error::custom(format!("invalid amount: {}", val))),
+        },
+        _ => Ok(AmountColumn::Empty),
+    }
+}
+
+/// Writes what `deserialize_amount` read: plain amounts with 4 decimal places, the others
+/// as they were written.
+pub(crate) fn serialize_amount<S: serde::Serializer>(
+    val: &AmountColumn,
+    s: S,
+) -> Result<S::Ok, S::Error> {
+    match val {
+        AmountColumn::NotPlain(invalid) => s.collect_str(invalid),
+        _ => serialize_4dp_or_none(&val.amount(), s),
+    }
+}
+
+pub(crate) fn parse_plain_decimal(val: &str) -> Option<f64> {
+    let unsigned = val.strip_prefix(['-', '+']).unwrap_or(val);
+    let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+    let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+    if whole.is_empty() && fraction.is_empty() || !digits(whole) || !digits(fraction) {
+        return None;
+    }
+    val.parse().ok()
+}
+
 pub fn serialize_4dp_or_none<S: serde::Serializer>(
     val: &Option<f64>,
     s: S,
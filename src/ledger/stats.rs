@@ -0,0 +1,124 @@
+use crate::ledger::{serialize_4dp, transaction::TransactionStatus};
+use serde::Serialize;
+use std::fmt::Display;
+
+/// Tally of how the processed transactions were handled, built from the event log.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessingStats {
+    pub transactions: u64,
+    pub applied: u64,
+    pub applied_partial: u64,
+    // synthetic resolves, not read from the input so not part of `transactions`
+    pub auto_resolved: u64,
+    pub ignored_locked: u64,
+    pub ignored_missing_reference: u64,
+    pub ignored_not_disputed: u64,
+    pub ignored_outside_window: u64,
+    pub ignored_unauthorized: u64,
+    pub failed_insufficient_funds: u64,
+    pub failed_insufficient_available_for_dispute: u64,
+    pub failed_invalid_amount: u64,
+    pub failed_amount_too_large: u64,
+    pub failed_currency_mismatch: u64,
+    pub failed_duplicate_tx_id: u64,
+    pub clients: u64,
+    pub locked_accounts: u64,
+}
+
+impl ProcessingStats {
+    pub fn record(&mut self, status: TransactionStatus) {
+        if status != TransactionStatus::AutoResolved {
+            self.transactions += 1;
+        }
+        let counter = match status {
+            TransactionStatus::Applied => &mut self.applied,
+            TransactionStatus::AppliedPartial => &mut self.applied_partial,
+            TransactionStatus::AutoResolved => &mut self.auto_resolved,
+            TransactionStatus::IgnoredLocked => &mut self.ignored_locked,
+            TransactionStatus::IgnoredMissingReference => &mut self.ignored_missing_reference,
+            TransactionStatus::IgnoredNotDisputed => &mut self.ignored_not_disputed,
+            TransactionStatus::IgnoredOutsideWindow => &mut self.ignored_outside_window,
+            TransactionStatus::IgnoredUnauthorized => &mut self.ignored_unauthorized,
+            TransactionStatus::FailedInsufficientFunds => &mut self.failed_insufficient_funds,
+            TransactionStatus::FailedInsufficientAvailableForDispute => {
+                &mut self.failed_insufficient_available_for_dispute
+            }
+            TransactionStatus::FailedInvalidAmount => &mut self.failed_invalid_amount,
+            TransactionStatus::FailedAmountTooLarge => &mut self.failed_amount_too_large,
+            TransactionStatus::FailedCurrencyMismatch => &mut self.failed_currency_mismatch,
+            TransactionStatus::FailedDuplicateTxID => &mut self.failed_duplicate_tx_id,
+        };
+        *counter += 1;
+    }
+
+    /// Transactions that were read but not applied, whatever the reason. Partially applied
+    /// withdrawals aren't rejected.
+    pub fn rejected(&self) -> u64 {
+        self.transactions - self.applied - self.applied_partial
+    }
+}
+
+impl Display for ProcessingStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "transactions={} applied={} applied_partial={} auto_resolved={} ignored_locked={} \
+             ignored_missing_reference={} ignored_not_disputed={} ignored_outside_window={} \
+             ignored_unauthorized={} failed_insufficient_funds={} \
+             failed_insufficient_available_for_dispute={} failed_invalid_amount={} \
+             failed_amount_too_large={} failed_currency_mismatch={} failed_duplicate_tx_id={} \
+             clients={} locked_accounts={}",
+            self.transactions,
+            self.applied,
+            self.applied_partial,
+            self.auto_resolved,
+            self.ignored_locked,
+            self.ignored_missing_reference,
+            self.ignored_not_disputed,
+            self.ignored_outside_window,
+            self.ignored_unauthorized,
+            self.failed_insufficient_funds,
+            self.failed_insufficient_available_for_dispute,
+            self.failed_invalid_amount,
+            self.failed_amount_too_large,
+            self.failed_currency_mismatch,
+            self.failed_duplicate_tx_id,
+            self.clients,
+            self.locked_accounts,
+        )
+    }
+}
+
+/// The money the event log says moved against what the accounts hold, see
+/// `Engine::ledger_proof`.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LedgerProof {
+    /// Net flow of the applied transactions: deposits, minus withdrawals, plus disputed
+    /// withdrawals (their funds are back in the account), minus chargebacks.
+    pub flows: f64,
+    /// Sum of every account's `total`.
+    pub totals: f64,
+    /// Whether the two agree within `tolerance`.
+    pub balanced: bool,
+    pub tolerance: f64,
+}
+
+impl Display for LedgerProof {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "ledger balanced: {}", self.balanced)
+    }
+}
+
+/// Engine-wide totals across all accounts, see `Engine::aggregate`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize)]
+pub struct AggregateSummary {
+    #[serde(serialize_with = "serialize_4dp")]
+    pub available: f64,
+    #[serde(serialize_with = "serialize_4dp")]
+    pub held: f64,
+    pub clients: u64,
+    pub locked_accounts: u64,
+    /// Sum of the amounts of every applied deposit and withdrawal.
+    #[serde(serialize_with = "serialize_4dp")]
+    pub applied_volume: f64,
+}
@@ -1,8 +1,9 @@
-use crate::ledger::serialize_4dp_or_none;
+use crate::ledger::{AmountColumn, deserialize_amount, serialize_amount};
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+// deserialized case-insensitively, see `from_str_ci`
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -10,12 +11,60 @@ pub enum TransactionType {
     Dispute,
     Resolve,
     Chargeback,
+    // administrative reversal of a chargeback lock, only honoured when the engine allows it
+    Unlock,
 }
 
 impl TransactionType {
+    /// Every type, in declaration order.
+    pub const ALL: [Self; 6] = [
+        Self::Deposit,
+        Self::Withdrawal,
+        Self::Dispute,
+        Self::Resolve,
+        Self::Chargeback,
+        Self::Unlock,
+    ];
+
     pub fn is_under_dispute(&self) -> bool {
         matches!(self, Self::Dispute)
     }
+
+    /// Deposits and withdrawals introduce new tx IDs, the other types reference existing ones.
+    pub fn introduces_tx_id(&self) -> bool {
+        matches!(self, Self::Deposit | Self::Withdrawal)
+    }
+
+    /// `from_str` ignoring ASCII case: `Deposit` and `DEPOSIT` are `deposit`. Input files
+    /// are read this way, upstream exports aren't always lowercase.
+    pub fn from_str_ci(s: &str) -> anyhow::Result<Self> {
+        if !s.bytes().any(|b| b.is_ascii_uppercase()) {
+            return s.parse();
+        }
+        s.to_ascii_lowercase()
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Unknown transaction type: {}", s))
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        struct TypeVisitor;
+
+        impl serde::de::Visitor<'_> for TypeVisitor {
+            type Value = TransactionType;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("a transaction type such as `deposit`")
+            }
+
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                TransactionType::from_str_ci(v).map_err(E::custom)
+            }
+        }
+
+        d.deserialize_str(TypeVisitor)
+    }
 }
 
 impl Display for TransactionType {
@@ -26,19 +75,239 @@ impl Display for TransactionType {
             Self::Dispute => write!(f, "dispute"),
             Self::Resolve => write!(f, "resolve"),
             Self::Chargeback => write!(f, "chargeback"),
+            Self::Unlock => write!(f, "unlock"),
+        }
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = anyhow::Error;
+
+    /// The names used in the `type` column: `deposit`, `withdrawal`, `dispute`...
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "deposit" => Self::Deposit,
+            "withdrawal" => Self::Withdrawal,
+            "dispute" => Self::Dispute,
+            "resolve" => Self::Resolve,
+            "chargeback" => Self::Chargeback,
+            "unlock" => Self::Unlock,
+            _ => anyhow::bail!("Unknown transaction type: {}", s),
+        })
+    }
+}
+
+/// Three-letter currency code such as `USD`, stored inline so `Transaction` stays `Copy`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Currency([u8; 3]);
+
+impl FromStr for Currency {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match <[u8; 3]>::try_from(s.as_bytes()) {
+            Ok(code) if code.iter().all(u8::is_ascii_alphabetic) => {
+                Ok(Self(code.map(|c| c.to_ascii_uppercase())))
+            }
+            _ => anyhow::bail!("Invalid currency code: {}", s),
+        }
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // only ever built from ASCII letters
+        self.0.iter().try_for_each(|&c| write!(f, "{}", c as char))
+    }
+}
+
+impl Serialize for Currency {
+    fn serialize<S: serde::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        s.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Currency {
+    fn deserialize<D: serde::Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        let val = <String as Deserialize>::deserialize(d)?;
+        val.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// An `amount` that parses as a number but isn't a plain decimal (`1e2`, `inf`, `NaN`), as it
+/// was written, so reports can show the row's own text. Stored inline so `Transaction` stays
+/// `Copy`: text past 14 bytes is cut and shown ending in `…`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidAmount {
+    text: [u8; 14],
+    len: u8,
+    cut: bool,
+}
+
+impl From<&str> for InvalidAmount {
+    fn from(s: &str) -> Self {
+        let mut len = s.len().min(14);
+        while !s.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut text = [0; 14];
+        text[..len].copy_from_slice(&s.as_bytes()[..len]);
+        Self {
+            text,
+            len: len as u8,
+            cut: len < s.len(),
         }
     }
 }
 
+impl Display for InvalidAmount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text =
+            std::str::from_utf8(&self.text[..self.len as usize]).expect("cut on a char boundary");
+        write!(f, "{}{}", text, if self.cut { "…" } else { "" })
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
-#[serde(rename_all = "camelCase")]
+#[serde(from = "TransactionRow", into = "TransactionRow")]
 pub struct Transaction {
-    #[serde(rename = "type")]
     pub _type: TransactionType,
     pub client: u16,
     pub tx: u32,
-    #[serde(serialize_with = "serialize_4dp_or_none")]
     pub amount: Option<f64>,
+    // `None` is the implicit currency of files without a `currency` column
+    pub currency: Option<Currency>,
+    // the amount column's text when it isn't a plain decimal, `amount` is `None` then.
+    // Written in its place, so the log and errors CSV show what the row said
+    pub invalid_amount: Option<InvalidAmount>,
+}
+
+// `Transaction` as read and written, its amount column carrying `amount` or `invalid_amount`
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TransactionRow {
+    #[serde(rename = "type")]
+    _type: TransactionType,
+    // the csv crate would also take `0x`-prefixed hex here, that's opt-in via `HexIdTransaction`
+    #[serde(deserialize_with = "deserialize_dec_id")]
+    client: u16,
+    #[serde(deserialize_with = "deserialize_dec_id")]
+    tx: u32,
+    // the column is required even though the value may be empty (disputes, resolves...)
+    #[serde(
+        serialize_with = "serialize_amount",
+        deserialize_with = "deserialize_amount"
+    )]
+    amount: AmountColumn,
+    // optional input column. Not serialized so the log/errors CSV keep their
+    // `type,client,tx,amount` columns
+    #[serde(default, skip_serializing)]
+    currency: Option<Currency>,
+}
+
+impl From<TransactionRow> for Transaction {
+    fn from(row: TransactionRow) -> Self {
+        Self {
+            currency: row.currency,
+            invalid_amount: row.amount.invalid(),
+            ..Self::new(row._type, row.client, row.tx, row.amount.amount())
+        }
+    }
+}
+
+impl From<Transaction> for TransactionRow {
+    fn from(tx: Transaction) -> Self {
+        Self {
+            _type: tx._type,
+            client: tx.client,
+            tx: tx.tx,
+            amount: tx.amount_column(),
+            currency: tx.currency,
+        }
+    }
+}
+
+/// `Transaction` as read with hexadecimal IDs allowed: `client`/`tx` may carry a `0x` prefix
+/// (`0x1F`), unprefixed IDs are decimal. Built by the consumer for `IdFormat::Hex` input.
+#[derive(Debug, Deserialize)]
+pub(crate) struct HexIdTransaction {
+    #[serde(rename = "type")]
+    _type: TransactionType,
+    #[serde(deserialize_with = "deserialize_hex_id")]
+    client: u16,
+    #[serde(deserialize_with = "deserialize_hex_id")]
+    tx: u32,
+    #[serde(deserialize_with = "deserialize_amount")]
+    amount: AmountColumn,
+    #[serde(default)]
+    currency: Option<Currency>,
+}
+
+impl From<HexIdTransaction> for Transaction {
+    fn from(row: HexIdTransaction) -> Self {
+        Self {
+            currency: row.currency,
+            invalid_amount: row.amount.invalid(),
+            ..Self::new(row._type, row.client, row.tx, row.amount.amount())
+        }
+    }
+}
+
+fn deserialize_dec_id<'de, D, T>(d: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_id(d, false)
+}
+
+fn deserialize_hex_id<'de, D, T>(d: D) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    deserialize_id(d, true)
+}
+
+// leading zeros are fine either way (`0x001F`, `00042`), the value must fit the ID type.
+// Parsed from the `&str` the deserializer lends, without copying each field into a `String`
+fn deserialize_id<'de, D, T>(d: D, hex: bool) -> Result<T, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: TryFrom<u64>,
+{
+    struct IdVisitor<T> {
+        hex: bool,
+        id: std::marker::PhantomData<T>,
+    }
+
+    impl<T: TryFrom<u64>> serde::de::Visitor<'_> for IdVisitor<T> {
+        type Value = T;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.write_str("a transaction or client ID")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            parse_id(v, self.hex)
+                .and_then(|id| T::try_from(id).ok())
+                .ok_or_else(|| E::custom(format!("invalid id: {}", v)))
+        }
+    }
+
+    d.deserialize_str(IdVisitor {
+        hex,
+        id: std::marker::PhantomData,
+    })
+}
+
+// an ID as `client`/`tx` are written, before checking it fits the ID type
+pub(crate) fn parse_id(val: &str, hex: bool) -> Option<u64> {
+    match val.strip_prefix("0x").or_else(|| val.strip_prefix("0X")) {
+        Some(digits) if hex => u64::from_str_radix(digits, 16).ok(),
+        Some(_) => None,
+        None => val.parse().ok(),
+    }
 }
 
 /// Status of how an incoming transaction line was handled.
@@ -46,20 +315,73 @@ pub struct Transaction {
 #[serde(rename_all = "snake_case")]
 pub enum TransactionStatus {
     Applied,
+    // withdrawal that drained the available funds short of its amount, see
+    // `Engine::with_partial_withdrawals`
+    AppliedPartial,
+    // synthetic resolve recorded by the engine for a dispute left open too long, or closed
+    // out by `Engine::resolve_all_open`
+    AutoResolved,
     IgnoredLocked,
     IgnoredMissingReference,
+    IgnoredNotDisputed,
+    IgnoredOutsideWindow,
+    IgnoredUnauthorized,
     FailedInsufficientFunds,
+    // the disputed deposit's funds are no longer available (withdrawn since)
+    FailedInsufficientAvailableForDispute,
     FailedInvalidAmount,
+    FailedAmountTooLarge,
+    FailedCurrencyMismatch,
+    // snake_case would make this `failed_duplicate_tx_i_d`, keep it in line with the log output
+    #[serde(rename = "failed_duplicate_tx_id")]
     FailedDuplicateTxID,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(from = "EntryRow")]
 pub struct TransactionEntry {
+    /// Sequence index the engine processed the transaction at, dense and increasing across
+    /// a run (shards included). A synthetic entry carries the index of the transaction that
+    /// triggered it.
+    pub seq: u64,
     #[serde(flatten)]
     pub tx: Transaction,
     pub status: TransactionStatus,
 }
 
+// `TransactionEntry` as written by the log. Deserializing goes through this flat row: csv
+// hands `#[serde(flatten)]` fields over as already inferred values, which would bypass the
+// `amount` parsing
+#[derive(Deserialize)]
+struct EntryRow {
+    // missing in logs dumped before the column existed
+    #[serde(default)]
+    seq: u64,
+    #[serde(rename = "type")]
+    _type: TransactionType,
+    client: u16,
+    tx: u32,
+    #[serde(deserialize_with = "deserialize_amount")]
+    amount: AmountColumn,
+    #[serde(default)]
+    currency: Option<Currency>,
+    status: TransactionStatus,
+}
+
+impl From<EntryRow> for TransactionEntry {
+    fn from(row: EntryRow) -> Self {
+        Self {
+            seq: row.seq,
+            tx: Transaction {
+                currency: row.currency,
+                invalid_amount: row.amount.invalid(),
+                ..Transaction::new(row._type, row.client, row.tx, row.amount.amount())
+            },
+            status: row.status,
+        }
+    }
+}
+
 impl Display for Transaction {
     // read account.rs for the exact same comment that I would write here
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -83,6 +405,175 @@ impl Transaction {
             client,
             tx,
             amount,
+            currency: None,
+            invalid_amount: None,
+        }
+    }
+
+    fn amount_column(&self) -> AmountColumn {
+        match (self.amount, self.invalid_amount) {
+            (_, Some(invalid)) => AmountColumn::NotPlain(invalid),
+            (Some(amount), None) => AmountColumn::Plain(amount),
+            (None, None) => AmountColumn::Empty,
+        }
+    }
+
+    pub fn builder() -> TransactionBuilder {
+        TransactionBuilder::default()
+    }
+
+    /// Checks the transaction's shape without an engine: deposits and withdrawals need a
+    /// positive, finite amount, the types referencing an existing transaction must not have
+    /// one. The engine fails a deposit/withdrawal with `FailedInvalidAmount` on the same
+    /// check; whether it applies still depends on the engine's state.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let (_type, tx) = (self._type, self.tx);
+        match (_type.introduces_tx_id(), self.amount_column()) {
+            (true, AmountColumn::Empty) => Err(ValidationError::MissingAmount { _type, tx }),
+            (true, AmountColumn::NotPlain(amount)) => {
+                Err(ValidationError::NotPlainAmount { _type, tx, amount })
+            }
+            (true, AmountColumn::Plain(amount)) if amount <= 0.0 || !amount.is_finite() => {
+                Err(ValidationError::InvalidAmount { _type, tx, amount })
+            }
+            (false, AmountColumn::Plain(_) | AmountColumn::NotPlain(_)) => {
+                Err(ValidationError::UnexpectedAmount { _type, tx })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Why `Transaction::validate` rejected a transaction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValidationError {
+    // a deposit or withdrawal without an amount
+    MissingAmount {
+        _type: TransactionType,
+        tx: u32,
+    },
+    // zero, negative, NaN or infinite
+    InvalidAmount {
+        _type: TransactionType,
+        tx: u32,
+        amount: f64,
+    },
+    // read as written since it isn't a plain decimal (`1e2`, `inf`)
+    NotPlainAmount {
+        _type: TransactionType,
+        tx: u32,
+        amount: InvalidAmount,
+    },
+    // a dispute, resolve, chargeback or unlock carrying an amount
+    UnexpectedAmount {
+        _type: TransactionType,
+        tx: u32,
+    },
+}
+
+impl Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingAmount { _type, tx } => write!(f, "{} {} requires an amount", _type, tx),
+            Self::InvalidAmount { _type, tx, amount } => write!(
+                f,
+                "{} {} has an invalid amount {}, it must be positive and finite",
+                _type, tx, amount
+            ),
+            Self::NotPlainAmount { _type, tx, amount } => write!(
+                f,
+                "{} {} has an invalid amount {}, it must be a plain decimal",
+                _type, tx, amount
+            ),
+            Self::UnexpectedAmount { _type, tx } => {
+                write!(f, "{} {} must not have an amount", _type, tx)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Named-setter alternative to `Transaction::new`, which takes easily swapped `client`/`tx` IDs.
+///
+/// `build` checks that every field is set and that the amount matches the type: deposits and
+/// withdrawals need one, the types referencing an existing transaction must not have one.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TransactionBuilder {
+    _type: Option<TransactionType>,
+    client: Option<u16>,
+    tx: Option<u32>,
+    amount: Option<f64>,
+    currency: Option<Currency>,
+}
+
+impl TransactionBuilder {
+    pub fn kind(mut self, _type: TransactionType) -> Self {
+        self._type = Some(_type);
+        self
+    }
+
+    pub fn deposit(self) -> Self {
+        self.kind(TransactionType::Deposit)
+    }
+
+    pub fn withdrawal(self) -> Self {
+        self.kind(TransactionType::Withdrawal)
+    }
+
+    pub fn dispute(self) -> Self {
+        self.kind(TransactionType::Dispute)
+    }
+
+    pub fn resolve(self) -> Self {
+        self.kind(TransactionType::Resolve)
+    }
+
+    pub fn chargeback(self) -> Self {
+        self.kind(TransactionType::Chargeback)
+    }
+
+    pub fn unlock(self) -> Self {
+        self.kind(TransactionType::Unlock)
+    }
+
+    pub fn client(mut self, client: u16) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub fn tx(mut self, tx: u32) -> Self {
+        self.tx = Some(tx);
+        self
+    }
+
+    pub fn amount(mut self, amount: f64) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+
+    pub fn currency(mut self, currency: Currency) -> Self {
+        self.currency = Some(currency);
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<Transaction> {
+        let Some(_type) = self._type else {
+            anyhow::bail!("Transaction type is not set");
+        };
+        let Some(client) = self.client else {
+            anyhow::bail!("Client is not set for {}", _type);
+        };
+        let Some(tx) = self.tx else {
+            anyhow::bail!("Transaction ID is not set for {}", _type);
+        };
+        match (_type.introduces_tx_id(), self.amount) {
+            (true, None) => anyhow::bail!("{} {} requires an amount", _type, tx),
+            (false, Some(_)) => anyhow::bail!("{} {} must not have an amount", _type, tx),
+            _ => Ok(Transaction {
+                currency: self.currency,
+                ..Transaction::new(_type, client, tx, self.amount)
+            }),
         }
     }
 }
@@ -98,22 +589,466 @@ pub fn transactions_to_csv<'a>(transactions: impl IntoIterator<Item = &'a Transa
 pub fn transaction_entries_to_csv<'a>(
     entries: impl IntoIterator<Item = &'a TransactionEntry>,
 ) -> String {
-    let mut buf = vec!["type,client,tx,amount,status".to_string()];
-    entries.into_iter().for_each(|entry| {
-        let mut line = entry.tx.to_string();
-        line.push_str(&format!(",{}", format_status(entry.status)));
-        buf.push(line);
-    });
+    transaction_entries_to_csv_filtered(entries, |_| true)
+}
+
+/// `transaction_entries_to_csv` with only the entries matching `predicate`.
+pub fn transaction_entries_to_csv_filtered<'a>(
+    entries: impl IntoIterator<Item = &'a TransactionEntry>,
+    predicate: impl Fn(&TransactionEntry) -> bool,
+) -> String {
+    let mut buf = vec!["seq,type,client,tx,amount,status".to_string()];
+    entries
+        .into_iter()
+        .filter(|entry| predicate(entry))
+        .for_each(|entry| {
+            buf.push(format!(
+                "{},{},{}",
+                entry.seq,
+                entry.tx,
+                format_status(entry.status)
+            ));
+        });
     buf.join("\n")
 }
 
+/// Only the entries that were not applied, with a human-readable `reason` next to the
+/// machine `status` token, so the file can be handed back to the upstream system.
+pub fn rejected_entries_to_csv<'a>(
+    entries: impl IntoIterator<Item = &'a TransactionEntry>,
+) -> String {
+    let mut buf = vec!["type,client,tx,amount,status,reason".to_string()];
+    entries
+        .into_iter()
+        .filter(|entry| {
+            !matches!(
+                entry.status,
+                TransactionStatus::Applied
+                    | TransactionStatus::AppliedPartial
+                    | TransactionStatus::AutoResolved
+            )
+        })
+        .for_each(|entry| {
+            let mut line = entry.tx.to_string();
+            line.push_str(&format!(",{},", format_status(entry.status)));
+            match entry.tx.invalid_amount {
+                // names what the row said, `1e2` and a literal `NaN` fail alike
+                Some(amount) if entry.status == TransactionStatus::FailedInvalidAmount => {
+                    line.push_str(&format!("Amount {} is not a plain decimal", amount));
+                }
+                _ => line.push_str(status_reason(entry.status)),
+            }
+            buf.push(line);
+        });
+    buf.join("\n")
+}
+
+fn status_reason(status: TransactionStatus) -> &'static str {
+    match status {
+        TransactionStatus::Applied => "Transaction applied",
+        TransactionStatus::AppliedPartial => {
+            "Withdrawal exceeded the available funds, only those were withdrawn"
+        }
+        TransactionStatus::AutoResolved => "Dispute resolved by the engine, not by a resolve",
+        TransactionStatus::IgnoredLocked => "Account is locked after a chargeback",
+        TransactionStatus::IgnoredMissingReference => {
+            "Referenced transaction not found or not in a valid dispute state"
+        }
+        TransactionStatus::IgnoredNotDisputed => {
+            "Referenced transaction is not under dispute (never disputed or already settled)"
+        }
+        TransactionStatus::IgnoredOutsideWindow => {
+            "Referenced transaction is too old to be disputed"
+        }
+        TransactionStatus::IgnoredUnauthorized => "Operation is not allowed by the engine",
+        TransactionStatus::FailedInsufficientFunds => "Insufficient available funds",
+        TransactionStatus::FailedInsufficientAvailableForDispute => {
+            "Disputed funds are no longer available"
+        }
+        TransactionStatus::FailedInvalidAmount => "Amount is missing or not positive",
+        TransactionStatus::FailedAmountTooLarge => "Amount exceeds the configured maximum",
+        TransactionStatus::FailedCurrencyMismatch => "Currency differs from the account's currency",
+        TransactionStatus::FailedDuplicateTxID => "Transaction ID was already used",
+    }
+}
+
 fn format_status(status: TransactionStatus) -> &'static str {
     match status {
         TransactionStatus::Applied => "applied",
+        TransactionStatus::AppliedPartial => "applied_partial",
+        TransactionStatus::AutoResolved => "auto_resolved",
         TransactionStatus::IgnoredLocked => "ignored_locked",
         TransactionStatus::IgnoredMissingReference => "ignored_missing_reference",
+        TransactionStatus::IgnoredNotDisputed => "ignored_not_disputed",
+        TransactionStatus::IgnoredOutsideWindow => "ignored_outside_window",
+        TransactionStatus::IgnoredUnauthorized => "ignored_unauthorized",
         TransactionStatus::FailedInsufficientFunds => "failed_insufficient_funds",
+        TransactionStatus::FailedInsufficientAvailableForDispute => {
+            "failed_insufficient_available_for_dispute"
+        }
         TransactionStatus::FailedInvalidAmount => "failed_invalid_amount",
+        TransactionStatus::FailedAmountTooLarge => "failed_amount_too_large",
+        TransactionStatus::FailedCurrencyMismatch => "failed_currency_mismatch",
         TransactionStatus::FailedDuplicateTxID => "failed_duplicate_tx_id",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(
+        _type: TransactionType,
+        tx: u32,
+        amount: Option<f64>,
+        status: TransactionStatus,
+    ) -> TransactionEntry {
+        TransactionEntry {
+            seq: u64::from(tx),
+            tx: Transaction::new(_type, 1, tx, amount),
+            status,
+        }
+    }
+
+    #[test]
+    fn rejected_entries_name_amounts_that_are_not_plain_decimals() {
+        let entries: Vec<TransactionEntry> = ["1e2", "NaN"]
+            .into_iter()
+            .enumerate()
+            .map(|(tx, amount)| TransactionEntry {
+                seq: tx as u64,
+                tx: parse(amount).unwrap(),
+                status: TransactionStatus::FailedInvalidAmount,
+            })
+            .collect();
+
+        let csv = rejected_entries_to_csv(&entries);
+        let lines: Vec<&str> = csv.lines().skip(1).collect();
+        assert_eq!(
+            lines,
+            [
+                "deposit,1,1,1e2,failed_invalid_amount,Amount 1e2 is not a plain decimal",
+                "deposit,1,1,NaN,failed_invalid_amount,Amount NaN is not a plain decimal",
+            ]
+        );
+        // and they read back as written
+        let log = transaction_entries_to_csv(&entries);
+        let read: Vec<TransactionEntry> = csv::Reader::from_reader(log.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, entries);
+    }
+
+    #[test]
+    fn rejected_entries_to_csv_skips_applied_entries() {
+        let entries = [
+            entry(
+                TransactionType::Deposit,
+                1,
+                Some(10.0),
+                TransactionStatus::Applied,
+            ),
+            entry(
+                TransactionType::Withdrawal,
+                2,
+                Some(20.0),
+                TransactionStatus::FailedInsufficientFunds,
+            ),
+            entry(
+                TransactionType::Dispute,
+                9,
+                None,
+                TransactionStatus::IgnoredMissingReference,
+            ),
+        ];
+
+        let csv = rejected_entries_to_csv(entries.iter());
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "type,client,tx,amount,status,reason");
+        assert_eq!(
+            lines[1],
+            "withdrawal,1,2,20.0000,failed_insufficient_funds,Insufficient available funds"
+        );
+        assert!(lines[2].starts_with("dispute,1,9,,ignored_missing_reference,"));
+    }
+
+    #[test]
+    fn filtered_log_keeps_matching_entries() {
+        let entries = [
+            entry(
+                TransactionType::Deposit,
+                1,
+                Some(10.0),
+                TransactionStatus::Applied,
+            ),
+            entry(
+                TransactionType::Dispute,
+                1,
+                None,
+                TransactionStatus::Applied,
+            ),
+            entry(
+                TransactionType::Chargeback,
+                1,
+                None,
+                TransactionStatus::Applied,
+            ),
+            entry(
+                TransactionType::Deposit,
+                2,
+                Some(5.0),
+                TransactionStatus::IgnoredLocked,
+            ),
+            entry(
+                TransactionType::Chargeback,
+                3,
+                None,
+                TransactionStatus::IgnoredMissingReference,
+            ),
+        ];
+
+        let csv = transaction_entries_to_csv_filtered(entries.iter(), |entry| {
+            entry.tx._type == TransactionType::Chargeback
+        });
+        assert_eq!(
+            csv,
+            "seq,type,client,tx,amount,status\n\
+             1,chargeback,1,1,,applied\n\
+             3,chargeback,1,3,,ignored_missing_reference"
+        );
+        assert_eq!(
+            transaction_entries_to_csv_filtered(entries.iter(), |_| true),
+            transaction_entries_to_csv(entries.iter())
+        );
+    }
+
+    #[test]
+    fn transaction_type_parses_column_names() {
+        assert_eq!(
+            "chargeback".parse::<TransactionType>().unwrap(),
+            TransactionType::Chargeback
+        );
+        for _type in [TransactionType::Withdrawal, TransactionType::Unlock] {
+            assert_eq!(_type.to_string().parse::<TransactionType>().unwrap(), _type);
+        }
+        assert!("refund".parse::<TransactionType>().is_err());
+    }
+
+    #[test]
+    fn transaction_type_ignores_case() {
+        for name in ["deposit", "Deposit", "DEPOSIT", "dEpOsIt"] {
+            assert_eq!(
+                TransactionType::from_str_ci(name).unwrap(),
+                TransactionType::Deposit
+            );
+        }
+        let err = TransactionType::from_str_ci("Refund").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown transaction type: Refund");
+        // `from_str` stays exact
+        assert!("Deposit".parse::<TransactionType>().is_err());
+
+        let rows = "type,client,tx,amount\nDeposit,1,1,10.0\nWITHDRAWAL,1,2,1.0\n\
+                    Dispute,1,1,\nReSolve,1,1,\nCHARGEBACK,1,1,\nUnlock,1,1,\n";
+        let types: Vec<TransactionType> = csv::Reader::from_reader(rows.as_bytes())
+            .deserialize::<Transaction>()
+            .map(|tx| tx.unwrap()._type)
+            .collect();
+        assert_eq!(
+            types,
+            [
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute,
+                TransactionType::Resolve,
+                TransactionType::Chargeback,
+                TransactionType::Unlock,
+            ]
+        );
+        let err = csv::Reader::from_reader("type,client,tx,amount\nRefund,1,1,1.0\n".as_bytes())
+            .deserialize::<Transaction>()
+            .next()
+            .unwrap()
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("Unknown transaction type: Refund"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn unlock_type_deserializes_from_lowercase() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader("unlock,5,10,".as_bytes());
+        let tx: Transaction = reader.deserialize().next().unwrap().unwrap();
+        assert_eq!(tx, Transaction::new(TransactionType::Unlock, 5, 10, None));
+        assert_eq!(tx.to_string(), "unlock,5,10,");
+    }
+
+    #[test]
+    fn builder_builds_transactions() {
+        let deposit = Transaction::builder()
+            .deposit()
+            .client(1)
+            .tx(7)
+            .amount(100.0)
+            .build()
+            .unwrap();
+        assert_eq!(
+            deposit,
+            Transaction::new(TransactionType::Deposit, 1, 7, Some(100.0))
+        );
+
+        let dispute = Transaction::builder().dispute().client(1).tx(7).build();
+        assert_eq!(
+            dispute.unwrap(),
+            Transaction::new(TransactionType::Dispute, 1, 7, None)
+        );
+    }
+
+    #[test]
+    fn builder_rejects_mismatched_amounts_and_missing_fields() {
+        let builder = Transaction::builder().client(1).tx(7);
+        assert!(builder.withdrawal().build().is_err());
+        assert!(builder.resolve().amount(1.0).build().is_err());
+        assert!(builder.chargeback().amount(1.0).build().is_err());
+        assert!(builder.amount(1.0).build().is_err());
+        assert!(
+            Transaction::builder()
+                .deposit()
+                .tx(7)
+                .amount(1.0)
+                .build()
+                .is_err()
+        );
+        assert!(
+            Transaction::builder()
+                .deposit()
+                .client(1)
+                .amount(1.0)
+                .build()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn currency_column_is_optional() {
+        let with_currency = "type,client,tx,amount,currency\ndeposit,1,1,5.0,usd\ndispute,1,1,,\n";
+        let rows: Vec<Transaction> = csv::Reader::from_reader(with_currency.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows[0].currency, Some("USD".parse().unwrap()));
+        assert_eq!(rows[1].currency, None);
+
+        let without = "type,client,tx,amount\ndeposit,1,1,5.0\n";
+        let rows: Vec<Transaction> = csv::Reader::from_reader(without.as_bytes())
+            .deserialize()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(rows[0].currency, None);
+        assert_eq!(rows[0].to_string(), "deposit,1,1,5.0000");
+
+        assert!("US".parse::<Currency>().is_err());
+        assert!("U$D".parse::<Currency>().is_err());
+    }
+
+    fn parse(amount: &str) -> Result<Transaction, csv::Error> {
+        let row = format!("type,client,tx,amount\ndeposit,1,1,{amount}\n");
+        csv::Reader::from_reader(row.as_bytes())
+            .deserialize()
+            .next()
+            .unwrap()
+    }
+
+    #[test]
+    fn amounts_must_be_plain_decimals() {
+        assert_eq!(parse("100").unwrap().amount, Some(100.0));
+        assert_eq!(parse("0.5").unwrap().amount, Some(0.5));
+        assert_eq!(parse(".5").unwrap().amount, Some(0.5));
+        // parsed, then rejected by the engine as non-positive (`FailedInvalidAmount`)
+        assert_eq!(parse("0.00").unwrap().amount, Some(0.0));
+        assert!(parse("-0.0").unwrap().amount.unwrap().is_sign_negative());
+
+        // numbers, just not written as plain decimals: kept as written for the engine to fail
+        for invalid in ["1e2", "1E-3", "inf", "-inf", "NaN"] {
+            let tx = parse(invalid).unwrap();
+            assert_eq!(tx.amount, None, "{invalid}");
+            assert_eq!(tx.invalid_amount.unwrap().to_string(), invalid);
+            assert_eq!(tx.to_string(), format!("deposit,1,1,{invalid}"));
+        }
+        // text past what's kept inline is cut
+        let long = parse("1.000000000000000e2").unwrap();
+        assert_eq!(long.invalid_amount.unwrap().to_string(), "1.000000000000…");
+        for rejected in ["0x10", "1.2.3", ".", "-", "abc"] {
+            let err = parse(rejected).unwrap_err();
+            assert!(
+                err.to_string().contains("invalid amount"),
+                "{rejected}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn validate_checks_the_amount_against_the_type() {
+        for _type in [TransactionType::Deposit, TransactionType::Withdrawal] {
+            assert_eq!(
+                Transaction::new(_type, 1, 7, Some(0.0001)).validate(),
+                Ok(())
+            );
+            assert_eq!(
+                Transaction::new(_type, 1, 7, None).validate(),
+                Err(ValidationError::MissingAmount { _type, tx: 7 })
+            );
+            for amount in [0.0, -1.5, f64::INFINITY, f64::NEG_INFINITY] {
+                assert_eq!(
+                    Transaction::new(_type, 1, 7, Some(amount)).validate(),
+                    Err(ValidationError::InvalidAmount {
+                        _type,
+                        tx: 7,
+                        amount
+                    })
+                );
+            }
+            assert!(matches!(
+                Transaction::new(_type, 1, 7, Some(f64::NAN)).validate(),
+                Err(ValidationError::InvalidAmount { amount, .. }) if amount.is_nan()
+            ));
+            let not_plain = Transaction {
+                invalid_amount: Some("1e2".into()),
+                ..Transaction::new(_type, 1, 7, None)
+            };
+            assert_eq!(
+                not_plain.validate(),
+                Err(ValidationError::NotPlainAmount {
+                    _type,
+                    tx: 7,
+                    amount: "1e2".into()
+                })
+            );
+        }
+
+        for _type in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+            TransactionType::Unlock,
+        ] {
+            assert_eq!(Transaction::new(_type, 1, 7, None).validate(), Ok(()));
+            assert_eq!(
+                Transaction::new(_type, 1, 7, Some(5.0)).validate(),
+                Err(ValidationError::UnexpectedAmount { _type, tx: 7 })
+            );
+        }
+
+        let err = Transaction::new(TransactionType::Withdrawal, 1, 7, Some(-2.0))
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "withdrawal 7 has an invalid amount -2, it must be positive and finite"
+        );
+    }
+}
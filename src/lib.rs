@@ -1,2 +1,101 @@
 pub mod ledger;
 pub mod processing;
+
+use ledger::{engine::Engine, transaction::Transaction};
+use processing::CsvOptions;
+
+/// Processes `transactions` in order on a default engine, without going through CSV.
+///
+/// ```
+/// use trex::ledger::transaction::Transaction;
+///
+/// let engine = trex::run([
+///     Transaction::builder().deposit().client(1).tx(1).amount(10.0).build().unwrap(),
+///     Transaction::builder().withdrawal().client(1).tx(2).amount(4.0).build().unwrap(),
+/// ]);
+/// assert_eq!(engine.get_account(1).unwrap().available(), 6.0);
+/// ```
+///
+/// Engine options (credit limits, dispute windows...) go through `Engine::new()`'s builders
+/// and `Engine::process` instead.
+pub fn run(transactions: impl IntoIterator<Item = Transaction>) -> Engine {
+    let mut engine = Engine::new();
+    transactions.into_iter().for_each(|tx| engine.process(tx));
+    engine
+}
+
+/// Parses one CSV record without a header, columns by position like `--no-header` input:
+/// `type,client,tx,amount[,currency]`. Rows are read exactly as in files (whitespace trimmed,
+/// types case-insensitive, decimal IDs).
+///
+/// ```
+/// use trex::ledger::transaction::{Transaction, TransactionType};
+///
+/// let tx = trex::parse_transaction_line("deposit, 1, 7, 2.5").unwrap();
+/// assert_eq!(tx, Transaction::new(TransactionType::Deposit, 1, 7, Some(2.5)));
+/// assert!(trex::parse_transaction_line("deposit, 1, x, 2.5").is_err());
+/// ```
+pub fn parse_transaction_line(line: &str) -> anyhow::Result<Transaction> {
+    let options = CsvOptions {
+        has_header: false,
+        ..CsvOptions::default()
+    };
+    let mut reader = options.reader(line.as_bytes());
+    let mut rows = options.transactions(&mut reader);
+    let Some(tx) = rows.next() else {
+        anyhow::bail!("Empty transaction line");
+    };
+    if rows.next().is_some() {
+        anyhow::bail!("Expected a single CSV record, found several in {:?}", line);
+    }
+    Ok(tx?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ledger::transaction::{TransactionStatus, TransactionType};
+
+    #[test]
+    fn run_processes_transactions_in_order() {
+        let engine = run([
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(10.0)),
+            Transaction::new(TransactionType::Deposit, 2, 2, Some(5.0)),
+            Transaction::new(TransactionType::Dispute, 1, 1, None),
+            Transaction::new(TransactionType::Withdrawal, 2, 3, Some(7.0)),
+            Transaction::new(TransactionType::Chargeback, 1, 1, None),
+        ]);
+
+        let first = engine.get_account(1).unwrap();
+        assert_eq!(first.total(), 0.0);
+        assert!(first.is_locked());
+        let second = engine.get_account(2).unwrap();
+        assert_eq!(second.available(), 5.0);
+        assert_eq!(
+            engine.transactions_for(2).last().unwrap().status,
+            TransactionStatus::FailedInsufficientFunds
+        );
+    }
+
+    #[test]
+    fn parse_transaction_line_reads_one_record() {
+        assert_eq!(
+            parse_transaction_line("Withdrawal,2,5,1.25").unwrap(),
+            Transaction::new(TransactionType::Withdrawal, 2, 5, Some(1.25))
+        );
+        assert_eq!(
+            parse_transaction_line(" dispute , 2 , 5 ,\n").unwrap(),
+            Transaction::new(TransactionType::Dispute, 2, 5, None)
+        );
+
+        let err = parse_transaction_line("refund,2,5,1.0").unwrap_err();
+        assert!(
+            err.to_string().contains("Unknown transaction type: refund"),
+            "{err}"
+        );
+        assert!(parse_transaction_line("deposit,2,5,abc").is_err());
+        assert!(parse_transaction_line("deposit,2").is_err());
+        assert!(parse_transaction_line("").is_err());
+        assert!(parse_transaction_line("deposit,1,1,1.0\ndeposit,1,2,1.0").is_err());
+    }
+}
@@ -1,31 +1,118 @@
-use std::env;
-use tokio::sync::mpsc;
+use std::{
+    env,
+    io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    process::ExitCode,
+};
 use trex::{
-    ledger::{account::accounts_to_csv, engine::Engine, transaction::transaction_entries_to_csv},
-    processing::{consumer::TransactionConsumer, producer::TransactionProducer},
+    ledger::{
+        RoundingMode,
+        account::{
+            Column, DEFAULT_PRECISION, ExtraColumn, Quoting, SortOrder, accounts_to_json_sorted,
+            write_accounts_csv_rows, write_accounts_csv_sorted,
+        },
+        engine::{Engine, EngineSnapshot, account_diffs_to_csv},
+        transaction::{
+            TransactionType, rejected_entries_to_csv, transaction_entries_to_csv,
+            transaction_entries_to_csv_filtered,
+        },
+    },
+    processing::{
+        ClientFilter, CsvOptions, IdFormat,
+        consumer::{TransactionConsumer, validate_file, validate_reader},
+        error::{ConsumeError, ProducerError},
+        producer::{DEFAULT_CHANNEL_CAPACITY, TransactionProducer, csv_files},
+    },
 };
 
-async fn run_engine(input: &str, mode: ProcessingMode) -> anyhow::Result<Engine> {
-    let (tx, rx) = mpsc::channel(100);
-    let consumer = TransactionConsumer::new(rx, Engine::new());
-    let mut producer = TransactionProducer::new(tx);
+async fn run_engine(cli: &CliArgs) -> anyhow::Result<Engine> {
+    let (producer, rx) = if cli.unbounded {
+        TransactionProducer::unbounded()
+    } else {
+        TransactionProducer::with_capacity(cli.channel_capacity)
+    };
+    // a single missing file fails before the consumer starts, unless missing files are skipped
+    let mut producer = producer
+        .with_path_check(cli.processing_mode() == ProcessingMode::SingleFile && !cli.skip_missing);
+    let mut engine = Engine::new()
+        .with_skip_processed_files(cli.skip_processed_files)
+        .with_locked_accounts(cli.seed_locked.iter().copied());
+    if let Some(mode) = cli.rounding {
+        engine = engine.with_rounding(mode);
+    }
+    let consumer = TransactionConsumer::new(rx, engine)
+        .with_shards(cli.shards)
+        .with_strict(!cli.lenient)
+        .with_csv_options(cli.csv_options)
+        .with_concurrency(cli.concurrency)
+        .with_client_filter(cli.clients.clone())
+        .with_skip_missing(cli.skip_missing);
+    let consumer = if cli.progress {
+        consumer
+            .with_progress(PROGRESS_EVERY, |count| {
+                eprintln!("processed {} records", count)
+            })
+            .with_client_count(PROGRESS_EVERY, |count| {
+                eprintln!("{} clients so far", count)
+            })
+    } else {
+        consumer
+    };
+    let consumer = if cli.graceful {
+        consumer.with_shutdown(async {
+            // without a handler, Ctrl-C keeps its default behavior
+            if tokio::signal::ctrl_c().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        })
+    } else {
+        consumer
+    };
+    // consume concurrently: a large directory would otherwise fill the bounded channel
+    let consumer = tokio::spawn(consumer.consume_with_reports());
 
-    match mode {
-        ProcessingMode::SingleFile => {
-            let path = input;
-            producer.produce(path.to_string()).await?;
-        }
-        ProcessingMode::MultiFile => {
-            let paths = input.split(',').collect::<Vec<&str>>();
-            for path in paths {
+    let produced = async {
+        for path in cli.paths() {
+            if path == STDIN_PATH {
+                producer.produce_stdin().await?;
+            } else if std::path::Path::new(path).is_dir() {
+                producer
+                    .produce_dir(std::path::Path::new(path), cli.recursive)
+                    .await?;
+            } else {
                 producer.produce(path.to_string()).await?;
             }
         }
+        anyhow::Ok(())
     }
+    .await;
     drop(producer);
-    consumer.consume().await
+    // a consumer failure explains a `ConsumerGone` on the producer side, report it first
+    let (engine, metrics, reports) = consumer.await??;
+    match produced {
+        // the consumer stopped on Ctrl-C, the rest of the input is left unprocessed
+        Err(e)
+            if cli.graceful
+                && matches!(
+                    e.downcast_ref::<ProducerError>(),
+                    Some(ProducerError::ConsumerGone)
+                ) => {}
+        produced => produced?,
+    }
+    if cli.timing {
+        eprintln!("{}", metrics);
+    }
+    if cli.file_report {
+        reports.iter().for_each(|report| eprintln!("{}", report));
+    }
+    Ok(engine)
 }
 
+// records between two `--progress` lines
+const PROGRESS_EVERY: u64 = 100_000;
+
+// reads CSV from stdin instead of a file
+const STDIN_PATH: &str = "-";
+
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 enum ProcessingMode {
     #[default]
@@ -33,33 +120,624 @@ enum ProcessingMode {
     MultiFile,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let args: Vec<String> = env::args().collect();
-    if args.len() < 2 || args.len() > 3 {
-        eprintln!(
-            "Usage: {} <transactions.csv[,file2.csv,...]> [--log]",
-            args[0]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CliArgs {
+    input: String,
+    print_log: bool,
+    format: OutputFormat,
+    errors_path: Option<String>,
+    shards: usize,
+    lenient: bool,
+    print_stats: bool,
+    report_dust: bool,
+    csv_options: CsvOptions,
+    channel_capacity: usize,
+    unbounded: bool,
+    graceful: bool,
+    validate_only: bool,
+    output: Option<String>,
+    append: bool,
+    verbose: bool,
+    skip_processed_files: bool,
+    precision: usize,
+    verify: bool,
+    prove: bool,
+    concurrency: usize,
+    seed_locked: Vec<u16>,
+    rounding: Option<RoundingMode>,
+    dry_run: bool,
+    with_net: bool,
+    with_volumes: bool,
+    columns: Option<Vec<Column>>,
+    sort: SortOrder,
+    quoting: Quoting,
+    recursive: bool,
+    progress: bool,
+    summary_json: bool,
+    log_types: Option<Vec<TransactionType>>,
+    clients: ClientFilter,
+    timing: bool,
+    file_report: bool,
+    diff_path: Option<String>,
+    trace_tx: Option<u32>,
+    held_breakdown: Option<u16>,
+    skip_missing: bool,
+}
+
+impl Default for CliArgs {
+    fn default() -> Self {
+        Self {
+            input: String::new(),
+            print_log: false,
+            format: OutputFormat::default(),
+            errors_path: None,
+            shards: 1,
+            lenient: false,
+            print_stats: false,
+            report_dust: false,
+            csv_options: CsvOptions::default(),
+            channel_capacity: DEFAULT_CHANNEL_CAPACITY,
+            unbounded: false,
+            graceful: false,
+            validate_only: false,
+            output: None,
+            append: false,
+            verbose: false,
+            skip_processed_files: false,
+            precision: DEFAULT_PRECISION,
+            verify: false,
+            prove: false,
+            concurrency: 1,
+            seed_locked: Vec::new(),
+            rounding: None,
+            dry_run: false,
+            with_net: false,
+            with_volumes: false,
+            columns: None,
+            sort: SortOrder::default(),
+            quoting: Quoting::default(),
+            recursive: false,
+            progress: false,
+            summary_json: false,
+            log_types: None,
+            clients: ClientFilter::default(),
+            timing: false,
+            file_report: false,
+            diff_path: None,
+            trace_tx: None,
+            held_breakdown: None,
+            skip_missing: false,
+        }
+    }
+}
+
+impl CliArgs {
+    fn processing_mode(&self) -> ProcessingMode {
+        if self.input.contains(",") {
+            ProcessingMode::MultiFile
+        } else {
+            ProcessingMode::default()
+        }
+    }
+
+    fn paths(&self) -> Vec<&str> {
+        match self.processing_mode() {
+            ProcessingMode::SingleFile => vec![self.input.as_str()],
+            ProcessingMode::MultiFile => self.input.split(',').collect(),
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> anyhow::Result<CliArgs> {
+    let mut cli = CliArgs::default();
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--log" => cli.print_log = true,
+            "--lenient" => cli.lenient = true,
+            "--stats" => cli.print_stats = true,
+            "--report-dust" => cli.report_dust = true,
+            "--unbounded" => cli.unbounded = true,
+            "--graceful" => cli.graceful = true,
+            "--validate-only" => cli.validate_only = true,
+            "--verbose" => cli.verbose = true,
+            "--skip-processed-files" => cli.skip_processed_files = true,
+            "--verify" => cli.verify = true,
+            "--prove" => cli.prove = true,
+            "--dry-run" => cli.dry_run = true,
+            "--with-net" => cli.with_net = true,
+            "--with-volumes" => cli.with_volumes = true,
+            "--recursive" => cli.recursive = true,
+            "--progress" => cli.progress = true,
+            "--timing" => cli.timing = true,
+            "--file-report" => cli.file_report = true,
+            "--no-header" => cli.csv_options.has_header = false,
+            "--raw-amounts" => cli.csv_options.raw_amounts = true,
+            "--skip-missing" => cli.skip_missing = true,
+            "--summary-json" => cli.summary_json = true,
+            "--format" => {
+                cli.format = match iter.next().map(String::as_str) {
+                    Some("csv") => OutputFormat::Csv,
+                    Some("json") => OutputFormat::Json,
+                    Some(other) => anyhow::bail!("Unknown output format: {}", other),
+                    None => anyhow::bail!("--format requires a value (csv|json)"),
+                }
+            }
+            "--id-format" => {
+                cli.csv_options.id_format = match iter.next().map(String::as_str) {
+                    Some("dec") => IdFormat::Dec,
+                    Some("hex") => IdFormat::Hex,
+                    _ => anyhow::bail!("--id-format requires a format (dec|hex)"),
+                }
+            }
+            "--rounding" => {
+                cli.rounding = match iter.next().map(String::as_str) {
+                    Some("half-up") => Some(RoundingMode::HalfUp),
+                    Some("half-even") => Some(RoundingMode::HalfEven),
+                    Some("truncate") => Some(RoundingMode::Truncate),
+                    _ => anyhow::bail!("--rounding requires a mode (half-up|half-even|truncate)"),
+                }
+            }
+            "--diff" => {
+                let Some(path) = iter.next() else {
+                    anyhow::bail!("--diff requires a snapshot file path");
+                };
+                cli.diff_path = Some(path.to_string());
+            }
+            "--trace-tx" => {
+                cli.trace_tx = match iter.next().map(|id| id.parse::<u32>()) {
+                    Some(Ok(id)) => Some(id),
+                    _ => anyhow::bail!("--trace-tx requires a transaction ID"),
+                }
+            }
+            "--held-breakdown" => {
+                cli.held_breakdown = match iter.next().map(|id| id.parse::<u16>()) {
+                    Some(Ok(id)) => Some(id),
+                    _ => anyhow::bail!("--held-breakdown requires a client ID"),
+                }
+            }
+            "--output" => {
+                let Some(path) = iter.next() else {
+                    anyhow::bail!("--output requires a file path");
+                };
+                cli.output = Some(path.to_string());
+            }
+            "--append" => cli.append = true,
+            "--errors" => {
+                let Some(path) = iter.next() else {
+                    anyhow::bail!("--errors requires a file path");
+                };
+                cli.errors_path = Some(path.to_string());
+            }
+            "--delimiter" => {
+                cli.csv_options.delimiter = match iter.next().map(String::as_str) {
+                    Some("\\t" | "tab") => b'\t',
+                    Some(d) if d.len() == 1 && d.is_ascii() => d.as_bytes()[0],
+                    _ => anyhow::bail!("--delimiter requires a single ASCII character"),
+                }
+            }
+            "--comment-char" => {
+                cli.csv_options.comment = match iter.next().map(String::as_str) {
+                    Some("none") => None,
+                    Some(c) if c.len() == 1 && c.is_ascii() => Some(c.as_bytes()[0]),
+                    _ => anyhow::bail!("--comment-char requires a single ASCII character, or none"),
+                }
+            }
+            "--channel-capacity" => {
+                cli.channel_capacity = match iter.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => anyhow::bail!("--channel-capacity requires a positive number"),
+                }
+            }
+            "--precision" => {
+                cli.precision = match iter.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) => n,
+                    _ => anyhow::bail!("--precision requires a number of decimal places"),
+                }
+            }
+            "--concurrency" => {
+                cli.concurrency = match iter.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => anyhow::bail!("--concurrency requires a positive number"),
+                }
+            }
+            "--seed-locked" => cli.seed_locked = client_list(arg, iter.next())?,
+            "--only-clients" => {
+                cli.clients.only = Some(client_list(arg, iter.next())?.into_iter().collect())
+            }
+            "--exclude-clients" => {
+                cli.clients.exclude = client_list(arg, iter.next())?.into_iter().collect()
+            }
+            "--columns" => {
+                let Some(list) = iter.next() else {
+                    anyhow::bail!("--columns requires a comma separated list of column names");
+                };
+                cli.columns = Some(
+                    list.split(',')
+                        .map(|name| name.trim().parse::<Column>())
+                        .collect::<anyhow::Result<Vec<_>>>()?,
+                );
+            }
+            "--sort" => {
+                let Some(order) = iter.next() else {
+                    anyhow::bail!(
+                        "--sort requires an order (client-asc|total-desc|available-desc)"
+                    );
+                };
+                cli.sort = order.parse()?;
+            }
+            "--quoting" => {
+                let Some(quoting) = iter.next() else {
+                    anyhow::bail!("--quoting requires a style (always|necessary|never)");
+                };
+                cli.quoting = quoting.parse()?;
+            }
+            "--log-types" => {
+                let Some(list) = iter.next() else {
+                    anyhow::bail!(
+                        "--log-types requires a comma separated list of transaction types"
+                    );
+                };
+                cli.log_types = Some(
+                    list.split(',')
+                        .map(|name| name.trim().parse::<TransactionType>())
+                        .collect::<anyhow::Result<Vec<_>>>()?,
+                );
+            }
+            "--max-record-bytes" => {
+                cli.csv_options.max_record_bytes = match iter.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => anyhow::bail!("--max-record-bytes requires a positive number"),
+                }
+            }
+            "--shards" => {
+                cli.shards = match iter.next().map(|n| n.parse::<usize>()) {
+                    Some(Ok(n)) if n > 0 => n,
+                    _ => anyhow::bail!("--shards requires a positive number"),
+                }
+            }
+            flag if flag.starts_with("--") => anyhow::bail!("Unknown flag: {}", flag),
+            input if cli.input.is_empty() => cli.input = input.to_string(),
+            extra => anyhow::bail!("Unexpected argument: {}", extra),
+        }
+    }
+    if cli.input.is_empty() {
+        anyhow::bail!("Missing input file");
+    }
+    if cli.columns.is_some()
+        && (cli.verbose || cli.with_net || cli.with_volumes || cli.format != OutputFormat::Csv)
+    {
+        anyhow::bail!(
+            "--columns selects every CSV column itself, it can't be combined with --verbose, --with-net, --with-volumes or --format"
+        );
+    }
+    if cli.quoting != Quoting::default() && cli.format != OutputFormat::Csv {
+        anyhow::bail!("--quoting applies to the CSV output, it can't be combined with --format");
+    }
+    if cli.summary_json && (cli.print_log || cli.dry_run || cli.format != OutputFormat::Csv) {
+        anyhow::bail!(
+            "--summary-json replaces the account output, it can't be combined with --log, --dry-run or --format"
+        );
+    }
+    if cli.diff_path.is_some()
+        && (cli.summary_json || cli.print_log || cli.dry_run || cli.format != OutputFormat::Csv)
+    {
+        anyhow::bail!(
+            "--diff replaces the account output, it can't be combined with --summary-json, --log, --dry-run or --format"
+        );
+    }
+    if cli.trace_tx.is_some()
+        && (cli.summary_json
+            || cli.diff_path.is_some()
+            || cli.print_log
+            || cli.dry_run
+            || cli.format != OutputFormat::Csv)
+    {
+        anyhow::bail!(
+            "--trace-tx replaces the account output, it can't be combined with --summary-json, --diff, --log, --dry-run or --format"
+        );
+    }
+    if cli.held_breakdown.is_some()
+        && (cli.summary_json
+            || cli.diff_path.is_some()
+            || cli.trace_tx.is_some()
+            || cli.print_log
+            || cli.dry_run
+            || cli.format != OutputFormat::Csv)
+    {
+        anyhow::bail!(
+            "--held-breakdown replaces the account output, it can't be combined with --summary-json, --diff, --trace-tx, --log, --dry-run or --format"
         );
-        std::process::exit(1);
     }
+    if cli.append && cli.output.is_none() {
+        anyhow::bail!("--append requires --output");
+    }
+    if cli.append
+        && (cli.summary_json
+            || cli.diff_path.is_some()
+            || cli.trace_tx.is_some()
+            || cli.held_breakdown.is_some()
+            || cli.print_log
+            || cli.dry_run
+            || cli.format != OutputFormat::Csv)
+    {
+        anyhow::bail!(
+            "--append adds account rows to a CSV file, it can't be combined with --summary-json, --diff, --trace-tx, --held-breakdown, --log, --dry-run or --format"
+        );
+    }
+    Ok(cli)
+}
 
-    let print_log = args.get(2).map(|s| s == "--log").unwrap_or(false);
+fn client_list(flag: &str, list: Option<&String>) -> anyhow::Result<Vec<u16>> {
+    let clients = list.map(|list| {
+        list.split(',')
+            .map(|client| client.trim().parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()
+    });
+    match clients {
+        Some(Ok(clients)) => Ok(clients),
+        _ => anyhow::bail!("{} requires a comma separated list of client IDs", flag),
+    }
+}
 
-    let processing_mode = if args[1].contains(",") {
-        ProcessingMode::MultiFile
-    } else {
-        ProcessingMode::default()
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
+    let args: Vec<String> = env::args().collect();
+    let cli = match parse_args(&args) {
+        Ok(cli) => cli,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!(
+                "Usage: {} <transactions.csv[,file2.csv,...]|-> [--log] [--format csv|json] [--errors <path>] [--shards <n>] [--lenient] [--stats] [--delimiter <char>] [--comment-char <char>|none] [--channel-capacity <n>] [--verbose] [--skip-processed-files] [--precision <n>] [--verify] [--prove] [--concurrency <n>] [--seed-locked <client,...>] [--rounding half-up|half-even|truncate] [--dry-run] [--with-net] [--with-volumes] [--columns <column,...>] [--sort client-asc|total-desc|available-desc] [--quoting always|necessary|never] [--recursive] [--progress] [--summary-json] [--log-types <type,...>] [--only-clients <client,...>] [--exclude-clients <client,...>] [--timing] [--file-report] [--id-format dec|hex] [--diff <snapshot.json>] [--skip-missing] [--no-header] [--report-dust] [--unbounded] [--trace-tx <id>] [--held-breakdown <client>] [--raw-amounts] [--max-record-bytes <n>] [--graceful] [--validate-only] [--output <path> [--append]]",
+                args[0]
+            );
+            std::process::exit(1);
+        }
+    };
+
+    if cli.validate_only {
+        let malformed = match validate(&cli) {
+            Ok(malformed) => malformed,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                return Ok(Outcome::Fatal.exit_code());
+            }
+        };
+        malformed.iter().for_each(|e| eprintln!("{}", e));
+        return Ok(match malformed.len() {
+            0 => Outcome::Clean,
+            _ => Outcome::Fatal,
+        }
+        .exit_code());
+    }
+
+    let result = run_engine(&cli).await;
+    let outcome = Outcome::of(&result);
+    let engine = match result {
+        Ok(engine) => engine,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            return Ok(outcome.exit_code());
+        }
     };
+    if cli.verify {
+        engine.verify_invariants()?;
+    }
+    if cli.print_stats || cli.dry_run {
+        eprintln!("{}", engine.stats());
+    }
+    if cli.prove {
+        eprintln!("{}", engine.ledger_proof());
+    }
+    if cli.report_dust {
+        let dust: Vec<String> = engine
+            .dust_accounts()
+            .iter()
+            .map(|client| client.to_string())
+            .collect();
+        eprintln!("dust accounts: {}", dust.join(","));
+    }
+    if let Some(path) = cli.errors_path.as_ref().filter(|_| !cli.dry_run) {
+        std::fs::write(
+            path,
+            rejected_entries_to_csv(engine.get_transactions().iter()),
+        )?;
+    }
+    match &cli.output {
+        Some(path) => write_output(&engine, &cli, path)?,
+        None => {
+            let mut stdout = BufWriter::new(std::io::stdout().lock());
+            render(&engine, &cli, &mut stdout, true)?;
+            writeln!(stdout)?;
+            stdout.flush()?;
+        }
+    }
+    Ok(outcome.exit_code())
+}
 
-    let engine = run_engine(&args[1], processing_mode).await?;
-    if print_log {
-        println!(
+// `--output`: what would go to stdout goes to `path`, replacing it. With `--append` the
+// account rows are added after what it holds, under a header only when it's new or empty;
+// a file starting with another header (different `--columns`, `--with-net`...) is refused,
+// and one whose last line isn't terminated gets its newline before the rows
+fn write_output(engine: &Engine, cli: &CliArgs, path: &str) -> anyhow::Result<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(cli.append)
+        .truncate(!cli.append)
+        .open(path)?;
+    let header = !cli.append || file.metadata()?.len() == 0;
+    let mut terminate_last_line = false;
+    if !header {
+        let mut existing_file = std::fs::File::open(path)?;
+        existing_file.seek(SeekFrom::End(-1))?;
+        let mut last = [0];
+        existing_file.read_exact(&mut last)?;
+        terminate_last_line = last != *b"\n";
+        existing_file.rewind()?;
+        let mut existing = String::new();
+        BufReader::new(existing_file).read_line(&mut existing)?;
+        let mut expected = Vec::new();
+        render(&Engine::new(), cli, &mut expected, true)?;
+        let expected = String::from_utf8(expected)?;
+        if existing.trim_end() != expected.trim_end() {
+            anyhow::bail!(
+                "--append: {} starts with header `{}`, this run writes `{}`",
+                path,
+                existing.trim_end(),
+                expected.trim_end()
+            );
+        }
+    }
+    let mut out = BufWriter::new(file);
+    if terminate_last_line {
+        writeln!(out)?;
+    }
+    render(engine, cli, &mut out, header)?;
+    // rows written without the header already end with a newline
+    if header {
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+// parses every input row without running the engine, returning the malformed ones; an
+// unreadable input or bad header stops the check
+fn validate(cli: &CliArgs) -> anyhow::Result<Vec<ConsumeError>> {
+    let mut malformed = Vec::new();
+    for path in cli.paths() {
+        if path == STDIN_PATH {
+            malformed.extend(validate_reader(&cli.csv_options, std::io::stdin().lock())?);
+        } else if std::path::Path::new(path).is_dir() {
+            for file in csv_files(std::path::Path::new(path), cli.recursive)? {
+                malformed.extend(validate_file(&cli.csv_options, &file)?);
+            }
+        } else {
+            malformed.extend(validate_file(&cli.csv_options, std::path::Path::new(path))?);
+        }
+    }
+    Ok(malformed)
+}
+
+/// How the run went, for shell callers. Usage errors exit with 1 before processing starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    // every transaction was applied
+    Clean,
+    // some transactions were ignored or failed, see `--stats` / `--errors`
+    Rejected,
+    // processing stopped: unreadable input, malformed CSV (strict mode)...
+    Fatal,
+}
+
+impl Outcome {
+    fn of(result: &anyhow::Result<Engine>) -> Self {
+        match result {
+            Ok(engine) if engine.stats().rejected() == 0 => Self::Clean,
+            Ok(_) => Self::Rejected,
+            Err(_) => Self::Fatal,
+        }
+    }
+
+    fn exit_code(self) -> ExitCode {
+        match self {
+            Self::Clean => ExitCode::SUCCESS,
+            Self::Rejected => ExitCode::from(2),
+            Self::Fatal => ExitCode::from(3),
+        }
+    }
+}
+
+// what goes to stdout: the transaction log (`--log`, `--dry-run`), the `--summary-json` totals
+// or the account statuses. Account CSV is streamed row by row, it can be large; without
+// `header` only its rows are written, see `write_output`
+fn render(
+    engine: &Engine,
+    cli: &CliArgs,
+    out: &mut impl Write,
+    header: bool,
+) -> anyhow::Result<()> {
+    if cli.print_log || cli.dry_run {
+        let entries = engine.get_transactions().iter();
+        let log = match &cli.log_types {
+            Some(types) => transaction_entries_to_csv_filtered(entries, |entry| {
+                types.contains(&entry.tx._type)
+            }),
+            None => transaction_entries_to_csv(entries),
+        };
+        return Ok(write!(out, "{}", log)?);
+    }
+    if let Some(tx_id) = cli.trace_tx {
+        let trail = transaction_entries_to_csv(engine.dispute_trail(tx_id));
+        return Ok(write!(out, "{}", trail)?);
+    }
+    if let Some(client) = cli.held_breakdown {
+        write!(out, "tx,held")?;
+        for (tx_id, held) in engine.held_breakdown(client) {
+            write!(out, "\n{},{:.p$}", tx_id, held, p = cli.precision)?;
+        }
+        return Ok(());
+    }
+    if cli.summary_json {
+        return Ok(serde_json::to_writer(out, &engine.aggregate())?);
+    }
+    if let Some(path) = &cli.diff_path {
+        let snapshot: EngineSnapshot = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        let diffs = Engine::restore(snapshot).diff(engine);
+        return Ok(write!(
+            out,
             "{}",
-            transaction_entries_to_csv(engine.get_transactions().iter())
-        );
-    } else {
-        println!("{}", accounts_to_csv(engine.get_accounts().values()));
+            account_diffs_to_csv(&diffs, cli.precision)
+        )?);
+    }
+    let accounts = engine.get_accounts().values();
+    match cli.format {
+        OutputFormat::Csv => {
+            let columns = match &cli.columns {
+                Some(columns) => columns.clone(),
+                None => Column::DEFAULT
+                    .into_iter()
+                    .chain(
+                        [
+                            (cli.verbose, ExtraColumn::DisputesOpen),
+                            (cli.with_net, ExtraColumn::NetPosition),
+                        ]
+                        .into_iter()
+                        .filter_map(|(enabled, column)| enabled.then_some(column))
+                        .chain(ExtraColumn::volumes().filter(|_| cli.with_volumes))
+                        .map(Column::Extra),
+                    )
+                    .collect(),
+            };
+            if header {
+                write_accounts_csv_sorted(
+                    out,
+                    accounts,
+                    cli.precision,
+                    &columns,
+                    cli.sort,
+                    cli.quoting,
+                )?
+            } else {
+                write_accounts_csv_rows(
+                    out,
+                    accounts,
+                    cli.precision,
+                    &columns,
+                    cli.sort,
+                    cli.quoting,
+                )?
+            }
+        }
+        OutputFormat::Json => write!(out, "{}", accounts_to_json_sorted(accounts, cli.sort)?)?,
     }
     Ok(())
 }
@@ -75,9 +753,606 @@ mod tests {
         );
     }
 
+    fn render_to_string(engine: &Engine, cli: &CliArgs) -> String {
+        let mut buf = Vec::new();
+        render(engine, cli, &mut buf, true).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    fn cli(input: &str) -> CliArgs {
+        CliArgs {
+            input: input.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        std::iter::once("trex")
+            .chain(list.iter().copied())
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn format_flag_selects_json_output() {
+        let cli = parse_args(&args(&["in.csv", "--format", "json"])).unwrap();
+        assert_eq!(cli.input, "in.csv");
+        assert_eq!(cli.format, OutputFormat::Json);
+
+        let cli = parse_args(&args(&["in.csv"])).unwrap();
+        assert_eq!(cli.format, OutputFormat::Csv);
+
+        assert!(parse_args(&args(&["in.csv", "--format", "xml"])).is_err());
+        assert!(parse_args(&args(&[])).is_err());
+    }
+
+    #[test]
+    fn errors_flag_takes_a_path() {
+        let cli = parse_args(&args(&["in.csv", "--errors", "rejected.csv"])).unwrap();
+        assert_eq!(cli.errors_path.as_deref(), Some("rejected.csv"));
+        assert!(parse_args(&args(&["in.csv", "--errors"])).is_err());
+    }
+
+    #[test]
+    fn delimiter_flag_accepts_single_chars_and_tab() {
+        let cli = parse_args(&args(&["in.csv", "--delimiter", ";"])).unwrap();
+        assert_eq!(cli.csv_options.delimiter, b';');
+        let cli = parse_args(&args(&["in.csv", "--delimiter", "tab"])).unwrap();
+        assert_eq!(cli.csv_options.delimiter, b'\t');
+        assert!(parse_args(&args(&["in.csv", "--delimiter", "::"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn unbounded_flag_processes_like_the_bounded_channel() {
+        let mut cli = cli("data/input/full_flow_large.csv");
+        let bounded = render_to_string(&run_engine(&cli).await.unwrap(), &cli);
+        cli = parse_args(&args(&["data/input/full_flow_large.csv", "--unbounded"])).unwrap();
+        assert!(cli.unbounded);
+        assert_eq!(
+            render_to_string(&run_engine(&cli).await.unwrap(), &cli),
+            bounded
+        );
+    }
+
+    #[test]
+    fn report_dust_flag_is_off_by_default() {
+        assert!(!parse_args(&args(&["in.csv"])).unwrap().report_dust);
+        assert!(
+            parse_args(&args(&["in.csv", "--report-dust"]))
+                .unwrap()
+                .report_dust
+        );
+    }
+
+    #[test]
+    fn csv_reading_flags() {
+        assert!(
+            parse_args(&args(&["in.csv"]))
+                .unwrap()
+                .csv_options
+                .has_header
+        );
+        let cli = parse_args(&args(&["in.csv", "--no-header"])).unwrap();
+        assert!(!cli.csv_options.has_header);
+        assert!(!cli.csv_options.raw_amounts);
+        let cli = parse_args(&args(&["in.csv", "--raw-amounts"])).unwrap();
+        assert!(cli.csv_options.raw_amounts);
+        assert!(
+            parse_args(&args(&["in.csv", "--file-report"]))
+                .unwrap()
+                .file_report
+        );
+        assert_eq!(
+            parse_args(&args(&["in.csv"])).unwrap().csv_options.comment,
+            Some(b'#')
+        );
+        let cli = parse_args(&args(&["in.csv", "--comment-char", ";"])).unwrap();
+        assert_eq!(cli.csv_options.comment, Some(b';'));
+        let cli = parse_args(&args(&["in.csv", "--comment-char", "none"])).unwrap();
+        assert_eq!(cli.csv_options.comment, None);
+        assert!(parse_args(&args(&["in.csv", "--comment-char", "//"])).is_err());
+        let cli = parse_args(&args(&["in.csv", "--max-record-bytes", "512"])).unwrap();
+        assert_eq!(cli.csv_options.max_record_bytes, 512);
+        assert!(parse_args(&args(&["in.csv", "--max-record-bytes", "0"])).is_err());
+    }
+
+    #[test]
+    fn summary_json_excludes_other_outputs() {
+        let cli = parse_args(&args(&["in.csv", "--summary-json"])).unwrap();
+        assert!(cli.summary_json);
+        assert!(parse_args(&args(&["in.csv", "--summary-json", "--log"])).is_err());
+        assert!(parse_args(&args(&["in.csv", "--summary-json", "--format", "json"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn trace_tx_prints_the_entries_of_one_tx_id() {
+        let cli = parse_args(&args(&[
+            "data/input/full_flow_large.csv",
+            "--trace-tx",
+            "1",
+        ]))
+        .unwrap();
+        assert_eq!(cli.trace_tx, Some(1));
+        let engine = run_engine(&cli).await.unwrap();
+        let output = render_to_string(&engine, &cli);
+        let mut rows = output.lines();
+        assert_eq!(rows.next(), Some("seq,type,client,tx,amount,status"));
+        let rows: Vec<&str> = rows.collect();
+        assert!(!rows.is_empty());
+        assert!(
+            rows.iter().all(|row| row.split(',').nth(3) == Some("1")),
+            "{output}"
+        );
+
+        assert!(parse_args(&args(&["in.csv", "--trace-tx", "x"])).is_err());
+        assert!(parse_args(&args(&["in.csv", "--trace-tx", "1", "--log"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn with_volumes_flag_appends_count_and_volume_columns() {
+        let cli = parse_args(&args(&[
+            "data/input/full_flow_large.csv",
+            "--verbose",
+            "--with-volumes",
+        ]))
+        .unwrap();
+        assert!(cli.with_volumes);
+        let engine = run_engine(&cli).await.unwrap();
+        let output = render_to_string(&engine, &cli);
+        let header: Vec<&str> = output.lines().next().unwrap().split(',').collect();
+        assert_eq!(header.len(), 6 + 2 * TransactionType::ALL.len());
+        assert_eq!(
+            header[5..8],
+            ["disputes_open", "deposit_count", "deposit_volume"]
+        );
+        assert!(
+            output
+                .lines()
+                .all(|row| row.split(',').count() == header.len())
+        );
+
+        // each row agrees with the account it was rendered from
+        let account = engine.get_account(1).unwrap();
+        let row = output.lines().find(|row| row.starts_with("1,")).unwrap();
+        let fields: Vec<&str> = row.split(',').collect();
+        assert_eq!(
+            fields[6],
+            account.count(TransactionType::Deposit).to_string()
+        );
+        assert_eq!(
+            fields[7],
+            format!("{:.4}", account.volume(TransactionType::Deposit))
+        );
+    }
+
+    #[tokio::test]
+    async fn sort_flag_orders_the_account_rows() {
+        let sorted = parse_args(&args(&[
+            "data/input/full_flow_large.csv",
+            "--sort",
+            "total-desc",
+        ]))
+        .unwrap();
+        assert_eq!(sorted.sort, SortOrder::TotalDesc);
+        let engine = run_engine(&sorted).await.unwrap();
+        let output = render_to_string(&engine, &sorted);
+        let totals: Vec<f64> = output
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').nth(3).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(totals.len(), engine.get_accounts().len());
+        assert!(totals.is_sorted_by(|a, b| a >= b), "{output}");
+
+        // client order unless asked otherwise
+        let default = render_to_string(&engine, &cli("data/input/full_flow_large.csv"));
+        let clients: Vec<u16> = default
+            .lines()
+            .skip(1)
+            .map(|row| row.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert!(clients.is_sorted(), "{default}");
+
+        let err = parse_args(&args(&["in.csv", "--sort", "total"])).unwrap_err();
+        assert!(
+            err.to_string().starts_with("Unknown sort order: total"),
+            "{err}"
+        );
+        assert!(parse_args(&args(&["in.csv", "--sort"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn quoting_flag_quotes_the_account_csv() {
+        let always = parse_args(&args(&[
+            "data/input/full_flow_large.csv",
+            "--quoting",
+            "always",
+        ]))
+        .unwrap();
+        let engine = run_engine(&always).await.unwrap();
+        let output = render_to_string(&engine, &always);
+        let mut rows = output.lines();
+        assert_eq!(
+            rows.next(),
+            Some("\"client\",\"available\",\"held\",\"total\",\"locked\"")
+        );
+        assert!(rows.all(|row| row.starts_with('"') && row.split(',').all(|f| f.ends_with('"'))));
+
+        for quoting in ["necessary", "never"] {
+            let cli = parse_args(&args(&[
+                "data/input/full_flow_large.csv",
+                "--quoting",
+                quoting,
+            ]))
+            .unwrap();
+            assert!(!render_to_string(&engine, &cli).contains('"'), "{quoting}");
+        }
+
+        assert!(parse_args(&args(&["in.csv", "--quoting", "all"])).is_err());
+        assert!(parse_args(&args(&["in.csv", "--quoting"])).is_err());
+        assert!(
+            parse_args(&args(&[
+                "in.csv",
+                "--quoting",
+                "always",
+                "--format",
+                "json"
+            ]))
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn prove_flag_balances_a_clean_fixture() {
+        let cli = parse_args(&args(&["data/input/full_flow_large.csv", "--prove"])).unwrap();
+        assert!(cli.prove);
+        let engine = run_engine(&cli).await.unwrap();
+        let proof = engine.ledger_proof();
+        assert!(proof.balanced, "{proof:?}");
+        assert_eq!(proof.to_string(), "ledger balanced: true");
+    }
+
+    #[tokio::test]
+    async fn columns_flag_selects_and_orders_the_output() {
+        let cli = parse_args(&args(&[
+            "data/input/full_flow_large.csv",
+            "--columns",
+            "client, total,locked",
+            "--precision",
+            "2",
+        ]))
+        .unwrap();
+        let engine = run_engine(&cli).await.unwrap();
+        let output = render_to_string(&engine, &cli);
+        let mut rows = output.lines();
+        assert_eq!(rows.next(), Some("client,total,locked"));
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(
+            rows.next(),
+            Some(format!("1,{:.2},{}", account.total(), account.is_locked()).as_str())
+        );
+
+        let err = parse_args(&args(&["in.csv", "--columns", "client,balance"])).unwrap_err();
+        assert!(
+            err.to_string().starts_with("Unknown column: balance"),
+            "{err}"
+        );
+        assert!(parse_args(&args(&["in.csv", "--columns"])).is_err());
+        assert!(parse_args(&args(&["in.csv", "--columns", "client", "--verbose"])).is_err());
+        assert!(
+            parse_args(&args(&[
+                "in.csv",
+                "--columns",
+                "client",
+                "--format",
+                "json"
+            ]))
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn validate_only_reports_malformed_rows_by_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,x,2.0\nwithdrawal,1,3,0.5\nrefund,1,4,1.0\n",
+        )
+        .unwrap();
+        let cli = parse_args(&args(&[path.to_str().unwrap(), "--validate-only"])).unwrap();
+        assert!(cli.validate_only);
+
+        let malformed = validate(&cli).unwrap();
+        let lines: Vec<u64> = malformed
+            .iter()
+            .map(|e| match e {
+                ConsumeError::CsvParse { line, .. } => *line,
+                other => panic!("unexpected error: {other}"),
+            })
+            .collect();
+        assert_eq!(lines, [3, 5]);
+        assert!(malformed[0].to_string().contains("at line 3"));
+
+        std::fs::write(&path, "type,client,tx,amount\ndeposit,1,1,1.0\n").unwrap();
+        assert!(validate(&cli).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+        assert!(validate(&cli).is_err());
+    }
+
+    #[tokio::test]
+    async fn held_breakdown_prints_the_open_disputes_of_a_client() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.csv");
+        std::fs::write(
+            &path,
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,2.5\ndeposit,2,3,1.0\n\
+             dispute,1,2,\ndispute,1,1,\ndispute,2,3,\n",
+        )
+        .unwrap();
+        let cli = parse_args(&args(&[
+            path.to_str().unwrap(),
+            "--held-breakdown",
+            "1",
+            "--precision",
+            "2",
+        ]))
+        .unwrap();
+        let engine = run_engine(&cli).await.unwrap();
+        assert_eq!(render_to_string(&engine, &cli), "tx,held\n1,10.00\n2,2.50");
+
+        assert!(parse_args(&args(&["in.csv", "--held-breakdown", "x"])).is_err());
+        assert!(
+            parse_args(&args(&[
+                "in.csv",
+                "--held-breakdown",
+                "1",
+                "--trace-tx",
+                "1"
+            ]))
+            .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn output_flag_writes_or_appends_to_a_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = |name: &str, rows: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, format!("type,client,tx,amount\n{rows}")).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        let first = input("first.csv", "deposit,1,1,1.5\n");
+        let second = input("second.csv", "deposit,2,2,2.0\ndeposit,3,3,3.0\n");
+        let output = dir.path().join("accounts.csv");
+        let output = output.to_str().unwrap();
+
+        // a new file gets the header
+        let cli = parse_args(&args(&[&first, "--output", output, "--append"])).unwrap();
+        write_output(&run_engine(&cli).await.unwrap(), &cli, output).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+
+        // a populated one only the rows
+        let cli = parse_args(&args(&[&second, "--output", output, "--append"])).unwrap();
+        write_output(&run_engine(&cli).await.unwrap(), &cli, output).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n\
+             2,2.0000,0.0000,2.0000,false\n3,3.0000,0.0000,3.0000,false\n"
+        );
+
+        // a last line without its newline gets one, the rows start on their own line
+        let unterminated = dir.path().join("unterminated.csv");
+        std::fs::write(
+            &unterminated,
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false",
+        )
+        .unwrap();
+        let unterminated = unterminated.to_str().unwrap();
+        let cli = parse_args(&args(&[&second, "--output", unterminated, "--append"])).unwrap();
+        write_output(&run_engine(&cli).await.unwrap(), &cli, unterminated).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(unterminated).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n\
+             2,2.0000,0.0000,2.0000,false\n3,3.0000,0.0000,3.0000,false\n"
+        );
+
+        // rows under another header are refused, the file is left as it was
+        let cli = parse_args(&args(&[
+            &second,
+            "--output",
+            output,
+            "--append",
+            "--with-net",
+        ]))
+        .unwrap();
+        let err = write_output(&run_engine(&cli).await.unwrap(), &cli, output).unwrap_err();
+        assert!(err.to_string().contains("net_position"), "{err}");
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n\
+             2,2.0000,0.0000,2.0000,false\n3,3.0000,0.0000,3.0000,false\n"
+        );
+
+        // without --append the file is replaced
+        let cli = parse_args(&args(&[&first, "--output", output])).unwrap();
+        write_output(&run_engine(&cli).await.unwrap(), &cli, output).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+
+        assert!(parse_args(&args(&["in.csv", "--append"])).is_err());
+        assert!(parse_args(&args(&["in.csv", "--output", output, "--append", "--log"])).is_err());
+    }
+
+    #[test]
+    fn log_types_flag_filters_the_log() {
+        let cli = parse_args(&args(&[
+            "in.csv",
+            "--log",
+            "--log-types",
+            "dispute, chargeback",
+        ]))
+        .unwrap();
+        assert_eq!(
+            cli.log_types,
+            Some(vec![TransactionType::Dispute, TransactionType::Chargeback])
+        );
+        let err = parse_args(&args(&["in.csv", "--log-types", "deposit,refund"])).unwrap_err();
+        assert_eq!(err.to_string(), "Unknown transaction type: refund");
+        assert!(parse_args(&args(&["in.csv", "--log-types"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn log_types_keep_only_chargebacks() {
+        let cli = CliArgs {
+            print_log: true,
+            log_types: Some(vec![TransactionType::Chargeback]),
+            ..cli("data/input/spec_violations.csv")
+        };
+        let engine = run_engine(&cli).await.unwrap();
+        let output = render_to_string(&engine, &cli);
+        let mut lines = output.lines();
+        assert_eq!(lines.next(), Some("seq,type,client,tx,amount,status"));
+        let rows: Vec<&str> = lines.collect();
+        assert!(!rows.is_empty());
+        assert!(
+            rows.iter()
+                .all(|row| row.split(',').nth(1) == Some("chargeback")),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn id_format_flag_selects_hex_ids() {
+        let cli = parse_args(&args(&["in.csv", "--id-format", "hex"])).unwrap();
+        assert_eq!(cli.csv_options.id_format, IdFormat::Hex);
+        let cli = parse_args(&args(&["in.csv"])).unwrap();
+        assert_eq!(cli.csv_options.id_format, IdFormat::Dec);
+        assert!(parse_args(&args(&["in.csv", "--id-format", "oct"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn diff_flag_compares_against_a_snapshot() {
+        let input = "data/input/spec_violations.csv";
+        let engine = run_engine(&cli(input)).await.unwrap();
+        let mut before = Engine::new();
+        before.process(trex::ledger::transaction::Transaction::new(
+            TransactionType::Deposit,
+            1,
+            1,
+            Some(10.0),
+        ));
+        let snapshot = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            snapshot.path(),
+            serde_json::to_string(&before.snapshot()).unwrap(),
+        )
+        .unwrap();
+
+        let cli = parse_args(&args(&[input, "--diff", snapshot.path().to_str().unwrap()])).unwrap();
+        let output = render_to_string(&engine, &cli);
+        let expected = account_diffs_to_csv(&before.diff(&engine), DEFAULT_PRECISION);
+        assert_eq!(output, expected);
+        assert_eq!(output.lines().count(), 1 + engine.get_accounts().len());
+        assert!(parse_args(&args(&[input, "--diff", "s.json", "--log"])).is_err());
+    }
+
+    #[test]
+    fn seed_locked_flag_takes_client_ids() {
+        let cli = parse_args(&args(&["in.csv", "--seed-locked", "5,7"])).unwrap();
+        assert_eq!(cli.seed_locked, vec![5, 7]);
+        assert!(parse_args(&args(&["in.csv", "--seed-locked", "5,x"])).is_err());
+        assert!(parse_args(&args(&["in.csv", "--seed-locked"])).is_err());
+    }
+
+    #[tokio::test]
+    async fn client_flags_scope_the_run() {
+        let cli = parse_args(&args(&[
+            "data/input/spec_violations.csv",
+            "--only-clients",
+            "1,2",
+            "--exclude-clients",
+            "2",
+        ]))
+        .unwrap();
+        assert_eq!(cli.clients.only, Some([1, 2].into()));
+        assert!(parse_args(&args(&["in.csv", "--exclude-clients", "x"])).is_err());
+
+        let engine = run_engine(&cli).await.unwrap();
+        assert_eq!(engine.get_accounts().keys().collect::<Vec<_>>(), [&1]);
+    }
+
+    #[tokio::test]
+    async fn dry_run_prints_the_log_of_a_real_run() {
+        let input = "data/input/spec_violations.csv";
+        let real = run_engine(&cli(input)).await.unwrap();
+        let dry_cli = CliArgs {
+            dry_run: true,
+            ..cli(input)
+        };
+        let dry = run_engine(&dry_cli).await.unwrap();
+
+        let output = render_to_string(&dry, &dry_cli);
+        assert_eq!(
+            output,
+            transaction_entries_to_csv(real.get_transactions().iter())
+        );
+        assert!(output.starts_with("seq,type,client,tx,amount,status"));
+    }
+
+    #[tokio::test]
+    async fn directory_input_applies_files_in_name_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let files = [
+            (
+                "01.csv",
+                "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,4.0\n",
+            ),
+            (
+                "02.csv",
+                "type,client,tx,amount\nwithdrawal,2,3,1.0\ndispute,1,1,\n",
+            ),
+            (
+                "03.csv",
+                "type,client,tx,amount\nchargeback,1,1,\ndeposit,2,4,2.0\n",
+            ),
+        ];
+        // written out of order, the directory listing order must not matter
+        for (name, content) in files.iter().rev() {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+
+        let input = dir.path().to_str().unwrap();
+        let first = run_engine(&cli(input)).await.unwrap();
+        let second = run_engine(&cli(input)).await.unwrap();
+        assert_eq!(first.get_accounts(), second.get_accounts());
+
+        let c1 = first.get_account(1).unwrap();
+        assert_close(c1.total(), 0.0);
+        assert!(c1.is_locked());
+        assert_close(first.get_account(2).unwrap().available(), 5.0);
+    }
+
+    #[tokio::test]
+    async fn outcome_reflects_rejections_and_failures() {
+        let clean = run_engine(&cli("data/input/disputes.csv")).await;
+        assert_eq!(Outcome::of(&clean), Outcome::Clean);
+
+        let rejected = run_engine(&cli("data/input/spec_violations.csv")).await;
+        assert_eq!(Outcome::of(&rejected), Outcome::Rejected);
+
+        let missing = run_engine(&cli("data/input/does_not_exist.csv")).await;
+        assert_eq!(Outcome::of(&missing), Outcome::Fatal);
+    }
+
     #[tokio::test]
     async fn whitespace_is_handled_correctly() {
-        let engine = run_engine("data/input/whitespace.csv", ProcessingMode::SingleFile)
+        let engine = run_engine(&cli("data/input/whitespace.csv"))
             .await
             .expect("engine should process whitespace.csv");
         let accounts = engine.get_accounts();
@@ -86,7 +1361,7 @@ mod tests {
 
     #[tokio::test]
     async fn full_flow_dataset_matches_expected_balances() {
-        let engine = run_engine("data/input/full_flow_large.csv", ProcessingMode::SingleFile)
+        let engine = run_engine(&cli("data/input/full_flow_large.csv"))
             .await
             .expect("engine should process full_flow_large.csv");
         let accounts = engine.get_accounts();
@@ -98,10 +1373,11 @@ mod tests {
         assert_close(c1.total(), 0.0);
         assert!(!c1.is_locked());
 
+        // the chargeback on disputed withdrawal 5 removes the restored funds again
         let c2 = accounts.get(&2).unwrap();
-        assert_close(c2.available(), 0.0);
+        assert_close(c2.available(), 100.0);
         assert_close(c2.held(), 0.0);
-        assert_close(c2.total(), 0.0);
+        assert_close(c2.total(), 100.0);
         assert!(c2.is_locked());
 
         let c3 = accounts.get(&3).unwrap();
@@ -110,25 +1386,27 @@ mod tests {
         assert_close(c3.total(), 80.0);
         assert!(!c3.is_locked());
 
+        // withdrawal 12 is still under dispute, its funds are restored into held
         let c4 = accounts.get(&4).unwrap();
         assert_close(c4.available(), 0.0001);
-        assert_close(c4.held(), 0.0);
-        assert_close(c4.total(), 0.0001);
+        assert_close(c4.held(), 999.9999);
+        assert_close(c4.total(), 1000.0);
         assert!(!c4.is_locked());
     }
 
     #[tokio::test]
     async fn spec_violations_are_ignored_and_locking_is_respected() {
-        let engine = run_engine("data/input/spec_violations.csv", ProcessingMode::SingleFile)
+        let engine = run_engine(&cli("data/input/spec_violations.csv"))
             .await
             .expect("engine should process spec_violations.csv");
         let accounts = engine.get_accounts();
         assert_eq!(accounts.len(), 2);
 
+        // the resolved dispute on withdrawal 2 returns the withdrawn 5.0
         let c1 = accounts.get(&1).unwrap();
-        assert_close(c1.available(), 7.0);
+        assert_close(c1.available(), 12.0);
         assert_close(c1.held(), 0.0);
-        assert_close(c1.total(), 7.0);
+        assert_close(c1.total(), 12.0);
         assert!(!c1.is_locked());
 
         let c2 = accounts.get(&2).unwrap();
@@ -137,4 +1415,46 @@ mod tests {
         assert_close(c2.total(), 0.0);
         assert!(c2.is_locked());
     }
+
+    #[tokio::test]
+    async fn sharded_run_matches_single_shard() {
+        for input in [
+            "data/input/full_flow_large.csv",
+            "data/input/duplicate_tx_ids.csv",
+            "data/input/spec_violations.csv,data/input/multi_client.csv",
+        ] {
+            let single = run_engine(&cli(input)).await.unwrap();
+            let sharded = run_engine(&CliArgs {
+                shards: 4,
+                ..cli(input)
+            })
+            .await
+            .unwrap();
+            assert_eq!(single.get_accounts(), sharded.get_accounts(), "{input}");
+            // the merged log is in read order, the same on every run
+            let again = run_engine(&CliArgs {
+                shards: 4,
+                ..cli(input)
+            })
+            .await
+            .unwrap();
+            assert_eq!(
+                sharded.get_transactions(),
+                again.get_transactions(),
+                "{input}"
+            );
+            assert_eq!(
+                single.get_transactions(),
+                sharded.get_transactions(),
+                "{input}"
+            );
+            let concurrent = run_engine(&CliArgs {
+                concurrency: 4,
+                ..cli(input)
+            })
+            .await
+            .unwrap();
+            assert_eq!(single.get_accounts(), concurrent.get_accounts(), "{input}");
+        }
+    }
 }
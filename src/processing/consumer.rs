@@ -1,39 +1,791 @@
-use std::path::PathBuf;
-use tokio::sync::mpsc;
+use flate2::read::GzDecoder;
+use log::warn;
+use std::{
+    collections::VecDeque,
+    fmt::Display,
+    fs::File,
+    future::Future,
+    hash::{DefaultHasher, Hasher},
+    io::Read,
+    ops::Range,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
 
-use crate::ledger::{engine::Engine, transaction::Transaction};
+use crate::{
+    ledger::{
+        engine::Engine,
+        transaction::{Transaction, TransactionStatus},
+    },
+    processing::{
+        ClientFilter, CsvOptions, WorkItem, WorkReceiver, error::ConsumeError, shard::ShardRouter,
+    },
+};
+
+/// Called with the running count of records handed to the engine, see `with_progress`.
+pub type ProgressCallback = Box<dyn Fn(u64) + Send>;
+
+/// Called with the number of distinct clients so far, see `with_client_count`.
+pub type ClientCountCallback = Box<dyn Fn(usize) + Send>;
+
+/// Completes when the consumer should stop taking work, see `with_shutdown`.
+pub type ShutdownSignal = Pin<Box<dyn Future<Output = ()> + Send>>;
 
 pub struct TransactionConsumer {
-    rx: mpsc::Receiver<PathBuf>,
+    rx: WorkReceiver,
     engine: Engine,
+    shards: usize,
+    strict: bool,
+    csv_options: CsvOptions,
+    concurrency: usize,
+    progress: Progress,
+    clients: ClientFilter,
+    metrics: ConsumeMetrics,
+    skip_missing: bool,
+    shutdown: Option<ShutdownSignal>,
+    // read for `WorkItem::Stdin` instead of the process's stdin, see `with_stdin`
+    stdin: Option<Box<dyn Read + Send>>,
+}
+
+/// Where the consumer's time went, see `consume_with_metrics`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConsumeMetrics {
+    /// Reading and deserializing CSV rows. Files read ahead (`with_concurrency`) and stdin
+    /// are parsed on parallel tasks, so this can exceed the wall-clock time. Records sent
+    /// already parsed are not included.
+    pub parse: Duration,
+    /// Handing transactions to the engine (`Engine::process`), or to the shard channels when
+    /// sharded.
+    pub process: Duration,
+}
+
+impl Display for ConsumeMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "parse={:?} process={:?}", self.parse, self.process)
+    }
+}
+
+/// What one input file contributed to a run, see `consume_with_reports`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileReport {
+    pub path: PathBuf,
+    /// Every row read, malformed ones skipped in lenient mode and ones with an out of range
+    /// client ID included.
+    pub rows_read: u64,
+    pub rows_applied: u64,
+    /// Rows the engine ignored or failed. Rows not counted as applied or rejected were
+    /// skipped as malformed or dropped by the client filter.
+    pub rows_rejected: u64,
+}
+
+impl Display for FileReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}: read={} applied={} rejected={}",
+            self.path.display(),
+            self.rows_read,
+            self.rows_applied,
+            self.rows_rejected
+        )
+    }
+}
+
+// a file's rows went to the engine as one contiguous run of sequence indexes, their
+// outcomes are read back from the event log once processing is over
+struct FileRun {
+    path: PathBuf,
+    rows_read: u64,
+    skipped: u64,
+    seqs: Range<u64>,
+}
+
+impl FileRun {
+    fn report(self, engine: &Engine) -> FileReport {
+        let log = engine.get_transactions();
+        let start = log.partition_point(|entry| entry.seq < self.seqs.start);
+        let end = log.partition_point(|entry| entry.seq < self.seqs.end);
+        let mut report = FileReport {
+            path: self.path,
+            rows_read: self.rows_read,
+            rows_applied: 0,
+            rows_rejected: 0,
+        };
+        for entry in &log[start..end] {
+            match entry.status {
+                TransactionStatus::Applied | TransactionStatus::AppliedPartial => {
+                    report.rows_applied += 1
+                }
+                // synthetic, not one of the file's rows
+                TransactionStatus::AutoResolved => {}
+                _ => report.rows_rejected += 1,
+            }
+        }
+        report
+    }
+}
+
+struct Progress {
+    every: u64,
+    count: u64,
+    callback: Option<ProgressCallback>,
+    client_count: Option<(u64, ClientCountCallback)>,
+}
+
+impl Progress {
+    fn tick(&mut self, sink: &Sink) {
+        self.count += 1;
+        if let Some(callback) = &self.callback
+            && self.count.is_multiple_of(self.every)
+        {
+            callback(self.count);
+        }
+        if let Some((every, callback)) = &self.client_count
+            && self.count.is_multiple_of(*every)
+        {
+            callback(sink.client_count());
+        }
+    }
+}
+
+// `Engine::process` never awaits, on a long file the consumer would hold its worker thread
+// until the end. Yielding every so often lets other tasks (shards, producers) make progress.
+const YIELD_EVERY: u64 = 1024;
+
+// where parsed transactions end up: the engine itself, or the shards built from it
+enum Target {
+    Single(Box<Engine>),
+    Sharded(ShardRouter),
+}
+
+struct Sink {
+    target: Target,
+    clients: ClientFilter,
+    metrics: ConsumeMetrics,
+}
+
+impl Sink {
+    /// Returns `false` if the client filter dropped the transaction.
+    async fn submit(&mut self, tx: Transaction) -> Result<bool, ConsumeError> {
+        if !self.clients.permits(tx.client) {
+            return Ok(false);
+        }
+        let started = Instant::now();
+        match &mut self.target {
+            Target::Single(engine) => {
+                engine.process(tx);
+                if engine.seq() % YIELD_EVERY == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+            Target::Sharded(router) => router.submit(tx).await?,
+        }
+        self.metrics.process += started.elapsed();
+        Ok(true)
+    }
+
+    fn seq(&self) -> u64 {
+        match &self.target {
+            Target::Single(engine) => engine.seq(),
+            Target::Sharded(router) => router.seq(),
+        }
+    }
+
+    fn client_count(&self) -> usize {
+        match &self.target {
+            Target::Single(engine) => engine.client_count(),
+            Target::Sharded(router) => router.client_count(),
+        }
+    }
+
+    /// Returns `false` if a file with the same content hash was already recorded.
+    fn record_processed_file(&mut self, hash: u64) -> bool {
+        match &mut self.target {
+            Target::Single(engine) => engine.record_processed_file(hash),
+            Target::Sharded(router) => router.record_processed_file(hash),
+        }
+    }
+
+    async fn finish(self) -> Result<Engine, ConsumeError> {
+        match self.target {
+            Target::Single(engine) => Ok(*engine),
+            Target::Sharded(router) => router.finish().await,
+        }
+    }
 }
 
 impl TransactionConsumer {
-    pub fn new(rx: mpsc::Receiver<PathBuf>, engine: Engine) -> Self {
-        Self { rx, engine }
+    pub fn new(rx: impl Into<WorkReceiver>, engine: Engine) -> Self {
+        Self {
+            rx: rx.into(),
+            engine,
+            shards: 1,
+            strict: true,
+            csv_options: CsvOptions::default(),
+            concurrency: 1,
+            progress: Progress {
+                every: 1,
+                count: 0,
+                callback: None,
+                client_count: None,
+            },
+            clients: ClientFilter::default(),
+            metrics: ConsumeMetrics::default(),
+            skip_missing: false,
+            shutdown: None,
+            stdin: None,
+        }
+    }
+
+    pub fn with_csv_options(mut self, csv_options: CsvOptions) -> Self {
+        self.csv_options = csv_options;
+        self
+    }
+
+    /// In strict mode (the default) a row that fails to deserialize aborts the run. With
+    /// `strict` off the row is skipped with a warning and the rest of the file still applies.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
     }
 
-    pub async fn consume(mut self) -> anyhow::Result<Engine> {
-        while let Some(path) = self.rx.recv().await {
-            // trim whitespace fix
-            let mut reader = csv::ReaderBuilder::new()
-                .trim(csv::Trim::All)
-                .from_path(path)?;
+    /// Processes clients on `shards` engines, each on its own task, routed by `client % shards`.
+    /// A client's transactions (and the disputes referencing them) always land on the same
+    /// shard, so results match a single engine, the merged event log included.
+    pub fn with_shards(mut self, shards: usize) -> Self {
+        self.shards = shards.max(1);
+        self
+    }
+
+    /// With `skip_missing`, a file that can't be opened (missing, no permission...) is skipped
+    /// with a warning and the run goes on with the next one. By default it aborts the run.
+    /// Failures after a file was opened still abort, its rows may be partly applied.
+    pub fn with_skip_missing(mut self, skip_missing: bool) -> Self {
+        self.skip_missing = skip_missing;
+        self
+    }
+
+    /// Reads `WorkItem::Stdin` from `source` rather than the process's stdin, e.g. in tests.
+    /// Only the first `WorkItem::Stdin` reads it, later ones find it exhausted.
+    pub fn with_stdin(mut self, source: impl Read + Send + 'static) -> Self {
+        self.stdin = Some(Box::new(source));
+        self
+    }
+
+    /// Stops taking work from the channel once `signal` completes (e.g. on Ctrl-C), as if the
+    /// channel had closed: the file being processed and those already read ahead are
+    /// finished, items still queued are dropped, and `consume` returns the engine built so
+    /// far. A producer still sending then gets `ConsumerGone`.
+    pub fn with_shutdown(mut self, signal: impl Future<Output = ()> + Send + 'static) -> Self {
+        self.shutdown = Some(Box::pin(signal));
+        self
+    }
+
+    /// Reads up to `concurrency` files at once on blocking tasks. Their transactions are still
+    /// applied one file after the other, in the order the paths arrived, so a client's
+    /// transactions keep their file-then-row order. Files read ahead are held in memory,
+    /// with the default of 1 each file is streamed into the engine instead.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Calls `callback` with the number of records processed so far, every `every` records
+    /// (rows skipped in lenient mode and files skipped as already processed don't count).
+    pub fn with_progress(mut self, every: u64, callback: impl Fn(u64) + Send + 'static) -> Self {
+        self.progress.every = every.max(1);
+        self.progress.callback = Some(Box::new(callback));
+        self
+    }
+
+    /// Calls `callback` with the number of distinct clients (accounts) so far, every `every`
+    /// records counted as for `with_progress`, e.g. to watch the client base grow.
+    pub fn with_client_count(
+        mut self,
+        every: u64,
+        callback: impl Fn(usize) + Send + 'static,
+    ) -> Self {
+        self.progress.client_count = Some((every.max(1), Box::new(callback)));
+        self
+    }
+
+    /// Drops transactions of clients the filter doesn't permit before they reach the engine,
+    /// see `ClientFilter`. They don't count towards progress either.
+    pub fn with_client_filter(mut self, clients: ClientFilter) -> Self {
+        self.clients = clients;
+        self
+    }
+
+    /// Reads CSV rows from an in-memory or streamed source (`&[u8]`, a `Cursor`, stdin...)
+    /// straight into the engine, with the same CSV options and strictness as files. Rows
+    /// applied here come before anything `consume` receives; malformed rows are reported
+    /// with a `<reader>` path.
+    pub async fn consume_reader<R: Read>(&mut self, reader: R) -> Result<(), ConsumeError> {
+        let mut reader = self.csv_options.reader(reader);
+        self.csv_options
+            .check_header(&mut reader, Path::new(READER_SOURCE))?;
+        let mut sink = Sink {
+            target: Target::Single(Box::new(std::mem::take(&mut self.engine))),
+            clients: std::mem::take(&mut self.clients),
+            metrics: self.metrics,
+        };
+        let result = submit_rows(
+            &mut sink,
+            &mut self.progress,
+            self.strict,
+            self.csv_options
+                .transactions(&mut reader)
+                .map(|row| row.map_err(|e| ConsumeError::from_csv(Path::new(READER_SOURCE), e))),
+        )
+        .await;
+        self.clients = sink.clients;
+        self.metrics = sink.metrics;
+        if let Target::Single(engine) = sink.target {
+            self.engine = *engine;
+        }
+        warn_skipped(result?.skipped);
+        Ok(())
+    }
+
+    pub async fn consume(self) -> Result<Engine, ConsumeError> {
+        self.consume_with_metrics().await.map(|(engine, _)| engine)
+    }
+
+    /// `consume`, also timing parsing and processing (including rows from `consume_reader`).
+    pub async fn consume_with_metrics(self) -> Result<(Engine, ConsumeMetrics), ConsumeError> {
+        let (engine, metrics, _) = self.consume_with_reports().await?;
+        Ok((engine, metrics))
+    }
+
+    /// `consume_with_metrics`, also reporting what each file received as a path contributed
+    /// (and stdin, as `<stdin>`), in the order they were applied. Files skipped (missing
+    /// with `with_skip_missing`, or already processed) have no report. Applied and rejected
+    /// rows are counted from the event log: both are 0 with the log disabled.
+    pub async fn consume_with_reports(
+        mut self,
+    ) -> Result<(Engine, ConsumeMetrics, Vec<FileReport>), ConsumeError> {
+        let skip_processed_files = self.engine.skips_processed_files();
+        let target = if self.shards > 1 {
+            Target::Sharded(ShardRouter::spawn(self.engine, self.shards))
+        } else {
+            Target::Single(Box::new(self.engine))
+        };
+        let mut sink = Sink {
+            target,
+            clients: self.clients,
+            metrics: self.metrics,
+        };
 
-            for result in reader.deserialize::<Transaction>() {
-                let tx: Transaction = result?;
-                self.engine.process(tx);
+        let mut skipped = 0;
+        let mut runs = Vec::new();
+        // files being read ahead, in arrival order
+        let mut pending: VecDeque<JoinHandle<Result<ParsedFile, ConsumeError>>> = VecDeque::new();
+        loop {
+            let item = match &mut self.shutdown {
+                Some(signal) => tokio::select! {
+                    biased;
+                    _ = signal.as_mut() => {
+                        warn!("Shutdown requested, not taking further work");
+                        break;
+                    }
+                    item = self.rx.recv() => item,
+                },
+                None => self.rx.recv().await,
+            };
+            let Some(item) = item else {
+                break;
+            };
+            match item {
+                WorkItem::Path(path) => {
+                    if self.skip_missing
+                        && let Err(e) = File::open(&path)
+                    {
+                        warn!("Skipping {}: {}", path.display(), e);
+                        continue;
+                    }
+                    if skip_processed_files && !sink.record_processed_file(file_hash(&path)?) {
+                        warn!(
+                            "Skipping {}: a file with the same content was already processed",
+                            path.display()
+                        );
+                        continue;
+                    }
+                    if self.concurrency > 1 {
+                        if pending.len() == self.concurrency {
+                            let run = apply_parsed(
+                                &mut sink,
+                                &mut self.progress,
+                                pending.pop_front().unwrap(),
+                            )
+                            .await?;
+                            skipped += run.skipped;
+                            runs.push(run);
+                        }
+                        let (options, strict) = (self.csv_options, self.strict);
+                        pending.push_back(tokio::task::spawn_blocking(move || {
+                            read_file(&options, strict, &path)
+                        }));
+                        continue;
+                    }
+                    let rows = open_rows(&self.csv_options, &path)?;
+                    let start = sink.seq();
+                    let rows =
+                        submit_rows(&mut sink, &mut self.progress, self.strict, rows).await?;
+                    skipped += rows.skipped;
+                    runs.push(FileRun {
+                        path,
+                        rows_read: rows.read,
+                        skipped: rows.skipped,
+                        seqs: start..sink.seq(),
+                    });
+                }
+                WorkItem::Stdin => {
+                    // stdin applies after the files sent before it
+                    while let Some(file) = pending.pop_front() {
+                        let run = apply_parsed(&mut sink, &mut self.progress, file).await?;
+                        skipped += run.skipped;
+                        runs.push(run);
+                    }
+                    let source = self
+                        .stdin
+                        .replace(Box::new(std::io::empty()))
+                        .unwrap_or_else(|| Box::new(std::io::stdin()));
+                    let start = sink.seq();
+                    let (rows, stopped) = submit_stdin(
+                        &mut sink,
+                        &mut self.progress,
+                        self.strict,
+                        self.csv_options,
+                        source,
+                        self.shutdown.as_mut(),
+                    )
+                    .await?;
+                    skipped += rows.skipped;
+                    runs.push(FileRun {
+                        path: PathBuf::from(STDIN_SOURCE),
+                        rows_read: rows.read,
+                        skipped: rows.skipped,
+                        seqs: start..sink.seq(),
+                    });
+                    if stopped {
+                        warn!("Shutdown requested, not reading the rest of stdin");
+                        break;
+                    }
+                }
+                WorkItem::Record(tx) => {
+                    // a record sent after a path applies after that file
+                    while let Some(file) = pending.pop_front() {
+                        let run = apply_parsed(&mut sink, &mut self.progress, file).await?;
+                        skipped += run.skipped;
+                        runs.push(run);
+                    }
+                    if sink.submit(tx).await? {
+                        self.progress.tick(&sink);
+                    }
+                }
             }
         }
-        Ok(self.engine)
+        while let Some(file) = pending.pop_front() {
+            let run = apply_parsed(&mut sink, &mut self.progress, file).await?;
+            skipped += run.skipped;
+            runs.push(run);
+        }
+        warn_skipped(skipped);
+        let metrics = sink.metrics;
+        let engine = sink.finish().await?;
+        let reports = runs.into_iter().map(|run| run.report(&engine)).collect();
+        Ok((engine, metrics, reports))
+    }
+}
+
+// stands in for the file path in errors about rows from `consume_reader`
+const READER_SOURCE: &str = "<reader>";
+
+// stands in for the file path in errors about rows from `WorkItem::Stdin`
+const STDIN_SOURCE: &str = "<stdin>";
+
+// parsed stdin rows waiting for the engine
+const STDIN_ROWS_CAPACITY: usize = 1024;
+
+// rows read from one source, and how many of them were skipped as malformed
+struct RowCounts {
+    read: u64,
+    skipped: u64,
+}
+
+/// Streams the parsed rows into the sink.
+async fn submit_rows(
+    sink: &mut Sink,
+    progress: &mut Progress,
+    strict: bool,
+    rows: impl Iterator<Item = Result<Transaction, ConsumeError>>,
+) -> Result<RowCounts, ConsumeError> {
+    let mut counts = RowCounts {
+        read: 0,
+        skipped: 0,
+    };
+    let mut rows = rows.enumerate();
+    loop {
+        let started = Instant::now();
+        let Some((record, result)) = rows.next() else {
+            break;
+        };
+        sink.metrics.parse += started.elapsed();
+        submit_row(sink, progress, strict, &mut counts, record, result).await?;
+    }
+    Ok(counts)
+}
+
+/// Streams stdin's rows into the sink. A blocking task reads and parses them, so a slow
+/// stdin doesn't hold up the runtime. Unlike a file, stdin may never end: a shutdown stops
+/// it between rows, which the returned flag tells.
+async fn submit_stdin(
+    sink: &mut Sink,
+    progress: &mut Progress,
+    strict: bool,
+    options: CsvOptions,
+    source: Box<dyn Read + Send>,
+    mut shutdown: Option<&mut ShutdownSignal>,
+) -> Result<(RowCounts, bool), ConsumeError> {
+    let (rows_tx, mut rows_rx) = tokio::sync::mpsc::channel(STDIN_ROWS_CAPACITY);
+    let reader = tokio::task::spawn_blocking(move || {
+        let path = Path::new(STDIN_SOURCE);
+        let mut reader = options.reader(source);
+        if let Err(e) = options.check_header(&mut reader, path) {
+            let _ = rows_tx.blocking_send((Duration::ZERO, Err(e)));
+            return;
+        }
+        let mut rows = options.transactions(&mut reader);
+        loop {
+            let started = Instant::now();
+            let Some(row) = rows.next() else {
+                return;
+            };
+            let row = row.map_err(|e| ConsumeError::from_csv(path, e));
+            // the consumer stopped, e.g. on a malformed row in strict mode
+            if rows_tx.blocking_send((started.elapsed(), row)).is_err() {
+                return;
+            }
+        }
+    });
+    let mut counts = RowCounts {
+        read: 0,
+        skipped: 0,
+    };
+    let mut record = 0;
+    loop {
+        let row = match &mut shutdown {
+            Some(signal) => tokio::select! {
+                biased;
+                _ = signal.as_mut() => return Ok((counts, true)),
+                row = rows_rx.recv() => row,
+            },
+            None => rows_rx.recv().await,
+        };
+        let Some((parse, result)) = row else {
+            break;
+        };
+        sink.metrics.parse += parse;
+        submit_row(sink, progress, strict, &mut counts, record, result).await?;
+        record += 1;
+    }
+    reader
+        .await
+        .map_err(|e| ConsumeError::Io(std::io::Error::other(e)))?;
+    Ok((counts, false))
+}
+
+// counts a row read and hands it to the sink unless it's skipped
+async fn submit_row(
+    sink: &mut Sink,
+    progress: &mut Progress,
+    strict: bool,
+    counts: &mut RowCounts,
+    record: usize,
+    result: Result<Transaction, ConsumeError>,
+) -> Result<(), ConsumeError> {
+    counts.read += 1;
+    let Some(tx) = parse_row(strict, record, result)? else {
+        counts.skipped += 1;
+        return Ok(());
+    };
+    if sink.submit(tx).await? {
+        progress.tick(sink);
+    }
+    Ok(())
+}
+
+// the transactions of one input file, whatever its format
+type FileRows = Box<dyn Iterator<Item = Result<Transaction, ConsumeError>> + Send>;
+
+/// Files named `*.jsonl` (or `*.jsonl.gz`) are read as JSON lines, anything else as CSV.
+/// Files with a `.gz` extension are decompressed while reading.
+fn open_rows(options: &CsvOptions, path: &Path) -> Result<FileRows, ConsumeError> {
+    let file = File::open(path).map_err(|e| ConsumeError::from_io(path, e))?;
+    let source: Box<dyn Read + Send> = if path.extension().is_some_and(|ext| ext == "gz") {
+        Box::new(GzDecoder::new(file))
+    } else {
+        Box::new(file)
+    };
+    let name = path.to_string_lossy();
+    if name.ends_with(".jsonl") || name.ends_with(".jsonl.gz") {
+        return Ok(Box::new(options.json_lines(source, path)));
+    }
+    let mut reader = options.reader(source);
+    options.check_header(&mut reader, path)?;
+    let path = path.to_path_buf();
+    Ok(Box::new(options.owned_transactions(reader).map(
+        move |row| row.map_err(|e| ConsumeError::from_csv(&path, e)),
+    )))
+}
+
+/// Parses every row of the file at `path` (plain or `.gz`, CSV or JSON lines) without
+/// processing anything, e.g. to check an input before a run. Returns the malformed rows as
+/// `ConsumeError::CsvParse` (`JsonParse` for JSON lines), with their line numbers, in file
+/// order: empty when every row parses. An unreadable file or a bad header is the error
+/// instead.
+pub fn validate_file(options: &CsvOptions, path: &Path) -> Result<Vec<ConsumeError>, ConsumeError> {
+    validate_rows(open_rows(options, path)?)
+}
+
+/// `validate_file` over an in-memory or streamed source, rows are reported with a
+/// `<reader>` path like `consume_reader` does.
+pub fn validate_reader<R: Read>(
+    options: &CsvOptions,
+    reader: R,
+) -> Result<Vec<ConsumeError>, ConsumeError> {
+    let path = Path::new(READER_SOURCE);
+    let mut reader = options.reader(reader);
+    options.check_header(&mut reader, path)?;
+    validate_rows(
+        options
+            .transactions(&mut reader)
+            .map(|row| row.map_err(|e| ConsumeError::from_csv(path, e))),
+    )
+}
+
+fn validate_rows(
+    rows: impl Iterator<Item = Result<Transaction, ConsumeError>>,
+) -> Result<Vec<ConsumeError>, ConsumeError> {
+    let mut malformed = Vec::new();
+    for result in rows {
+        match result {
+            Ok(_) => {}
+            Err(
+                e @ (ConsumeError::CsvParse { .. }
+                | ConsumeError::JsonParse { .. }
+                | ConsumeError::ClientOutOfRange { .. }),
+            ) => malformed.push(e),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(malformed)
+}
+
+// a file read ahead of its turn, see `with_concurrency`
+struct ParsedFile {
+    path: PathBuf,
+    transactions: Vec<Transaction>,
+    skipped: u64,
+    parse: Duration,
+}
+
+fn read_file(options: &CsvOptions, strict: bool, path: &Path) -> Result<ParsedFile, ConsumeError> {
+    let started = Instant::now();
+    let mut parsed = ParsedFile {
+        path: path.to_path_buf(),
+        transactions: Vec::new(),
+        skipped: 0,
+        parse: Duration::ZERO,
+    };
+    for (record, result) in open_rows(options, path)?.enumerate() {
+        match parse_row(strict, record, result)? {
+            Some(tx) => parsed.transactions.push(tx),
+            None => parsed.skipped += 1,
+        }
+    }
+    parsed.parse = started.elapsed();
+    Ok(parsed)
+}
+
+/// Waits for a file read ahead and submits its transactions.
+async fn apply_parsed(
+    sink: &mut Sink,
+    progress: &mut Progress,
+    file: JoinHandle<Result<ParsedFile, ConsumeError>>,
+) -> Result<FileRun, ConsumeError> {
+    let parsed = file
+        .await
+        .map_err(|e| ConsumeError::Io(std::io::Error::other(e)))??;
+    sink.metrics.parse += parsed.parse;
+    let start = sink.seq();
+    let rows_read = parsed.transactions.len() as u64 + parsed.skipped;
+    for tx in parsed.transactions {
+        if sink.submit(tx).await? {
+            progress.tick(sink);
+        }
+    }
+    Ok(FileRun {
+        path: parsed.path,
+        rows_read,
+        skipped: parsed.skipped,
+        seqs: start..sink.seq(),
+    })
+}
+
+// hashes the raw bytes, so a compressed file and its uncompressed copy count as different files
+fn file_hash(path: &Path) -> Result<u64, ConsumeError> {
+    let mut file = File::open(path).map_err(|e| ConsumeError::from_csv(path, e.into()))?;
+    let mut hasher = DefaultHasher::new();
+    let mut buf = [0; 8192];
+    loop {
+        match file.read(&mut buf)? {
+            0 => return Ok(hasher.finish()),
+            n => hasher.write(&buf[..n]),
+        }
+    }
+}
+
+/// Returns `None` for a malformed row that should be skipped (lenient mode only), or a row
+/// whose client ID is out of range (always). I/O failures are never skipped, the rest of the
+/// file can't be trusted after one.
+fn parse_row(
+    strict: bool,
+    record: usize,
+    result: Result<Transaction, ConsumeError>,
+) -> Result<Option<Transaction>, ConsumeError> {
+    match result {
+        Ok(tx) => Ok(Some(tx)),
+        Err(e @ ConsumeError::ClientOutOfRange { .. }) => {
+            warn!("Skipping record {}: {}", record + 1, e);
+            Ok(None)
+        }
+        Err(e @ (ConsumeError::CsvParse { .. } | ConsumeError::JsonParse { .. })) if !strict => {
+            warn!("Skipping malformed record {}: {}", record + 1, e);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+fn warn_skipped(skipped: u64) {
+    if skipped > 0 {
+        warn!("Skipped {} malformed record(s)", skipped);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Write;
+    use crate::{
+        ledger::transaction::{TransactionStatus, TransactionType, rejected_entries_to_csv},
+        processing::{DEFAULT_MAX_RECORD_BYTES, IdFormat},
+    };
+    use std::{io::Write, path::PathBuf};
     use tempfile::NamedTempFile;
+    use tokio::sync::mpsc;
 
     fn create_csv(content: &str) -> NamedTempFile {
         let mut file = NamedTempFile::new().unwrap();
@@ -41,6 +793,757 @@ mod tests {
         file
     }
 
+    #[tokio::test]
+    async fn client_sorted_input_matches_the_unsorted_path() {
+        let mut rows = String::from("type,client,tx,amount\n");
+        for client in 1..=20u32 {
+            let base = client * 10;
+            rows += &format!("deposit,{client},{base},100.0\n");
+            rows += &format!("withdrawal,{client},{},30.0\n", base + 1);
+            rows += &format!("dispute,{client},{base},\n");
+            match client % 3 {
+                0 => rows += &format!("resolve,{client},{base},\n"),
+                1 => rows += &format!("chargeback,{client},{base},\n"),
+                _ => {}
+            }
+            rows += &format!("deposit,{client},{},5.0\n", base + 2);
+        }
+        let csv = create_csv(&rows);
+        let path = csv.path().to_path_buf();
+
+        let run = |engine: Engine, shards: usize| {
+            let path = path.clone();
+            async move {
+                let (path_tx, path_rx) = mpsc::channel(10);
+                path_tx.send(WorkItem::Path(path)).await.unwrap();
+                drop(path_tx);
+                TransactionConsumer::new(path_rx, engine)
+                    .with_shards(shards)
+                    .consume()
+                    .await
+                    .unwrap()
+            }
+        };
+        let unsorted = run(Engine::new(), 1).await;
+        for shards in [1, 4] {
+            for drop_finished in [false, true] {
+                let sorted = run(Engine::new().with_sorted_clients(drop_finished), shards).await;
+                assert_eq!(sorted.get_accounts(), unsorted.get_accounts());
+                assert_eq!(sorted.get_transactions(), unsorted.get_transactions());
+                assert_eq!(sorted.aggregate(), unsorted.aggregate());
+                assert_eq!(sorted.ledger_proof(), unsorted.ledger_proof());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn jsonl_input_balances_like_the_csv_equivalent() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, content: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, content).unwrap();
+            path
+        };
+        let csv = write(
+            "all.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\nwithdrawal,1,3,2.5\n\
+             dispute,1,1,\nresolve,1,1,\ndispute,2,2,\nchargeback,2,2,\n",
+        );
+        let jsonl = write(
+            "all.jsonl",
+            r#"{"type":"deposit","client":1,"tx":1,"amount":10.0}
+{"type":"deposit","client":2,"tx":2,"amount":"5.0"}
+{"type":"withdrawal","client":1,"tx":3,"amount":2.5}
+
+{"type":"dispute","client":1,"tx":1}
+{"type":"resolve","client":1,"tx":1,"amount":null}
+{"type":"dispute","client":2,"tx":2}
+{"type":"chargeback","client":2,"tx":2}
+"#,
+        );
+        // the disputes in the JSON lines file reference the CSV file's deposits
+        let first = write(
+            "first.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,5.0\n",
+        );
+        let rest = write(
+            "rest.jsonl",
+            r#"{"type":"withdrawal","client":1,"tx":3,"amount":2.5}
+{"type":"dispute","client":1,"tx":1}
+{"type":"resolve","client":1,"tx":1}
+{"type":"dispute","client":2,"tx":2}
+{"type":"chargeback","client":2,"tx":2}
+"#,
+        );
+
+        let run = |paths: Vec<PathBuf>, concurrency: usize| async move {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            for path in paths {
+                path_tx.send(WorkItem::Path(path)).await.unwrap();
+            }
+            drop(path_tx);
+            TransactionConsumer::new(path_rx, Engine::new())
+                .with_concurrency(concurrency)
+                .consume()
+                .await
+                .unwrap()
+        };
+        let expected = run(vec![csv], 1).await;
+        assert_eq!(expected.get_account(1).unwrap().total(), 7.5);
+        assert!(expected.get_account(2).unwrap().is_locked());
+        for concurrency in [1, 2] {
+            for paths in [vec![jsonl.clone()], vec![first.clone(), rest.clone()]] {
+                let engine = run(paths, concurrency).await;
+                assert_eq!(engine.get_accounts(), expected.get_accounts());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn malformed_jsonl_lines_follow_the_strict_setting() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.jsonl");
+        std::fs::write(
+            &path,
+            "{\"type\":\"deposit\",\"client\":1,\"tx\":1,\"amount\":10.0}\n\
+             {\"type\":\"deposit\",\"client\":1,\"tx\":\"x\",\"amount\":1.0}\n\
+             deposit,1,3,1.0\n\
+             {\"type\":\"deposit\",\"client\":70000,\"tx\":4,\"amount\":1.0}\n\
+             {\"type\":\"withdrawal\",\"client\":1,\"tx\":5,\"amount\":4.0}\n",
+        )
+        .unwrap();
+
+        let lines: Vec<u64> = validate_file(&CsvOptions::default(), &path)
+            .unwrap()
+            .iter()
+            .map(|e| match e {
+                ConsumeError::JsonParse { line, .. }
+                | ConsumeError::ClientOutOfRange { line, .. } => *line,
+                other => panic!("unexpected error: {other}"),
+            })
+            .collect();
+        assert_eq!(lines, [2, 3, 4]);
+
+        for strict in [false, true] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            path_tx.send(WorkItem::Path(path.clone())).await.unwrap();
+            drop(path_tx);
+            let result = TransactionConsumer::new(path_rx, Engine::new())
+                .with_strict(strict)
+                .consume()
+                .await;
+            match result {
+                Ok(engine) => {
+                    assert!(!strict);
+                    assert_eq!(engine.get_account(1).unwrap().total(), 6.0);
+                    assert_eq!(engine.get_accounts().len(), 1);
+                }
+                Err(e) => {
+                    assert!(strict);
+                    assert!(matches!(e, ConsumeError::JsonParse { line: 2, .. }), "{e}");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn stdin_rows_follow_the_strict_setting() {
+        let rows = "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,x,1.0\nwithdrawal,1,3,4.0\n";
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 9, Some(1.0));
+
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new())
+            .with_strict(false)
+            .with_stdin(rows.as_bytes());
+        path_tx.send(WorkItem::Stdin).await.unwrap();
+        // applies after stdin, like a record after a file
+        path_tx.send(WorkItem::Record(deposit)).await.unwrap();
+        drop(path_tx);
+        let (engine, _, reports) = consumer.consume_with_reports().await.unwrap();
+        assert_eq!(engine.get_account(1).unwrap().total(), 7.0);
+        assert_eq!(
+            engine
+                .get_transactions()
+                .iter()
+                .map(|entry| entry.tx.tx)
+                .collect::<Vec<_>>(),
+            [1, 3, 9]
+        );
+        assert_eq!(
+            reports,
+            [FileReport {
+                path: PathBuf::from("<stdin>"),
+                rows_read: 3,
+                rows_applied: 2,
+                rows_rejected: 0,
+            }]
+        );
+
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new()).with_stdin(rows.as_bytes());
+        path_tx.send(WorkItem::Stdin).await.unwrap();
+        drop(path_tx);
+        let err = consumer.consume().await.unwrap_err();
+        assert!(
+            matches!(&err, ConsumeError::CsvParse { path, line: 3, .. } if path == Path::new("<stdin>")),
+            "{err}"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_what_each_file_contributed() {
+        let first = create_csv(
+            "type,client,tx,amount\ndeposit,1,1,10.0\nwithdrawal,1,2,50.0\ndeposit,1,x,1.0\n\
+             dispute,1,1,\n",
+        );
+        let second =
+            create_csv("type,client,tx,amount\ndeposit,2,3,5.0\ndeposit,2,1,5.0\nresolve,1,1,\n");
+        let expected = [
+            FileReport {
+                path: first.path().to_path_buf(),
+                rows_read: 4,
+                rows_applied: 2,
+                rows_rejected: 1,
+            },
+            FileReport {
+                path: second.path().to_path_buf(),
+                rows_read: 3,
+                rows_applied: 2,
+                rows_rejected: 1,
+            },
+        ];
+
+        for (shards, concurrency) in [(1, 1), (3, 1), (1, 2)] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            let consumer = TransactionConsumer::new(path_rx, Engine::new())
+                .with_strict(false)
+                .with_shards(shards)
+                .with_concurrency(concurrency);
+            for file in [&first, &second] {
+                path_tx
+                    .send(WorkItem::Path(file.path().to_path_buf()))
+                    .await
+                    .unwrap();
+            }
+            drop(path_tx);
+
+            let (_, _, reports) = consumer.consume_with_reports().await.unwrap();
+            assert_eq!(
+                reports, expected,
+                "shards={shards} concurrency={concurrency}"
+            );
+        }
+        assert_eq!(
+            expected[1].to_string(),
+            format!("{}: read=3 applied=2 rejected=1", second.path().display())
+        );
+    }
+
+    #[tokio::test]
+    async fn consumes_csv_from_an_in_memory_reader() {
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer = TransactionConsumer::new(path_rx, Engine::new());
+
+        let rows = "type,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,30.0\n";
+        consumer
+            .consume_reader(std::io::Cursor::new(rows))
+            .await
+            .unwrap();
+        // a plain byte slice works too, and applies after the first reader
+        consumer
+            .consume_reader(b"type,client,tx,amount\ndispute,1,2,\n".as_slice())
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let engine = consumer.consume().await.unwrap();
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 70.0);
+        assert_eq!(account.held(), 30.0);
+        assert_eq!(engine.get_transactions().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn malformed_reader_row_reports_its_line() {
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer = TransactionConsumer::new(path_rx, Engine::new());
+
+        let rows = "type,client,tx,amount\ndeposit,1,1,1.0\nteleport,1,2,1.0\n";
+        let err = consumer
+            .consume_reader(std::io::Cursor::new(rows))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(&err, ConsumeError::CsvParse { path, line: 3, .. } if path == Path::new("<reader>")),
+            "got {err:?}"
+        );
+
+        // lenient mode skips the row, the engine keeps what applied before
+        let mut consumer = consumer.with_strict(false);
+        consumer
+            .consume_reader(std::io::Cursor::new(rows.replace("1,1,", "1,3,")))
+            .await
+            .unwrap();
+        assert_eq!(consumer.engine.get_account(1).unwrap().available(), 2.0);
+    }
+
+    #[tokio::test]
+    async fn client_filter_drops_transactions_before_the_engine() {
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer =
+            TransactionConsumer::new(path_rx, Engine::new()).with_client_filter(ClientFilter {
+                only: Some([1, 2, 9].into()),
+                exclude: [9].into(),
+            });
+        let csv = create_csv(
+            "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,9,2,50.0\n\
+             deposit,3,3,10.0\n\
+             deposit,1,2,5.0\n\
+             dispute,2,3,\n\
+             dispute,1,2,\n",
+        );
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let engine = consumer.consume().await.unwrap();
+        assert!(engine.get_account(9).is_none());
+        assert!(engine.get_account(3).is_none());
+        assert!(
+            engine
+                .get_transactions()
+                .iter()
+                .all(|entry| [1, 2].contains(&entry.tx.client))
+        );
+        // client 9's tx 2 never existed for the engine: client 1 may use the ID
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.total(), 105.0);
+        assert_eq!(account.held(), 5.0);
+        // and the dispute on client 3's filtered deposit finds nothing
+        let dispute = engine.transactions_for(2)[0];
+        assert_eq!(dispute.status, TransactionStatus::IgnoredMissingReference);
+    }
+
+    #[tokio::test]
+    async fn metrics_time_parsing_and_processing() {
+        let rows: String = (1..=200)
+            .map(|tx| format!("deposit,{},{},1.5\n", tx % 7, tx))
+            .collect();
+        let csv = create_csv(&format!("type,client,tx,amount\n{rows}"));
+        for concurrency in [1, 2] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            let consumer =
+                TransactionConsumer::new(path_rx, Engine::new()).with_concurrency(concurrency);
+            path_tx
+                .send(WorkItem::Path(csv.path().to_path_buf()))
+                .await
+                .unwrap();
+            drop(path_tx);
+
+            let (engine, metrics) = consumer.consume_with_metrics().await.unwrap();
+            assert_eq!(engine.get_transactions().len(), 200);
+            assert!(metrics.parse > Duration::ZERO, "{metrics:?}");
+            assert!(metrics.process > Duration::ZERO, "{metrics:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn comment_lines_are_skipped() {
+        let rows = "# exported 2024-05-01\n\
+                    type,client,tx,amount\n\
+                    # opening balance\n\
+                    deposit,1,1,10.0\n\
+                    #deposit,1,2,99.0\n\
+                    withdrawal,1,3,4.0\n\
+                    # the end\n";
+
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer = TransactionConsumer::new(path_rx, Engine::new());
+        consumer.consume_reader(rows.as_bytes()).await.unwrap();
+        let entries = consumer.engine.get_transactions();
+        assert_eq!(
+            entries.iter().map(|entry| entry.tx.tx).collect::<Vec<_>>(),
+            [1, 3]
+        );
+        assert_eq!(consumer.engine.get_account(1).unwrap().total(), 6.0);
+
+        // reported line numbers are the file's, comment and blank lines included
+        let malformed = validate_reader(
+            &CsvOptions::default(),
+            "# exported\ntype,client,tx,amount\n# note\ndeposit,x,1,1.0\n\n#\n\
+             deposit,1,\"2\",\"multi\n# line\"\r\n\r\n\
+             # note\ndeposit,70000,3,1.0\ndeposit,1,y,1.0\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        let lines: Vec<u64> = malformed
+            .iter()
+            .map(|e| match e {
+                ConsumeError::CsvParse { line, .. }
+                | ConsumeError::ClientOutOfRange { line, .. } => *line,
+                other => panic!("unexpected error: {other}"),
+            })
+            .collect();
+        assert_eq!(lines, [4, 7, 11, 12]);
+        // as counted by the line length limit
+        let options = CsvOptions {
+            max_record_bytes: 30,
+            ..CsvOptions::default()
+        };
+        let too_long = format!("type,client,tx,amount\n# note\n\n{}\n", "x".repeat(31));
+        assert!(matches!(
+            validate_reader(&options, too_long.as_bytes()),
+            Err(ConsumeError::RecordTooLarge { line: 4, .. })
+        ));
+
+        // another comment character, or none: `#` lines are then malformed rows
+        for (comment, parses) in [(Some(b';'), false), (None, false), (Some(b'#'), true)] {
+            let options = CsvOptions {
+                comment,
+                ..CsvOptions::default()
+            };
+            let malformed = validate_reader(&options, rows.as_bytes());
+            assert_eq!(
+                malformed.is_ok_and(|malformed| malformed.is_empty()),
+                parses,
+                "{comment:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn hex_ids_parse_only_in_hex_mode() {
+        let rows = "type,client,tx,amount\ndeposit,0x10,0x00ff,10.0\ndeposit,00042,7,1.0\n";
+
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer =
+            TransactionConsumer::new(path_rx, Engine::new()).with_csv_options(CsvOptions {
+                id_format: IdFormat::Hex,
+                ..CsvOptions::default()
+            });
+        consumer.consume_reader(rows.as_bytes()).await.unwrap();
+        let entries = consumer.engine.get_transactions();
+        assert_eq!((entries[0].tx.client, entries[0].tx.tx), (16, 255));
+        assert_eq!((entries[1].tx.client, entries[1].tx.tx), (42, 7));
+
+        // dec mode (the default) keeps rejecting the prefix
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer = TransactionConsumer::new(path_rx, Engine::new());
+        let err = consumer.consume_reader(rows.as_bytes()).await.unwrap_err();
+        assert!(
+            matches!(err, ConsumeError::CsvParse { line: 2, .. }),
+            "got {err:?}"
+        );
+
+        // a hex ID still has to fit the column's type (a client ID that doesn't is skipped,
+        // see `out_of_range_clients_are_skipped_even_in_strict_mode`)
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer =
+            TransactionConsumer::new(path_rx, Engine::new()).with_csv_options(CsvOptions {
+                id_format: IdFormat::Hex,
+                ..CsvOptions::default()
+            });
+        let too_large = "type,client,tx,amount\ndeposit,1,0x100000000,1.0\n";
+        assert!(consumer.consume_reader(too_large.as_bytes()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn raw_amounts_fail_malformed_amounts_as_invalid() {
+        let rows = "type,client,tx,amount\n\
+                    deposit,1,1, 100.00 \n\
+                    deposit,1,2,abc\n\
+                    withdrawal,1,3,\"12,50\"\n\
+                    withdrawal,1,4,1e1\n\
+                    dispute,1,1,oops\n";
+
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer =
+            TransactionConsumer::new(path_rx, Engine::new()).with_csv_options(CsvOptions {
+                raw_amounts: true,
+                ..CsvOptions::default()
+            });
+        consumer.consume_reader(rows.as_bytes()).await.unwrap();
+
+        let entries = consumer.engine.get_transactions();
+        assert_eq!(entries[0].tx.amount, Some(100.0));
+        assert_eq!(
+            entries.iter().map(|e| e.status).collect::<Vec<_>>(),
+            [
+                TransactionStatus::Applied,
+                TransactionStatus::FailedInvalidAmount,
+                TransactionStatus::FailedInvalidAmount,
+                TransactionStatus::FailedInvalidAmount,
+                // disputes carry no amount, a malformed one doesn't matter
+                TransactionStatus::Applied,
+            ]
+        );
+        assert_eq!(consumer.engine.get_account(1).unwrap().held(), 100.0);
+
+        // by default a malformed amount is a malformed row
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer = TransactionConsumer::new(path_rx, Engine::new());
+        let err = consumer.consume_reader(rows.as_bytes()).await.unwrap_err();
+        assert!(
+            matches!(err, ConsumeError::CsvParse { line: 3, .. }),
+            "got {err:?}"
+        );
+        assert_eq!(consumer.engine.get_account(1).unwrap().total(), 100.0);
+    }
+
+    #[tokio::test]
+    async fn wrong_header_fails_before_any_row() {
+        let csv = create_csv("kind,clientid,id,value\ndeposit,1,1,10.0\n");
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new()).with_strict(false);
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let Err(ConsumeError::BadHeader {
+            expected, found, ..
+        }) = consumer.consume().await
+        else {
+            panic!("expected a bad header error");
+        };
+        assert_eq!(expected, ["type", "client", "tx", "amount"]);
+        assert_eq!(found, ["kind", "clientid", "id", "value"]);
+
+        // columns in another order, or extra ones, are fine
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer = TransactionConsumer::new(path_rx, Engine::new());
+        consumer
+            .consume_reader("client,tx,amount,type,note\n1,1,10.0,deposit,x\n".as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(consumer.engine.get_account(1).unwrap().total(), 10.0);
+    }
+
+    #[tokio::test]
+    async fn out_of_range_clients_are_skipped_even_in_strict_mode() {
+        let csv = create_csv(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,70000,2,5.0\ndeposit,65535,3,2.0\n",
+        );
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new());
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let (engine, _, reports) = consumer.consume_with_reports().await.unwrap();
+        assert_eq!(engine.get_account(1).unwrap().total(), 10.0);
+        assert_eq!(engine.get_account(65535).unwrap().total(), 2.0);
+        assert_eq!(engine.get_accounts().len(), 2);
+        assert_eq!(engine.get_transactions().len(), 2);
+        assert_eq!((reports[0].rows_read, reports[0].rows_applied), (3, 2));
+
+        // a hex ID too large is out of range as well, a malformed one still isn't skipped
+        let options = CsvOptions {
+            id_format: IdFormat::Hex,
+            ..CsvOptions::default()
+        };
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer =
+            TransactionConsumer::new(path_rx, Engine::new()).with_csv_options(options);
+        let rows = "type,client,tx,amount\ndeposit,0x10000,1,1.0\ndeposit,2,2,1.0\n";
+        consumer.consume_reader(rows.as_bytes()).await.unwrap();
+        assert_eq!(consumer.engine.get_accounts().len(), 1);
+        let rows = "type,client,tx,amount\ndeposit,-1,3,1.0\n";
+        assert!(matches!(
+            consumer.consume_reader(rows.as_bytes()).await,
+            Err(ConsumeError::CsvParse { line: 2, .. })
+        ));
+
+        // validation reports the row instead
+        let malformed = validate_file(&CsvOptions::default(), csv.path()).unwrap();
+        assert_eq!(malformed.len(), 1);
+        assert!(
+            matches!(
+                malformed[0],
+                ConsumeError::ClientOutOfRange {
+                    line: 3,
+                    client: 70000,
+                    ..
+                }
+            ),
+            "{}",
+            malformed[0]
+        );
+        assert!(
+            malformed[0]
+                .to_string()
+                .ends_with("at line 3: 70000 is above 65535")
+        );
+    }
+
+    #[tokio::test]
+    async fn oversized_records_fail_instead_of_being_buffered() {
+        let huge_amount = "9".repeat(4 * 1024 * 1024);
+        let csv = create_csv(&format!(
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,{huge_amount}\n"
+        ));
+        let (path_tx, path_rx) = mpsc::channel(10);
+        // lenient mode skips malformed rows, an oversized one still stops the run
+        let consumer = TransactionConsumer::new(path_rx, Engine::new()).with_strict(false);
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let Err(ConsumeError::RecordTooLarge { path, line, limit }) = consumer.consume().await
+        else {
+            panic!("expected a record too large error");
+        };
+        assert_eq!((path.as_path(), line), (csv.path(), 3));
+        assert_eq!(limit, DEFAULT_MAX_RECORD_BYTES);
+
+        // the limit is configurable, and also bounds a record spanning several lines
+        let options = CsvOptions {
+            max_record_bytes: 22,
+            ..CsvOptions::default()
+        };
+        let rows =
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,\"1\n2\n3\n4\n5\n6\n7\n8\"\n";
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer =
+            TransactionConsumer::new(path_rx, Engine::new()).with_csv_options(options);
+        let err = consumer.consume_reader(rows.as_bytes()).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                ConsumeError::RecordTooLarge {
+                    line: 3,
+                    limit: 22,
+                    ..
+                }
+            ),
+            "{err}"
+        );
+        assert_eq!(consumer.engine.get_account(1).unwrap().total(), 10.0);
+    }
+
+    #[tokio::test]
+    async fn no_header_reads_columns_by_position() {
+        let rows = "deposit,1,1,10.0\ndeposit,1,2,5.0\ndispute,1,2,\n";
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer =
+            TransactionConsumer::new(path_rx, Engine::new()).with_csv_options(CsvOptions {
+                has_header: false,
+                ..CsvOptions::default()
+            });
+        consumer.consume_reader(rows.as_bytes()).await.unwrap();
+
+        // the first line is a transaction, not a header
+        let account = consumer.engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 10.0);
+        assert_eq!(account.held(), 5.0);
+    }
+
+    #[tokio::test]
+    async fn non_plain_amount_rows_fail_as_invalid_amounts() {
+        let rows = "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             deposit,1,2,nan\n\
+             deposit,1,3,inf\n\
+             withdrawal,1,4,-inf\n\
+             deposit,1,5,infinity\n\
+             deposit,1,6,1e2\n\
+             withdrawal,1,7,2.5\n";
+
+        // strict mode too: they're rows the engine fails, not malformed ones
+        let (_path_tx, path_rx) = mpsc::channel(10);
+        let mut consumer = TransactionConsumer::new(path_rx, Engine::new());
+        consumer.consume_reader(rows.as_bytes()).await.unwrap();
+        let statuses: Vec<_> = consumer
+            .engine
+            .get_transactions()
+            .iter()
+            .map(|entry| entry.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                TransactionStatus::Applied,
+                TransactionStatus::FailedInvalidAmount,
+                TransactionStatus::FailedInvalidAmount,
+                TransactionStatus::FailedInvalidAmount,
+                TransactionStatus::FailedInvalidAmount,
+                TransactionStatus::FailedInvalidAmount,
+                TransactionStatus::Applied,
+            ]
+        );
+        let account = consumer.engine.get_account(1).unwrap();
+        assert_eq!(account.total(), 7.5);
+        assert!(account.total().is_finite());
+
+        // the error report tells `nan`, `inf` and `1e2` apart, as each row wrote it
+        let errors = rejected_entries_to_csv(consumer.engine.get_transactions());
+        let reported: Vec<(&str, &str)> = errors
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                (fields[3], fields[5])
+            })
+            .collect();
+        assert_eq!(
+            reported,
+            [
+                ("nan", "Amount nan is not a plain decimal"),
+                ("inf", "Amount inf is not a plain decimal"),
+                ("-inf", "Amount -inf is not a plain decimal"),
+                ("infinity", "Amount infinity is not a plain decimal"),
+                ("1e2", "Amount 1e2 is not a plain decimal"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn skip_missing_goes_on_with_the_other_files() {
+        let first = create_csv("type,client,tx,amount\ndeposit,1,1,10.0\n");
+        let second = create_csv("type,client,tx,amount\ndeposit,2,2,20.0\n");
+        let paths = [
+            first.path().to_path_buf(),
+            PathBuf::from("does/not/exist.csv"),
+            second.path().to_path_buf(),
+        ];
+
+        for concurrency in [1, 3] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            let consumer = TransactionConsumer::new(path_rx, Engine::new())
+                .with_skip_missing(true)
+                .with_concurrency(concurrency);
+            for path in &paths {
+                path_tx.send(WorkItem::Path(path.clone())).await.unwrap();
+            }
+            drop(path_tx);
+
+            let engine = consumer.consume().await.unwrap();
+            assert_eq!(engine.get_account(1).unwrap().total(), 10.0);
+            assert_eq!(engine.get_account(2).unwrap().total(), 20.0);
+        }
+
+        // fail-fast stays the default
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new());
+        for path in &paths {
+            path_tx.send(WorkItem::Path(path.clone())).await.unwrap();
+        }
+        drop(path_tx);
+        assert!(matches!(
+            consumer.consume().await,
+            Err(ConsumeError::FileNotFound(_))
+        ));
+    }
+
     #[tokio::test]
     async fn parses_and_processes_valid_csv() {
         let csv = create_csv("type,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,50.0\n");
@@ -48,7 +1551,10 @@ mod tests {
 
         let consumer = TransactionConsumer::new(path_rx, Engine::new());
 
-        path_tx.send(csv.path().to_path_buf()).await.unwrap();
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
         drop(path_tx);
 
         let engine = consumer.consume().await.unwrap();
@@ -64,7 +1570,10 @@ mod tests {
 
         let consumer = TransactionConsumer::new(path_rx, Engine::new());
 
-        path_tx.send(csv.path().to_path_buf()).await.unwrap();
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
         drop(path_tx);
 
         let engine = consumer.consume().await.unwrap();
@@ -80,8 +1589,14 @@ mod tests {
 
         let consumer = TransactionConsumer::new(path_rx, Engine::new());
 
-        path_tx.send(csv1.path().to_path_buf()).await.unwrap();
-        path_tx.send(csv2.path().to_path_buf()).await.unwrap();
+        path_tx
+            .send(WorkItem::Path(csv1.path().to_path_buf()))
+            .await
+            .unwrap();
+        path_tx
+            .send(WorkItem::Path(csv2.path().to_path_buf()))
+            .await
+            .unwrap();
         drop(path_tx);
 
         let engine = consumer.consume().await.unwrap();
@@ -97,7 +1612,10 @@ mod tests {
 
         let consumer = TransactionConsumer::new(path_rx, Engine::new());
 
-        path_tx.send(csv.path().to_path_buf()).await.unwrap();
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
         drop(path_tx);
 
         let engine = consumer.consume().await.unwrap();
@@ -108,7 +1626,7 @@ mod tests {
 
     #[tokio::test]
     async fn exits_when_producer_closes() {
-        let (path_tx, path_rx) = mpsc::channel::<PathBuf>(10);
+        let (path_tx, path_rx) = mpsc::channel::<WorkItem>(10);
 
         let consumer = TransactionConsumer::new(path_rx, Engine::new());
 
@@ -130,7 +1648,10 @@ mod tests {
 
         let consumer = TransactionConsumer::new(path_rx, Engine::new());
 
-        path_tx.send(csv.path().to_path_buf()).await.unwrap();
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
         drop(path_tx);
 
         let engine = consumer.consume().await.unwrap();
@@ -153,7 +1674,10 @@ mod tests {
 
         let consumer = TransactionConsumer::new(path_rx, Engine::new());
 
-        path_tx.send(csv.path().to_path_buf()).await.unwrap();
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
         drop(path_tx);
 
         let engine = consumer.consume().await.unwrap();
@@ -165,4 +1689,516 @@ mod tests {
         let acc2 = engine.get_account(2).unwrap();
         assert_eq!(acc2.available(), 200.0);
     }
+
+    #[tokio::test]
+    async fn sharded_consume_matches_single_engine() {
+        let content = "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             deposit,2,1,50.0\n\
+             deposit,2,2,50.0\n\
+             deposit,3,3,10.0\n\
+             withdrawal,1,4,30.0\n\
+             dispute,1,1,\n\
+             dispute,2,2,\n\
+             chargeback,2,2,\n\
+             deposit,4,4,5.0\n";
+
+        let mut results = Vec::new();
+        for shards in [1, 3] {
+            let csv = create_csv(content);
+            let (path_tx, path_rx) = mpsc::channel(10);
+            let consumer = TransactionConsumer::new(path_rx, Engine::new()).with_shards(shards);
+
+            path_tx
+                .send(WorkItem::Path(csv.path().to_path_buf()))
+                .await
+                .unwrap();
+            drop(path_tx);
+
+            let engine = consumer.consume().await.unwrap();
+            assert_eq!(engine.get_transactions().len(), 9);
+            results.push(engine.get_accounts().clone());
+        }
+        assert_eq!(results[0], results[1]);
+        // tx 1 reused by client 2 on another shard is still a duplicate
+        assert_eq!(results[1].get(&2).unwrap().total(), 0.0);
+        assert_eq!(results[1].get(&4).unwrap().total(), 0.0);
+    }
+
+    #[tokio::test]
+    async fn missing_file_is_reported_as_not_found() {
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new());
+
+        path_tx
+            .send(WorkItem::Path(PathBuf::from("does/not/exist.csv")))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let err = consumer.consume().await.unwrap_err();
+        assert!(
+            matches!(&err, ConsumeError::FileNotFound(path) if path == Path::new("does/not/exist.csv"))
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_row_reports_path_and_line() {
+        let csv = create_csv("type,client,tx,amount\ndeposit,1,1,1.0\nteleport,1,2,1.0\n");
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new());
+
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let err = consumer.consume().await.unwrap_err();
+        match err {
+            ConsumeError::CsvParse { path, line, .. } => {
+                assert_eq!(path, csv.path());
+                assert_eq!(line, 3);
+            }
+            other => panic!("expected CsvParse, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn unreadable_path_is_reported_as_io() {
+        let csv = create_csv("type,client,tx,amount\n");
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new());
+
+        // a regular file used as a directory fails with ENOTDIR rather than NotFound
+        path_tx
+            .send(WorkItem::Path(csv.path().join("nested.csv")))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let err = consumer.consume().await.unwrap_err();
+        assert!(matches!(err, ConsumeError::Io(_)), "got {err:?}");
+        // still converts into anyhow for `main`
+        let _: anyhow::Error = err.into();
+    }
+
+    #[tokio::test]
+    async fn lenient_mode_skips_malformed_rows() {
+        let content = "type,client,tx,amount\n\
+             deposit,1,1,100.0\n\
+             teleport,1,2,5.0\n\
+             withdrawal,1,3,40.0\n";
+
+        let csv = create_csv(content);
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new()).with_strict(false);
+
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let engine = consumer.consume().await.unwrap();
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 60.0);
+        assert_eq!(engine.get_transactions().len(), 2);
+
+        // strict (default) still aborts on the same file
+        let csv = create_csv(content);
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new());
+
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        assert!(consumer.consume().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn processes_records_sent_without_a_file() {
+        let (item_tx, item_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(item_rx, Engine::new());
+
+        let records = [
+            Transaction::new(TransactionType::Deposit, 1, 1, Some(100.0)),
+            Transaction::new(TransactionType::Withdrawal, 1, 2, Some(30.0)),
+            Transaction::new(TransactionType::Dispute, 1, 2, None),
+        ];
+        for tx in records {
+            item_tx.send(WorkItem::Record(tx)).await.unwrap();
+        }
+        drop(item_tx);
+
+        let engine = consumer.consume().await.unwrap();
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 70.0);
+        assert_eq!(account.held(), 30.0);
+        assert_eq!(engine.get_transactions().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn cross_file_dispute_with_mismatched_client_is_ignored() {
+        let first = create_csv("type,client,tx,amount\ndeposit,1,1,100.0\ndeposit,2,2,40.0\n");
+        let second = create_csv("type,client,tx,amount\ndispute,2,1,\ndispute,1,1,\n");
+
+        for shards in [1, 4] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            let consumer = TransactionConsumer::new(path_rx, Engine::new()).with_shards(shards);
+
+            for file in [&first, &second] {
+                path_tx
+                    .send(WorkItem::Path(file.path().to_path_buf()))
+                    .await
+                    .unwrap();
+            }
+            drop(path_tx);
+
+            let engine = consumer.consume().await.unwrap();
+            let mismatched = engine
+                .get_transactions()
+                .iter()
+                .find(|entry| entry.tx._type == TransactionType::Dispute && entry.tx.client == 2)
+                .unwrap();
+            assert_eq!(
+                mismatched.status,
+                TransactionStatus::IgnoredMissingReference,
+                "shards={shards}"
+            );
+
+            // only the owner's dispute from file 2 applied
+            let owner = engine.get_account(1).unwrap();
+            assert_eq!(owner.available(), 0.0);
+            assert_eq!(owner.held(), 100.0);
+            let other = engine.get_account(2).unwrap();
+            assert_eq!(other.available(), 40.0);
+            assert_eq!(other.held(), 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn custom_delimiters_match_comma_results() {
+        let rows = [
+            ["type", "client", "tx", "amount"],
+            ["deposit", "1", "1", "100.0"],
+            ["deposit", "2", "2", "20.0"],
+            ["withdrawal", "1", "3", "30.5"],
+            ["dispute", "2", "2", ""],
+        ];
+
+        let mut results = Vec::new();
+        for delimiter in [b',', b'\t', b';'] {
+            let separator = (delimiter as char).to_string();
+            let content: String = rows.iter().map(|row| row.join(&separator) + "\n").collect();
+            let csv = create_csv(&content);
+            let (path_tx, path_rx) = mpsc::channel(10);
+            let consumer =
+                TransactionConsumer::new(path_rx, Engine::new()).with_csv_options(CsvOptions {
+                    delimiter,
+                    ..CsvOptions::default()
+                });
+
+            path_tx
+                .send(WorkItem::Path(csv.path().to_path_buf()))
+                .await
+                .unwrap();
+            drop(path_tx);
+
+            results.push(consumer.consume().await.unwrap().get_accounts().clone());
+        }
+        assert_eq!(results[0].get(&1).unwrap().available(), 69.5);
+        assert_eq!(results[0], results[1]);
+        assert_eq!(results[0], results[2]);
+    }
+
+    #[tokio::test]
+    async fn leading_utf8_bom_is_ignored() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(
+            b"\xEF\xBB\xBFtype,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,40.0\n",
+        )
+        .unwrap();
+        let (path_tx, path_rx) = mpsc::channel(10);
+        let consumer = TransactionConsumer::new(path_rx, Engine::new());
+
+        path_tx
+            .send(WorkItem::Path(file.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let engine = consumer.consume().await.unwrap();
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 60.0);
+        assert_eq!(account.total(), 60.0);
+    }
+
+    #[tokio::test]
+    async fn gzip_compressed_csv_matches_plain_csv() {
+        let content = "type,client,tx,amount\ndeposit,1,1,100.0\nwithdrawal,1,2,40.0\n\
+                       deposit,2,3,5.0\ndispute,2,3,\n";
+        let plain = create_csv(content);
+        let gzipped = tempfile::Builder::new()
+            .suffix(".csv.gz")
+            .tempfile()
+            .unwrap();
+        let mut encoder =
+            flate2::write::GzEncoder::new(gzipped.as_file(), flate2::Compression::default());
+        encoder.write_all(content.as_bytes()).unwrap();
+        encoder.finish().unwrap();
+
+        let mut results = Vec::new();
+        for file in [&plain, &gzipped] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            path_tx
+                .send(WorkItem::Path(file.path().to_path_buf()))
+                .await
+                .unwrap();
+            drop(path_tx);
+            let engine = TransactionConsumer::new(path_rx, Engine::new())
+                .consume()
+                .await
+                .unwrap();
+            results.push(engine.get_accounts().clone());
+        }
+
+        assert_eq!(results[0].len(), 2);
+        assert_eq!(results[0], results[1]);
+    }
+
+    #[tokio::test]
+    async fn skip_processed_files_ignores_a_repeated_file() {
+        let csv = create_csv("type,client,tx,amount\ndeposit,1,1,100.0\ndispute,1,1,\n");
+        for shards in [1, 4] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            for _ in 0..2 {
+                path_tx
+                    .send(WorkItem::Path(csv.path().to_path_buf()))
+                    .await
+                    .unwrap();
+            }
+            drop(path_tx);
+
+            let engine =
+                TransactionConsumer::new(path_rx, Engine::new().with_skip_processed_files(true))
+                    .with_shards(shards)
+                    .consume()
+                    .await
+                    .unwrap();
+
+            let account = engine.get_account(1).unwrap();
+            assert_eq!(account.available(), 0.0);
+            assert_eq!(account.held(), 100.0);
+            // the second copy never reached the engine
+            assert_eq!(engine.get_transactions().len(), 2);
+            assert!(engine.already_processed(file_hash(csv.path()).unwrap()));
+        }
+    }
+
+    #[tokio::test]
+    async fn extra_trailing_columns_are_ignored() {
+        // a named extra column, and an unnamed one only present on some rows
+        let csv = create_csv(
+            "type,client,tx,amount,batch_id\ndeposit,1,1,10.0,b1\ndeposit,1,2,5.0,b1,x\n\
+             dispute,1,2,,b2\n",
+        );
+        let (path_tx, path_rx) = mpsc::channel(10);
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let engine = TransactionConsumer::new(path_rx, Engine::new())
+            .consume()
+            .await
+            .unwrap();
+        let account = engine.get_account(1).unwrap();
+        assert_eq!(account.available(), 10.0);
+        assert_eq!(account.held(), 5.0);
+    }
+
+    #[tokio::test]
+    async fn missing_amount_column_is_an_error() {
+        let csv = create_csv("type,client,tx\ndeposit,1,1\n");
+        let (path_tx, path_rx) = mpsc::channel(10);
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let err = TransactionConsumer::new(path_rx, Engine::new())
+            .consume()
+            .await
+            .unwrap_err();
+        match err {
+            ConsumeError::BadHeader { found, .. } => assert_eq!(found, ["type", "client", "tx"]),
+            other => panic!("expected BadHeader, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_reads_keep_file_order() {
+        let first = create_csv("type,client,tx,amount\ndeposit,1,1,100.0\ndeposit,2,2,50.0\n");
+        let second = create_csv("type,client,tx,amount\nwithdrawal,2,3,20.0\ndeposit,3,4,1.0\n");
+        let third = create_csv("type,client,tx,amount\ndispute,1,1,\nwithdrawal,2,5,30.0\n");
+
+        for concurrency in [1, 2, 3, 8] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            for file in [&first, &second, &third] {
+                path_tx
+                    .send(WorkItem::Path(file.path().to_path_buf()))
+                    .await
+                    .unwrap();
+            }
+            // records queued behind the files apply after them
+            path_tx
+                .send(WorkItem::Record(Transaction::new(
+                    TransactionType::Resolve,
+                    1,
+                    1,
+                    None,
+                )))
+                .await
+                .unwrap();
+            drop(path_tx);
+
+            let engine = TransactionConsumer::new(path_rx, Engine::new())
+                .with_concurrency(concurrency)
+                .consume()
+                .await
+                .unwrap();
+
+            let statuses: Vec<_> = engine.get_transactions().iter().map(|e| e.status).collect();
+            assert_eq!(
+                statuses,
+                vec![TransactionStatus::Applied; 7],
+                "{concurrency}"
+            );
+            assert_eq!(engine.get_account(1).unwrap().available(), 100.0);
+            assert_eq!(engine.get_account(2).unwrap().available(), 0.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn long_files_yield_to_other_tasks() {
+        let rows: String = (1..=5000)
+            .map(|tx| format!("deposit,1,{tx},1.0\n"))
+            .collect();
+        let csv = create_csv(&format!("type,client,tx,amount\n{rows}"));
+        let (path_tx, path_rx) = mpsc::channel(10);
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        // single-threaded runtime: the task can only run while the consumer yields
+        let ran = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let other = tokio::spawn({
+            let ran = ran.clone();
+            async move { ran.store(true, std::sync::atomic::Ordering::SeqCst) }
+        });
+
+        let engine = TransactionConsumer::new(path_rx, Engine::new())
+            .consume()
+            .await
+            .unwrap();
+        assert!(ran.load(std::sync::atomic::Ordering::SeqCst));
+        other.await.unwrap();
+        assert_eq!(engine.get_account(1).unwrap().total(), 5000.0);
+    }
+
+    #[tokio::test]
+    async fn client_count_follows_distinct_clients() {
+        let rows: String = (1..=300)
+            .map(|tx| format!("deposit,{},{tx},1.0\n", tx / 50))
+            .collect();
+        let csv = create_csv(&format!("type,client,tx,amount\n{rows}"));
+
+        for shards in [1, 3] {
+            let (path_tx, path_rx) = mpsc::channel(10);
+            path_tx
+                .send(WorkItem::Path(csv.path().to_path_buf()))
+                .await
+                .unwrap();
+            drop(path_tx);
+
+            let counts = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let recorded = counts.clone();
+            let engine = TransactionConsumer::new(path_rx, Engine::new())
+                .with_shards(shards)
+                .with_client_count(100, move |count| recorded.lock().unwrap().push(count))
+                .consume()
+                .await
+                .unwrap();
+
+            assert_eq!(engine.client_count(), 7);
+            assert_eq!(*counts.lock().unwrap(), vec![3, 5, 7]);
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_returns_the_work_done_so_far() {
+        let first = create_csv("type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,1,2,5.0\n");
+        let second = create_csv("type,client,tx,amount\ndeposit,2,3,20.0\n");
+        let (path_tx, path_rx) = mpsc::channel(10);
+        // stands in for Ctrl-C
+        let (cancel, cancelled) = tokio::sync::oneshot::channel::<()>();
+        let (processed_tx, mut processed) = mpsc::unbounded_channel();
+
+        let consumer = tokio::spawn(
+            TransactionConsumer::new(path_rx, Engine::new())
+                .with_progress(1, move |count| processed_tx.send(count).unwrap())
+                .with_shutdown(async {
+                    let _ = cancelled.await;
+                })
+                .consume(),
+        );
+        path_tx
+            .send(WorkItem::Path(first.path().to_path_buf()))
+            .await
+            .unwrap();
+        while processed.recv().await != Some(2) {}
+        cancel.send(()).unwrap();
+        // queued after the signal, never processed; the channel stays open
+        path_tx
+            .send(WorkItem::Path(second.path().to_path_buf()))
+            .await
+            .unwrap();
+
+        let engine = consumer.await.unwrap().unwrap();
+        assert_eq!(engine.get_account(1).unwrap().total(), 15.0);
+        assert!(engine.get_account(2).is_none());
+        assert!(path_tx.is_closed());
+    }
+
+    #[tokio::test]
+    async fn progress_callback_fires_every_n_records() {
+        let rows: String = (1..=2500)
+            .map(|tx| format!("deposit,1,{tx},1.0\n"))
+            .collect();
+        let csv = create_csv(&format!("type,client,tx,amount\n{rows}"));
+        let (path_tx, path_rx) = mpsc::channel(10);
+        path_tx
+            .send(WorkItem::Path(csv.path().to_path_buf()))
+            .await
+            .unwrap();
+        drop(path_tx);
+
+        let calls = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let recorded = calls.clone();
+        TransactionConsumer::new(path_rx, Engine::new())
+            .with_progress(1000, move |count| recorded.lock().unwrap().push(count))
+            .consume()
+            .await
+            .unwrap();
+
+        assert_eq!(*calls.lock().unwrap(), vec![1000, 2000]);
+    }
 }
@@ -0,0 +1,255 @@
+use crate::ledger::account::AccountId;
+use std::{fmt::Display, path::PathBuf};
+
+/// Failures that abort `TransactionConsumer::consume`.
+#[derive(Debug)]
+pub enum ConsumeError {
+    FileNotFound(PathBuf),
+    CsvParse {
+        path: PathBuf,
+        line: u64,
+        source: csv::Error,
+    },
+    // a `.jsonl` line that isn't a transaction object
+    JsonParse {
+        path: PathBuf,
+        line: u64,
+        source: serde_json::Error,
+    },
+    // the header doesn't name the columns rows are read by
+    BadHeader {
+        path: PathBuf,
+        expected: Vec<String>,
+        found: Vec<String>,
+    },
+    // a record over `CsvOptions::max_record_bytes`, reading stops there
+    RecordTooLarge {
+        path: PathBuf,
+        line: u64,
+        limit: usize,
+    },
+    // a row whose client ID doesn't fit an `AccountId`. Only reported by `validate_file`,
+    // processing skips the row
+    ClientOutOfRange {
+        path: PathBuf,
+        line: u64,
+        client: u64,
+    },
+    Io(std::io::Error),
+    // a shard task panicked or stopped receiving before the input was exhausted
+    ShardFailed(String),
+}
+
+impl ConsumeError {
+    pub(crate) fn from_csv(path: &std::path::Path, err: csv::Error) -> Self {
+        if !err.is_io_error() {
+            let line = err.position().map(|pos| pos.line()).unwrap_or_default();
+            return Self::CsvParse {
+                path: path.to_path_buf(),
+                line,
+                source: err,
+            };
+        }
+        match err.into_kind() {
+            csv::ErrorKind::Io(io) => Self::from_io(path, io),
+            _ => unreachable!("is_io_error checked above"),
+        }
+    }
+
+    pub(crate) fn from_io(path: &std::path::Path, io: std::io::Error) -> Self {
+        if io.kind() == std::io::ErrorKind::NotFound {
+            Self::FileNotFound(path.to_path_buf())
+        } else if let Some(too_large) = RecordTooLarge::of(&io) {
+            Self::RecordTooLarge {
+                path: path.to_path_buf(),
+                line: too_large.line,
+                limit: too_large.limit,
+            }
+        } else if let Some(out_of_range) = io.get_ref().and_then(|e| e.downcast_ref()) {
+            let ClientOutOfRange { line, client } = *out_of_range;
+            Self::ClientOutOfRange {
+                path: path.to_path_buf(),
+                line,
+                client,
+            }
+        } else {
+            Self::Io(io)
+        }
+    }
+}
+
+impl Display for ConsumeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FileNotFound(path) => write!(f, "File not found: {}", path.display()),
+            Self::CsvParse { path, line, source } => {
+                write!(
+                    f,
+                    "CSV parse error in {} at line {}: {}",
+                    path.display(),
+                    line,
+                    source
+                )
+            }
+            Self::JsonParse { path, line, source } => write!(
+                f,
+                "JSON parse error in {} at line {}: {}",
+                path.display(),
+                line,
+                source
+            ),
+            Self::BadHeader {
+                path,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Bad header in {}: expected columns {}, found {}",
+                path.display(),
+                expected.join(","),
+                found.join(",")
+            ),
+            Self::RecordTooLarge { path, line, limit } => write!(
+                f,
+                "Record too large in {} at line {}: longer than {} bytes",
+                path.display(),
+                line,
+                limit
+            ),
+            Self::ClientOutOfRange { path, line, client } => write!(
+                f,
+                "Client ID out of range in {} at line {}: {} is above {}",
+                path.display(),
+                line,
+                client,
+                AccountId::MAX
+            ),
+            Self::Io(err) => write!(f, "I/O error: {}", err),
+            Self::ShardFailed(reason) => write!(f, "Shard failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConsumeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::CsvParse { source, .. } => Some(source),
+            Self::JsonParse { source, .. } => Some(source),
+            Self::Io(err) => Some(err),
+            Self::FileNotFound(_)
+            | Self::BadHeader { .. }
+            | Self::RecordTooLarge { .. }
+            | Self::ClientOutOfRange { .. }
+            | Self::ShardFailed(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for ConsumeError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// A record over `CsvOptions::max_record_bytes`. The CSV reader fails with it wrapped in a
+/// `std::io::Error` (kind `InvalidData`), `ConsumeError` unwraps it as `RecordTooLarge`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordTooLarge {
+    pub line: u64,
+    pub limit: usize,
+}
+
+impl RecordTooLarge {
+    pub(crate) fn of(err: &std::io::Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl Display for RecordTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Record at line {} is longer than {} bytes",
+            self.line, self.limit
+        )
+    }
+}
+
+impl std::error::Error for RecordTooLarge {}
+
+impl From<RecordTooLarge> for std::io::Error {
+    fn from(err: RecordTooLarge) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// A row whose client ID is a valid number too large for an `AccountId`. Rows fail with it
+/// wrapped in a `std::io::Error` (kind `InvalidData`) so it can be told apart from a
+/// malformed row: the consumer skips it with a warning, strict mode or not.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientOutOfRange {
+    pub line: u64,
+    pub client: u64,
+}
+
+impl Display for ClientOutOfRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Client ID {} at line {} is above {}",
+            self.client,
+            self.line,
+            AccountId::MAX
+        )
+    }
+}
+
+impl std::error::Error for ClientOutOfRange {}
+
+impl From<ClientOutOfRange> for std::io::Error {
+    fn from(err: ClientOutOfRange) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Failures surfaced by `TransactionProducer`.
+#[derive(Debug)]
+pub enum ProducerError {
+    // the receiving side of the channel was dropped, nothing will process the work item
+    ConsumerGone,
+    // a path that doesn't exist or isn't a file, see `TransactionProducer::with_path_check`
+    Unreadable(PathBuf),
+    // listing an input directory failed
+    Io(std::io::Error),
+}
+
+impl Display for ProducerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ConsumerGone => write!(f, "Consumer is gone, the channel is closed"),
+            Self::Unreadable(path) => write!(f, "Unreadable input file: {}", path.display()),
+            Self::Io(err) => write!(f, "I/O error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for ProducerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::ConsumerGone | Self::Unreadable(_) => None,
+            Self::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProducerError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ProducerError {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        Self::ConsumerGone
+    }
+}
@@ -1,2 +1,541 @@
 pub mod consumer;
+pub mod error;
 pub mod producer;
+mod shard;
+
+use crate::ledger::{
+    account::AccountId,
+    parse_plain_decimal,
+    transaction::{HexIdTransaction, Transaction, parse_id},
+};
+use crate::processing::error::{ClientOutOfRange, ConsumeError, RecordTooLarge};
+use log::warn;
+use serde_json::Value;
+use std::{
+    collections::{HashSet, VecDeque},
+    io::{BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+
+/// Payload flowing from the producer to the consumer: a file to read, the process's stdin
+/// (read as CSV by the consumer, like a file), or a transaction that was already parsed and
+/// has no file behind it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WorkItem {
+    Path(PathBuf),
+    Stdin,
+    Record(Transaction),
+}
+
+/// Sending side of the producer -> consumer channel, bounded (the producer waits when it's
+/// full) or unbounded (never waits, the queue grows as needed).
+#[derive(Debug, Clone)]
+pub enum WorkSender {
+    Bounded(mpsc::Sender<WorkItem>),
+    Unbounded(mpsc::UnboundedSender<WorkItem>),
+}
+
+impl WorkSender {
+    pub async fn send(&self, item: WorkItem) -> Result<(), mpsc::error::SendError<WorkItem>> {
+        match self {
+            Self::Bounded(tx) => tx.send(item).await,
+            Self::Unbounded(tx) => tx.send(item),
+        }
+    }
+}
+
+impl From<mpsc::Sender<WorkItem>> for WorkSender {
+    fn from(tx: mpsc::Sender<WorkItem>) -> Self {
+        Self::Bounded(tx)
+    }
+}
+
+impl From<mpsc::UnboundedSender<WorkItem>> for WorkSender {
+    fn from(tx: mpsc::UnboundedSender<WorkItem>) -> Self {
+        Self::Unbounded(tx)
+    }
+}
+
+/// Receiving side of the producer -> consumer channel, see `WorkSender`.
+#[derive(Debug)]
+pub enum WorkReceiver {
+    Bounded(mpsc::Receiver<WorkItem>),
+    Unbounded(mpsc::UnboundedReceiver<WorkItem>),
+}
+
+impl WorkReceiver {
+    pub async fn recv(&mut self) -> Option<WorkItem> {
+        match self {
+            Self::Bounded(rx) => rx.recv().await,
+            Self::Unbounded(rx) => rx.recv().await,
+        }
+    }
+}
+
+impl From<mpsc::Receiver<WorkItem>> for WorkReceiver {
+    fn from(rx: mpsc::Receiver<WorkItem>) -> Self {
+        Self::Bounded(rx)
+    }
+}
+
+impl From<mpsc::UnboundedReceiver<WorkItem>> for WorkReceiver {
+    fn from(rx: mpsc::UnboundedReceiver<WorkItem>) -> Self {
+        Self::Unbounded(rx)
+    }
+}
+
+/// How input CSV is read, shared by every file (and stdin) in a run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvOptions {
+    pub delimiter: u8,
+    pub id_format: IdFormat,
+    /// Without a header, columns are read by position: `type,client,tx,amount[,currency]`.
+    pub has_header: bool,
+    /// Reads `amount` as raw text and parses it separately: a malformed value (`12,50`,
+    /// `abc`) becomes a missing amount, so a deposit/withdrawal fails with
+    /// `FailedInvalidAmount` instead of the whole row being a parse error.
+    pub raw_amounts: bool,
+    /// Longest record read, in bytes: reading fails with `RecordTooLarge` past it rather than
+    /// buffering an arbitrarily large field.
+    pub max_record_bytes: usize,
+    /// Lines starting with this byte are comments and skipped, `#` by default. `None` reads
+    /// every line as a record. Line numbers in errors still count comment lines, they're
+    /// those of the file.
+    pub comment: Option<u8>,
+}
+
+/// Default `CsvOptions::max_record_bytes`, far above any real row.
+pub const DEFAULT_MAX_RECORD_BYTES: usize = 64 * 1024;
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: b',',
+            id_format: IdFormat::default(),
+            has_header: true,
+            raw_amounts: false,
+            max_record_bytes: DEFAULT_MAX_RECORD_BYTES,
+            comment: Some(b'#'),
+        }
+    }
+}
+
+/// How the `client` and `tx` columns are written.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum IdFormat {
+    /// Plain decimal integers, `0x1F` is a malformed row.
+    #[default]
+    Dec,
+    /// Also accepts `0x`-prefixed hexadecimal IDs; unprefixed ones are still decimal.
+    Hex,
+}
+
+impl CsvOptions {
+    pub(crate) fn reader<R: Read>(&self, source: R) -> csv::Reader<LimitedLines<R>> {
+        self.reader_builder().from_reader(LimitedLines::new(
+            source,
+            self.max_record_bytes,
+            self.comment,
+        ))
+    }
+
+    fn reader_builder(&self) -> csv::ReaderBuilder {
+        // NOTE: the csv reader already skips a leading UTF-8 BOM (Excel exports), so the
+        // header is read as `type` rather than `\u{feff}type` - covered by the consumer tests
+        let mut builder = csv::ReaderBuilder::new();
+        // trim whitespace fix
+        builder
+            .trim(csv::Trim::All)
+            .delimiter(self.delimiter)
+            .has_headers(self.has_header)
+            .comment(self.comment);
+        // upstream exports may append columns after `amount`, they're ignored. Rows missing a
+        // required column still fail to deserialize
+        builder.flexible(true);
+        builder
+    }
+
+    /// Checks the header names the columns rows are read by, so a file with other column
+    /// names fails once with `BadHeader` instead of on every row. Nothing to check without a
+    /// header, or in an empty file.
+    pub(crate) fn check_header<R: Read>(
+        &self,
+        reader: &mut csv::Reader<R>,
+        path: &Path,
+    ) -> Result<(), ConsumeError> {
+        if !self.has_header {
+            return Ok(());
+        }
+        let found = reader
+            .headers()
+            .map_err(|e| ConsumeError::from_csv(path, e))?;
+        if found.is_empty()
+            || REQUIRED_COLUMNS
+                .iter()
+                .all(|c| found.iter().any(|f| f == *c))
+        {
+            return Ok(());
+        }
+        Err(ConsumeError::BadHeader {
+            path: path.to_path_buf(),
+            expected: REQUIRED_COLUMNS.map(String::from).to_vec(),
+            found: found.iter().map(String::from).collect(),
+        })
+    }
+
+    /// Deserializes the reader's rows as transactions, reading IDs per `id_format`.
+    pub(crate) fn transactions<'r, R: Read>(
+        &self,
+        reader: &'r mut csv::Reader<LimitedLines<R>>,
+    ) -> Rows<csv::StringRecordsIter<'r, LimitedLines<R>>> {
+        let headers = self.headers(reader);
+        let lines = reader.get_ref().lines.clone();
+        self.rows(headers, lines, reader.records())
+    }
+
+    /// `transactions`, taking the reader along so the rows can outlive this scope.
+    pub(crate) fn owned_transactions<R: Read>(
+        &self,
+        mut reader: csv::Reader<LimitedLines<R>>,
+    ) -> Rows<csv::StringRecordsIntoIter<LimitedLines<R>>> {
+        let headers = self.headers(&mut reader);
+        let lines = reader.get_ref().lines.clone();
+        self.rows(headers, lines, reader.into_records())
+    }
+
+    /// Reads `source` as JSON lines: one transaction object per line, keyed by the CSV column
+    /// names (`{"type":"deposit","client":1,"tx":1,"amount":"2.5"}`). IDs and amounts may be
+    /// JSON numbers or strings, `amount` may be left out. `id_format`, `raw_amounts` and
+    /// `max_record_bytes` apply as they do to CSV, blank lines are skipped.
+    pub(crate) fn json_lines<R: Read>(&self, source: R, path: &Path) -> JsonLines<R> {
+        JsonLines {
+            lines: BufReader::new(LimitedLines::new(source, self.max_record_bytes, None)),
+            options: *self,
+            path: path.to_path_buf(),
+            line: 0,
+        }
+    }
+
+    fn headers<R: Read>(&self, reader: &mut csv::Reader<R>) -> Option<csv::StringRecord> {
+        if self.has_header {
+            reader.headers().ok().cloned()
+        } else {
+            None
+        }
+    }
+
+    fn rows<I>(
+        &self,
+        headers: Option<csv::StringRecord>,
+        lines: Arc<Mutex<LineIndex>>,
+        records: I,
+    ) -> Rows<I> {
+        let column = |name: &str, position: usize| match &headers {
+            Some(headers) => headers.iter().position(|column| column == name),
+            None => Some(position),
+        };
+        let client_column = column("client", 1);
+        let amount_column = column("amount", 3);
+        Rows {
+            records,
+            lines,
+            client_column,
+            headers,
+            id_format: self.id_format,
+            amount_column: amount_column.filter(|_| self.raw_amounts),
+            max_record_bytes: self.max_record_bytes,
+        }
+    }
+}
+
+// columns a header must name, in any order. Others (`currency`, upstream extras) are fine
+const REQUIRED_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
+
+pub(crate) struct Rows<I> {
+    records: I,
+    lines: Arc<Mutex<LineIndex>>,
+    client_column: Option<usize>,
+    headers: Option<csv::StringRecord>,
+    id_format: IdFormat,
+    // only set with `raw_amounts`
+    amount_column: Option<usize>,
+    max_record_bytes: usize,
+}
+
+impl<I> Rows<I> {
+    // numbers the record by the file's lines, comment and blank ones included
+    fn number_by_file_line(&self, record: &mut csv::StringRecord) {
+        let Some(position) = record.position() else {
+            return;
+        };
+        let mut position = position.clone();
+        let line = self
+            .lines
+            .lock()
+            .expect("line index lock")
+            .line_at(position.byte());
+        position.set_line(line);
+        record.set_position(Some(position));
+    }
+
+    // a client ID too large for an `AccountId` fails the row with `ClientOutOfRange` rather
+    // than as a malformed row, so it can be skipped on its own
+    fn client_out_of_range(&self, record: &csv::StringRecord) -> Option<ClientOutOfRange> {
+        let client = parse_id(
+            record.get(self.client_column?)?,
+            self.id_format == IdFormat::Hex,
+        )?;
+        (client > AccountId::MAX as u64).then(|| ClientOutOfRange {
+            line: record.position().map(|pos| pos.line()).unwrap_or_default(),
+            client,
+        })
+    }
+
+    // blanks a malformed amount so the row deserializes with no amount
+    fn blank_malformed_amount(&self, record: csv::StringRecord) -> csv::StringRecord {
+        let Some(column) = self.amount_column else {
+            return record;
+        };
+        match record.get(column) {
+            Some(amount) if !amount.is_empty() && parse_plain_decimal(amount).is_none() => {
+                let line = record.position().map(|pos| pos.line()).unwrap_or_default();
+                warn!(
+                    "Invalid amount {:?} at line {}, read as missing",
+                    amount, line
+                );
+                let mut blanked: csv::StringRecord = record
+                    .iter()
+                    .enumerate()
+                    .map(|(i, field)| if i == column { "" } else { field })
+                    .collect();
+                blanked.set_position(record.position().cloned());
+                blanked
+            }
+            _ => record,
+        }
+    }
+}
+
+impl<I: Iterator<Item = csv::Result<csv::StringRecord>>> Iterator for Rows<I> {
+    type Item = Result<Transaction, csv::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let record = match self.records.next()?.map(|mut record| {
+            self.number_by_file_line(&mut record);
+            record
+        }) {
+            // a quoted field may span lines, `LimitedLines` only bounds each one
+            Ok(record) if record.as_byte_record().as_slice().len() > self.max_record_bytes => {
+                let line = record.position().map(|pos| pos.line()).unwrap_or_default();
+                let too_large = RecordTooLarge {
+                    line,
+                    limit: self.max_record_bytes,
+                };
+                return Some(Err(std::io::Error::from(too_large).into()));
+            }
+            Ok(record) => match self.client_out_of_range(&record) {
+                Some(out_of_range) => return Some(Err(std::io::Error::from(out_of_range).into())),
+                None => self.blank_malformed_amount(record),
+            },
+            Err(e) => return Some(Err(e)),
+        };
+        let headers = self.headers.as_ref();
+        Some(match self.id_format {
+            IdFormat::Dec => record.deserialize(headers),
+            IdFormat::Hex => record
+                .deserialize::<HexIdTransaction>(headers)
+                .map(Transaction::from),
+        })
+    }
+}
+
+pub(crate) struct JsonLines<R> {
+    lines: BufReader<LimitedLines<R>>,
+    options: CsvOptions,
+    path: PathBuf,
+    line: u64,
+}
+
+impl<R: Read> JsonLines<R> {
+    fn transaction(&self, text: &str) -> Result<Transaction, ConsumeError> {
+        let parse_error = |source| ConsumeError::JsonParse {
+            path: self.path.clone(),
+            line: self.line,
+            source,
+        };
+        let mut row: serde_json::Map<String, Value> =
+            serde_json::from_str(text).map_err(parse_error)?;
+        // the fields deserialize from text like CSV fields do, a number reads as its digits
+        for value in row.values_mut() {
+            if let Value::Number(number) = value {
+                *value = Value::String(number.to_string());
+            }
+        }
+        row.entry("amount").or_insert(Value::Null);
+        let hex = self.options.id_format == IdFormat::Hex;
+        if let Some(client) = row
+            .get("client")
+            .and_then(Value::as_str)
+            .and_then(|client| parse_id(client, hex))
+            && client > AccountId::MAX as u64
+        {
+            return Err(ConsumeError::ClientOutOfRange {
+                path: self.path.clone(),
+                line: self.line,
+                client,
+            });
+        }
+        if self.options.raw_amounts
+            && let Some(Value::String(amount)) = row.get("amount")
+            && !amount.is_empty()
+            && parse_plain_decimal(amount).is_none()
+        {
+            warn!(
+                "Invalid amount {:?} at line {}, read as missing",
+                amount, self.line
+            );
+            row.insert("amount".to_string(), Value::Null);
+        }
+        let row = Value::Object(row);
+        match self.options.id_format {
+            IdFormat::Dec => serde_json::from_value(row),
+            IdFormat::Hex => serde_json::from_value::<HexIdTransaction>(row).map(Transaction::from),
+        }
+        .map_err(parse_error)
+    }
+}
+
+impl<R: Read> Iterator for JsonLines<R> {
+    type Item = Result<Transaction, ConsumeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut text = String::new();
+        while text.trim().is_empty() {
+            text.clear();
+            match self.lines.read_line(&mut text) {
+                Ok(0) => return None,
+                Ok(_) => self.line += 1,
+                Err(e) => return Some(Err(ConsumeError::from_io(&self.path, e))),
+            }
+        }
+        Some(self.transaction(&text))
+    }
+}
+
+// fails the read once a line runs past `limit` bytes, before the CSV reader has buffered it
+// whole: an enormous field is an error instead of an allocation. Also notes where each line
+// starts, see `LineIndex`
+pub(crate) struct LimitedLines<R> {
+    inner: R,
+    limit: usize,
+    line: u64,
+    line_len: usize,
+    comment: Option<u8>,
+    // bytes read so far
+    offset: u64,
+    // where the current line starts, until its first byte is read
+    line_start: Option<u64>,
+    lines: Arc<Mutex<LineIndex>>,
+}
+
+impl<R> LimitedLines<R> {
+    fn new(inner: R, limit: usize, comment: Option<u8>) -> Self {
+        Self {
+            inner,
+            limit,
+            line: 1,
+            line_len: 0,
+            comment,
+            offset: 0,
+            line_start: Some(0),
+            lines: Arc::default(),
+        }
+    }
+}
+
+impl<R: Read> Read for LimitedLines<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let mut lines = self.lines.lock().expect("line index lock");
+        for (i, segment) in buf[..n].split(|&b| b == b'\n').enumerate() {
+            if i > 0 {
+                // an empty line ends before its first byte
+                if let Some(start) = self.line_start {
+                    lines.starts.push_back((start, true));
+                }
+                self.offset += 1;
+                self.line_start = Some(self.offset);
+                self.line += 1;
+                self.line_len = 0;
+            }
+            if let (Some(start), Some(&first)) = (self.line_start, segment.first()) {
+                let skipped = first == b'\r' || Some(first) == self.comment;
+                lines.starts.push_back((start, skipped));
+                self.line_start = None;
+            }
+            self.offset += segment.len() as u64;
+            self.line_len += segment.len();
+            if self.line_len > self.limit {
+                return Err(RecordTooLarge {
+                    line: self.line,
+                    limit: self.limit,
+                }
+                .into());
+            }
+        }
+        Ok(n)
+    }
+}
+
+// The csv reader's positions leave comment and blank lines out: a record's position is where
+// the previous one ended (before or on its last `\n`), and its line number doesn't count the
+// lines skipped in between. So records are numbered from the line starts `LimitedLines`
+// notes: the line the position falls on, past the lines the reader skips. `Rows` drops the
+// lines its records have passed, so only those read ahead are kept
+#[derive(Debug, Default)]
+pub(crate) struct LineIndex {
+    // (byte offset, whether the csv reader skips the line) of the lines not passed yet
+    starts: VecDeque<(u64, bool)>,
+    passed: u64,
+}
+
+impl LineIndex {
+    fn line_at(&mut self, byte: u64) -> u64 {
+        // after a `\r\n` terminator, the position is still on the `\n`
+        while self
+            .starts
+            .get(1)
+            .is_some_and(|&(start, _)| start <= byte + 1)
+        {
+            self.starts.pop_front();
+            self.passed += 1;
+        }
+        while let Some(&(start, true)) = self.starts.front()
+            && start >= byte
+        {
+            self.starts.pop_front();
+            self.passed += 1;
+        }
+        self.passed + 1
+    }
+}
+
+/// Which clients' transactions the consumer hands to the engine, for scoped reprocessing.
+/// Filtered transactions never reach the engine: no account, no event log entry, and
+/// disputes referencing them find nothing. The default lets every client through.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientFilter {
+    /// When set, only these clients pass.
+    pub only: Option<HashSet<AccountId>>,
+    /// These clients never pass, even if listed in `only`.
+    pub exclude: HashSet<AccountId>,
+}
+
+impl ClientFilter {
+    pub fn permits(&self, client: AccountId) -> bool {
+        self.only.as_ref().is_none_or(|only| only.contains(&client))
+            && !self.exclude.contains(&client)
+    }
+}
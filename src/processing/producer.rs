@@ -1,24 +1,121 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::sync::mpsc;
 
+use crate::{
+    ledger::transaction::Transaction,
+    processing::{WorkItem, WorkReceiver, WorkSender, error::ProducerError},
+};
+
+pub const DEFAULT_CHANNEL_CAPACITY: usize = 100;
+
 pub struct TransactionProducer {
-    tx: mpsc::Sender<PathBuf>,
+    tx: WorkSender,
+    check_paths: bool,
 }
 
 impl TransactionProducer {
-    pub fn new(tx: mpsc::Sender<PathBuf>) -> Self {
-        Self { tx }
+    pub fn new(tx: impl Into<WorkSender>) -> Self {
+        Self {
+            tx: tx.into(),
+            check_paths: false,
+        }
+    }
+
+    /// Creates the bounded channel as well, returning the receiver for the consumer.
+    /// `capacity` is how many work items can be in flight before `produce` waits.
+    pub fn with_capacity(capacity: usize) -> (Self, WorkReceiver) {
+        let (tx, rx) = mpsc::channel(capacity);
+        (Self::new(tx), rx.into())
+    }
+
+    /// Creates an unbounded channel instead: `produce` never waits on the consumer, at the
+    /// cost of queueing every work item it hasn't taken yet in memory.
+    pub fn unbounded() -> (Self, WorkReceiver) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self::new(tx), rx.into())
+    }
+
+    /// With `check`, `produce` opens the file before sending it and fails with
+    /// `ProducerError::Unreadable` when it can't be opened (missing, no read permission) or
+    /// is a directory, rather than the consumer failing once it gets there. Off by default:
+    /// paths are sent as they are.
+    pub fn with_path_check(mut self, check: bool) -> Self {
+        self.check_paths = check;
+        self
+    }
+
+    pub async fn produce(&mut self, transaction_file: String) -> Result<(), ProducerError> {
+        let path = PathBuf::from(transaction_file);
+        if self.check_paths && !is_readable_file(&path).await {
+            return Err(ProducerError::Unreadable(path));
+        }
+        self.tx.send(WorkItem::Path(path)).await?;
+        Ok(())
+    }
+
+    /// Produces every `.csv` (or `.csv.gz`) file in `dir`, sorted by path so that disputes
+    /// referencing an earlier file apply after it. With `recursive`, subdirectories are
+    /// included too, their files sorted along with the rest by full path.
+    pub async fn produce_dir(&mut self, dir: &Path, recursive: bool) -> Result<(), ProducerError> {
+        for file in csv_files(dir, recursive)? {
+            self.tx.send(WorkItem::Path(file)).await?;
+        }
+        Ok(())
+    }
+
+    pub async fn produce_record(&mut self, transaction: Transaction) -> Result<(), ProducerError> {
+        self.tx.send(WorkItem::Record(transaction)).await?;
+        Ok(())
     }
 
-    pub async fn produce(&mut self, transaction_file: String) -> anyhow::Result<()> {
-        self.tx.send(PathBuf::from(transaction_file)).await?;
+    /// Has the consumer read stdin as CSV at this point of the input, with the same options
+    /// and strictness as files.
+    pub async fn produce_stdin(&mut self) -> Result<(), ProducerError> {
+        self.tx.send(WorkItem::Stdin).await?;
         Ok(())
     }
 }
 
+// opens the file and drops it right away: permissions are only enforced on open
+async fn is_readable_file(path: &Path) -> bool {
+    let Ok(file) = tokio::fs::File::open(path).await else {
+        return false;
+    };
+    // directories open fine on unix
+    file.metadata()
+        .await
+        .is_ok_and(|metadata| !metadata.is_dir())
+}
+
+/// The `.csv` and `.csv.gz` files `produce_dir` produces from `dir`, in the same order.
+pub fn csv_files(dir: &Path, recursive: bool) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    collect_csv_files(dir, recursive, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+
+fn collect_csv_files(dir: &Path, recursive: bool, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            if recursive {
+                collect_csv_files(&path, recursive, files)?;
+            }
+        } else if path
+            .to_str()
+            .is_some_and(|p| p.ends_with(".csv") || p.ends_with(".csv.gz"))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::ledger::transaction::TransactionType;
 
     #[tokio::test]
     async fn sends_path_through_channel() {
@@ -28,7 +125,7 @@ mod tests {
         producer.produce("test.csv".to_string()).await.unwrap();
 
         let received = rx.recv().await.unwrap();
-        assert_eq!(received, PathBuf::from("test.csv"));
+        assert_eq!(received, WorkItem::Path(PathBuf::from("test.csv")));
     }
 
     #[tokio::test]
@@ -40,18 +137,172 @@ mod tests {
         producer.produce("file2.csv".to_string()).await.unwrap();
         producer.produce("file3.csv".to_string()).await.unwrap();
 
-        assert_eq!(rx.recv().await.unwrap(), PathBuf::from("file1.csv"));
-        assert_eq!(rx.recv().await.unwrap(), PathBuf::from("file2.csv"));
-        assert_eq!(rx.recv().await.unwrap(), PathBuf::from("file3.csv"));
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            WorkItem::Path(PathBuf::from("file1.csv"))
+        );
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            WorkItem::Path(PathBuf::from("file2.csv"))
+        );
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            WorkItem::Path(PathBuf::from("file3.csv"))
+        );
     }
 
     #[tokio::test]
     async fn channel_closes_on_drop() {
-        let (tx, mut rx) = mpsc::channel::<PathBuf>(10);
+        let (tx, mut rx) = mpsc::channel::<WorkItem>(10);
         let producer = TransactionProducer::new(tx);
 
         drop(producer);
 
         assert!(rx.recv().await.is_none());
     }
+
+    #[tokio::test]
+    async fn sends_records_and_stdin_in_order() {
+        let (tx, mut rx) = mpsc::channel(10);
+        let mut producer = TransactionProducer::new(tx);
+
+        let deposit = Transaction::new(TransactionType::Deposit, 1, 1, Some(5.0));
+        producer.produce_record(deposit).await.unwrap();
+        producer.produce_stdin().await.unwrap();
+        drop(producer);
+
+        assert_eq!(rx.recv().await.unwrap(), WorkItem::Record(deposit));
+        assert_eq!(rx.recv().await.unwrap(), WorkItem::Stdin);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn with_capacity_bounds_in_flight_items() {
+        let (mut producer, mut rx) = TransactionProducer::with_capacity(2);
+
+        producer.produce("file1.csv".to_string()).await.unwrap();
+        producer.produce("file2.csv".to_string()).await.unwrap();
+        let WorkSender::Bounded(tx) = &producer.tx else {
+            panic!("with_capacity makes a bounded channel");
+        };
+        assert!(tx.try_send(WorkItem::Path("file3.csv".into())).is_err());
+
+        assert_eq!(
+            rx.recv().await.unwrap(),
+            WorkItem::Path(PathBuf::from("file1.csv"))
+        );
+    }
+
+    #[tokio::test]
+    async fn dropped_consumer_is_a_typed_error() {
+        let (mut producer, rx) = TransactionProducer::with_capacity(DEFAULT_CHANNEL_CAPACITY);
+        drop(rx);
+
+        let err = producer.produce("file1.csv".to_string()).await.unwrap_err();
+        assert!(matches!(err, ProducerError::ConsumerGone));
+    }
+
+    #[tokio::test]
+    async fn path_check_fails_fast_on_missing_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let existing = dir.path().join("in.csv");
+        std::fs::write(&existing, "type,client,tx,amount\n").unwrap();
+        let missing = dir.path().join("missing.csv");
+
+        let (producer, mut rx) = TransactionProducer::with_capacity(DEFAULT_CHANNEL_CAPACITY);
+        let mut producer = producer.with_path_check(true);
+        for path in [&existing, &missing] {
+            let produced = producer.produce(path.display().to_string()).await;
+            assert_eq!(produced.is_ok(), path == &existing);
+        }
+        let err = producer
+            .produce(dir.path().display().to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProducerError::Unreadable(ref path) if path == dir.path()));
+        drop(producer);
+        assert_eq!(rx.recv().await, Some(WorkItem::Path(existing)));
+        assert_eq!(rx.recv().await, None);
+
+        // lazy, the missing file is left for the consumer to report
+        let (mut producer, mut rx) = TransactionProducer::with_capacity(DEFAULT_CHANNEL_CAPACITY);
+        producer
+            .produce(missing.display().to_string())
+            .await
+            .unwrap();
+        assert_eq!(rx.recv().await, Some(WorkItem::Path(missing)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn path_check_fails_on_files_without_read_permission() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("in.csv");
+        std::fs::write(&path, "type,client,tx,amount\n").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o200)).unwrap();
+        if std::fs::File::open(&path).is_ok() {
+            // running as root, permissions aren't enforced
+            return;
+        }
+
+        let (producer, _rx) = TransactionProducer::with_capacity(DEFAULT_CHANNEL_CAPACITY);
+        let err = producer
+            .with_path_check(true)
+            .produce(path.display().to_string())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, ProducerError::Unreadable(ref unreadable) if unreadable == &path));
+    }
+
+    #[tokio::test]
+    async fn produces_directory_files_in_sorted_order() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("nested")).unwrap();
+        for name in ["b.csv", "a.csv", "notes.txt", "nested/0.csv", "c.csv.gz"] {
+            std::fs::write(dir.path().join(name), "").unwrap();
+        }
+
+        for (recursive, expected) in [
+            (false, vec!["a.csv", "b.csv", "c.csv.gz"]),
+            (true, vec!["a.csv", "b.csv", "c.csv.gz", "nested/0.csv"]),
+        ] {
+            let (tx, mut rx) = mpsc::channel(10);
+            let mut producer = TransactionProducer::new(tx);
+            producer.produce_dir(dir.path(), recursive).await.unwrap();
+            drop(producer);
+
+            let mut produced = Vec::new();
+            while let Some(item) = rx.recv().await {
+                produced.push(item);
+            }
+            let expected: Vec<_> = expected
+                .into_iter()
+                .map(|name| WorkItem::Path(dir.path().join(name)))
+                .collect();
+            assert_eq!(produced, expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn unbounded_channel_never_waits_for_the_consumer() {
+        let (mut producer, mut rx) = TransactionProducer::unbounded();
+
+        // far past any bounded capacity, with nothing receiving meanwhile
+        for i in 0..DEFAULT_CHANNEL_CAPACITY * 50 {
+            producer.produce(format!("file{i}.csv")).await.unwrap();
+        }
+        drop(producer);
+
+        let mut received = 0;
+        while let Some(item) = rx.recv().await {
+            assert_eq!(
+                item,
+                WorkItem::Path(PathBuf::from(format!("file{received}.csv")))
+            );
+            received += 1;
+        }
+        assert_eq!(received, DEFAULT_CHANNEL_CAPACITY * 50);
+    }
 }
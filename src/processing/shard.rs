@@ -0,0 +1,120 @@
+use std::collections::HashSet;
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::{
+    ledger::{account::AccountId, engine::Engine, transaction::Transaction},
+    processing::error::ConsumeError,
+};
+
+enum ShardCommand {
+    // the tx ID was already claimed by a transaction routed to another shard
+    Reserve(u32),
+    // carries the global sequence index the transaction was read at
+    Process(Transaction, u64),
+}
+
+/// Routes transactions to engines running on their own tasks, keyed by `client % shards`.
+pub(crate) struct ShardRouter {
+    senders: Vec<mpsc::Sender<ShardCommand>>,
+    handles: Vec<JoinHandle<Engine>>,
+    // tx IDs are globally unique, so the duplicate index has to live in front of the shards
+    claimed_tx_ids: HashSet<u32>,
+    // the shards never see files, hashes recorded during the run are handed back in `finish`
+    processed_files: HashSet<u64>,
+    // every transaction opens its client's account, so the clients sent so far (and those
+    // the engine already had) are the shards' accounts
+    clients: HashSet<AccountId>,
+    next_seq: u64,
+}
+
+impl ShardRouter {
+    pub(crate) fn spawn(engine: Engine, shards: usize) -> Self {
+        let next_seq = engine.seq();
+        let processed_files = engine.processed_files().clone();
+        let clients = engine.get_accounts().keys().copied().collect();
+        let (senders, handles) = engine
+            .into_shards(shards)
+            .into_iter()
+            .map(|mut engine| {
+                let (tx, mut rx) = mpsc::channel::<ShardCommand>(1024);
+                let handle = tokio::spawn(async move {
+                    while let Some(command) = rx.recv().await {
+                        match command {
+                            ShardCommand::Reserve(tx_id) => engine.reserve_tx_id(tx_id),
+                            ShardCommand::Process(tx, seq) => {
+                                engine.sync_seq(seq);
+                                engine.process(tx);
+                            }
+                        }
+                    }
+                    engine
+                });
+                (tx, handle)
+            })
+            .unzip();
+        Self {
+            senders,
+            handles,
+            claimed_tx_ids: HashSet::new(),
+            processed_files,
+            clients,
+            next_seq,
+        }
+    }
+
+    pub(crate) async fn submit(&mut self, tx: Transaction) -> Result<(), ConsumeError> {
+        let shard_gone = |_| ConsumeError::ShardFailed("shard stopped receiving".to_string());
+        let shard = &self.senders[tx.client as usize % self.senders.len()];
+        if tx._type.introduces_tx_id() && !self.claimed_tx_ids.insert(tx.tx) {
+            shard
+                .send(ShardCommand::Reserve(tx.tx))
+                .await
+                .map_err(shard_gone)?;
+        }
+        self.clients.insert(tx.client);
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        shard
+            .send(ShardCommand::Process(tx, seq))
+            .await
+            .map_err(shard_gone)
+    }
+
+    /// Sequence index the next submitted transaction gets.
+    pub(crate) fn seq(&self) -> u64 {
+        self.next_seq
+    }
+
+    pub(crate) fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Returns `false` if the hash was already recorded.
+    pub(crate) fn record_processed_file(&mut self, hash: u64) -> bool {
+        self.processed_files.insert(hash)
+    }
+
+    /// Closes the shard channels, waits for every shard and merges them back into one engine.
+    pub(crate) async fn finish(self) -> Result<Engine, ConsumeError> {
+        drop(self.senders);
+        let mut engines = Vec::with_capacity(self.handles.len());
+        for handle in self.handles {
+            engines.push(
+                handle
+                    .await
+                    .map_err(|e| ConsumeError::ShardFailed(e.to_string()))?,
+            );
+        }
+        let mut engines = engines.into_iter();
+        let mut merged = engines.next().expect("at least one shard");
+        for shard in engines {
+            merged
+                .merge(shard)
+                .map_err(|e| ConsumeError::ShardFailed(e.to_string()))?;
+        }
+        self.processed_files.into_iter().for_each(|hash| {
+            merged.record_processed_file(hash);
+        });
+        Ok(merged)
+    }
+}